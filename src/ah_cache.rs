@@ -0,0 +1,89 @@
+// Reply AHs for a UD server, cached per source. ibv_create_ah_from_wc resolves the
+// GID table and, on RoCE, the peer's MAC on every call, which is too slow to do
+// for every request at high rates.
+use std::collections::HashMap;
+use std::io::Error as IOError;
+use std::sync::{Arc, Mutex};
+
+use crate::ibv::{IbvAh, IbvGrh, IbvPd, IbvWc};
+
+// Everything ibv_create_ah_from_wc builds the reply path from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct AhKey {
+    slid: u16,
+    sl: u8,
+    dlid_path_bits: u8,
+    // Source GID, destination GID and version/class/flow word of the GRH, when the
+    // datagram carried one.
+    grh: Option<([u8; 16], [u8; 16], u32)>,
+}
+
+impl AhKey {
+    fn new(wc: &IbvWc, grh: &IbvGrh) -> Self {
+        let has_grh = wc.has_grh();
+        Self {
+            slid: wc.slid,
+            sl: wc.sl,
+            dlid_path_bits: wc.dlid_path_bits,
+            grh: has_grh.then(|| {
+                (
+                    grh.sgid.octets(),
+                    grh.dgid.octets(),
+                    grh.version_tclass_flow,
+                )
+            }),
+        }
+    }
+}
+
+pub struct AhCache<'a> {
+    pd: &'a IbvPd,
+    port_num: u8,
+    capacity: usize,
+    ahs: Mutex<HashMap<AhKey, Arc<IbvAh>>>,
+}
+
+impl<'a> AhCache<'a> {
+    pub fn new(pd: &'a IbvPd, port_num: u8) -> Self {
+        Self::with_capacity(pd, port_num, usize::MAX)
+    }
+    // Holds at most `capacity` AHs; past that an arbitrary entry is dropped to
+    // make room. Senders holding its Arc keep that AH alive until they let go.
+    pub fn with_capacity(pd: &'a IbvPd, port_num: u8, capacity: usize) -> Self {
+        Self {
+            pd,
+            port_num,
+            capacity: capacity.max(1),
+            ahs: Mutex::new(HashMap::new()),
+        }
+    }
+    // The AH for replying to the sender of `wc`, created on first use. `grh` is the
+    // header in front of the payload (see `IbvGrh::from_recv_buf`); it is ignored
+    // for completions without IBV_WC_GRH. Keep the Arc until the reply's send
+    // completes.
+    pub fn get(&self, wc: &IbvWc, grh: &IbvGrh) -> Result<Arc<IbvAh>, IOError> {
+        let key = AhKey::new(wc, grh);
+        let mut ahs = self.ahs.lock().unwrap();
+        if let Some(ah) = ahs.get(&key) {
+            return Ok(ah.clone());
+        }
+        let ah = Arc::new(IbvAh::from_wc(self.pd, self.port_num, wc, grh)?);
+        if ahs.len() >= self.capacity {
+            if let Some(evicted) = ahs.keys().next().copied() {
+                ahs.remove(&evicted);
+            }
+        }
+        ahs.insert(key, ah.clone());
+        Ok(ah)
+    }
+    pub fn len(&self) -> usize {
+        self.ahs.lock().unwrap().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    // Drops every cached AH, e.g. after a GID table change made them stale.
+    pub fn clear(&self) {
+        self.ahs.lock().unwrap().clear();
+    }
+}
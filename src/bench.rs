@@ -0,0 +1,299 @@
+// ib_send_bw / ib_read_lat style sanity checks over an already connected RC QP,
+// plus registration cost measurements.
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::ffi;
+use crate::ibv::{IbvAccess, IbvCq, IbvMr, IbvPd, IbvQp, IbvSendWr, IbvWc, RemoteToken};
+
+#[derive(Clone, Copy, Debug)]
+pub struct BenchOpts {
+    pub iters: usize,
+    pub msg_size: u32,
+    // Maximum number of WRs in flight; must not exceed the QP's max_send_wr.
+    pub tx_depth: usize,
+    // Target of RDMA reads, ignored by the send benchmark.
+    pub remote: RemoteToken,
+    // How long a run may go without a completion before it gives up.
+    pub timeout: Duration,
+}
+
+impl Default for BenchOpts {
+    fn default() -> Self {
+        Self {
+            iters: 1000,
+            msg_size: 65536,
+            tx_depth: 128,
+            remote: RemoteToken::default(),
+            timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct BwResult {
+    pub iters: usize,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub gbps: f64,
+    pub msg_rate: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LatResult {
+    pub iters: usize,
+    pub min: Duration,
+    pub avg: Duration,
+    pub p50: Duration,
+    pub p99: Duration,
+    pub p999: Duration,
+    pub max: Duration,
+}
+
+fn check_opts(mr: &IbvMr, opts: &BenchOpts) -> Result<(), IOError> {
+    let invalid = |msg: String| Err(IOError::new(ErrorKind::InvalidInput, msg));
+    if opts.iters == 0 {
+        return invalid("iters must be positive".to_string());
+    }
+    if opts.tx_depth == 0 {
+        return invalid("tx_depth must be positive".to_string());
+    }
+    if opts.msg_size as u64 > mr.length() {
+        return invalid(format!(
+            "msg_size {} exceeds the MR's {} bytes",
+            opts.msg_size,
+            mr.length()
+        ));
+    }
+    Ok(())
+}
+
+fn stalled(timeout: Duration) -> IOError {
+    IOError::new(
+        ErrorKind::TimedOut,
+        format!("no completion within {:?}", timeout),
+    )
+}
+
+fn wr(mr: &IbvMr, sge: &mut ffi::ibv_sge, opts: &BenchOpts, wr_id: u64) -> IbvSendWr {
+    sge.addr = mr.addr();
+    sge.length = opts.msg_size;
+    sge.lkey = mr.lkey();
+    let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+    wr.wr_id = wr_id;
+    wr.sg_list = sge;
+    wr.num_sge = 1;
+    wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+    wr
+}
+
+fn post(qp: &IbvQp, wr: &IbvSendWr) -> Result<(), IOError> {
+    let mut bad_wr = std::ptr::null::<IbvSendWr>();
+    qp.post_send(wr, std::ptr::addr_of_mut!(bad_wr))
+}
+
+// Reaps completions into `wcs`, failing on the first unsuccessful one.
+fn reap(cq: &IbvCq, wcs: &mut [IbvWc]) -> Result<usize, IOError> {
+    let polled = cq
+        .poll(wcs)
+        .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+    if let Some(wc) = polled
+        .iter()
+        .find(|wc| wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS)
+    {
+        return Err(IOError::other(format!(
+            "wr_id {} failed: {}",
+            wc.wr_id,
+            wc.status_str()
+        )));
+    }
+    Ok(polled.len())
+}
+
+// Streams `iters` SENDs of `msg_size` bytes from the start of `mr`, keeping up to
+// `tx_depth` in flight. The peer must keep enough receives posted to absorb them;
+// if it doesn't, the run fails with TimedOut after `timeout` without a completion.
+pub fn run_send_bw(
+    qp: &IbvQp,
+    cq: &IbvCq,
+    mr: &IbvMr,
+    opts: &BenchOpts,
+) -> Result<BwResult, IOError> {
+    check_opts(mr, opts)?;
+    let mut sge = unsafe { std::mem::zeroed::<ffi::ibv_sge>() };
+    let mut wr = wr(mr, &mut sge, opts, 0);
+    wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND;
+    let mut wcs = vec![unsafe { std::mem::zeroed::<IbvWc>() }; opts.tx_depth];
+    let (mut posted, mut completed) = (0, 0);
+    let start = Instant::now();
+    let mut last_progress = start;
+    while completed < opts.iters {
+        while posted < opts.iters && posted - completed < opts.tx_depth {
+            wr.wr_id = posted as u64;
+            post(qp, &wr)?;
+            posted += 1;
+        }
+        let n = reap(cq, &mut wcs)?;
+        if n > 0 {
+            completed += n;
+            last_progress = Instant::now();
+        } else if last_progress.elapsed() > opts.timeout {
+            return Err(stalled(opts.timeout));
+        }
+    }
+    let elapsed = start.elapsed();
+    let bytes = opts.iters as u64 * opts.msg_size as u64;
+    let secs = elapsed.as_secs_f64();
+    Ok(BwResult {
+        iters: opts.iters,
+        bytes,
+        elapsed,
+        gbps: bytes as f64 * 8.0 / secs / 1e9,
+        msg_rate: opts.iters as f64 / secs,
+    })
+}
+
+// Issues `iters` RDMA reads of `msg_size` bytes from `remote` into `mr`,
+// one at a time, and reports the round-trip distribution.
+pub fn run_read_lat(
+    qp: &IbvQp,
+    cq: &IbvCq,
+    mr: &IbvMr,
+    opts: &BenchOpts,
+) -> Result<LatResult, IOError> {
+    check_opts(mr, opts)?;
+    let mut sge = unsafe { std::mem::zeroed::<ffi::ibv_sge>() };
+    let mut wr = wr(mr, &mut sge, opts, 0);
+    wr.opcode = ffi::ibv_wr_opcode::IBV_WR_RDMA_READ;
+    let remote = opts.remote.sge_for(0, opts.msg_size as u64)?;
+    wr.wr.rdma.remote_addr = remote.addr;
+    wr.wr.rdma.rkey = remote.rkey;
+    let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }];
+    let mut samples = Vec::with_capacity(opts.iters);
+    for i in 0..opts.iters {
+        wr.wr_id = i as u64;
+        let start = Instant::now();
+        post(qp, &wr)?;
+        while reap(cq, &mut wcs)? == 0 {
+            if start.elapsed() > opts.timeout {
+                return Err(stalled(opts.timeout));
+            }
+        }
+        samples.push(start.elapsed());
+    }
+    samples.sort_unstable();
+    let percentile = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+    Ok(LatResult {
+        iters: opts.iters,
+        min: samples[0],
+        avg: samples.iter().sum::<Duration>() / opts.iters as u32,
+        p50: percentile(0.5),
+        p99: percentile(0.99),
+        p999: percentile(0.999),
+        max: samples[samples.len() - 1],
+    })
+}
+
+// Times ibv_reg_mr for each size in `sizes`. The buffers are written before
+// registering, so page faults are excluded and the numbers are pure pin + HCA
+// translation setup. Each MR is deregistered (untimed) before the next size.
+pub fn measure_reg_mr(
+    pd: &IbvPd,
+    sizes: &[usize],
+    access: IbvAccess,
+) -> Result<Vec<(usize, Duration)>, IOError> {
+    let mut results = Vec::with_capacity(sizes.len());
+    for &size in sizes {
+        let buf = vec![1_u8; size.max(1)];
+        let start = Instant::now();
+        let mr = IbvMr::new(pd, &buf, access)?;
+        let elapsed = start.elapsed();
+        drop(mr);
+        results.push((size, elapsed));
+    }
+    Ok(results)
+}
+
+// Converts raw completion timestamps (IbvCqEx::read_completion_ts) to nanoseconds,
+// given IbvDeviceAttrEx::hca_core_clock in kHz.
+pub fn hca_ticks_to_ns(ticks: u64, hca_core_clock_khz: u64) -> u64 {
+    (ticks as u128 * 1_000_000 / hca_core_clock_khz.max(1) as u128) as u64
+}
+
+// Throughput from timestamped completions, e.g. the wallclock or converted HCA
+// timestamps of an ex-CQ. Each completion marks the end of its transfer, so the
+// bytes of the first one in a span went over the wire before the span began and
+// are not counted in it.
+#[derive(Clone, Debug)]
+pub struct ThroughputMeter {
+    window_ns: u64,
+    // (timestamp_ns, byte_len) within the window, oldest first.
+    samples: VecDeque<(u64, u32)>,
+    window_bytes: u64,
+    first: Option<(u64, u32)>,
+    last_ns: u64,
+    total_bytes: u64,
+    count: u64,
+}
+
+impl ThroughputMeter {
+    // `window` is the span the instantaneous rate is computed over.
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window_ns: window.as_nanos().min(u64::MAX as u128) as u64,
+            samples: VecDeque::new(),
+            window_bytes: 0,
+            first: None,
+            last_ns: 0,
+            total_bytes: 0,
+            count: 0,
+        }
+    }
+    // Completions from different QPs may arrive slightly out of timestamp order;
+    // an older timestamp is taken as the latest one seen.
+    pub fn record(&mut self, timestamp_ns: u64, byte_len: u32) {
+        let ts = timestamp_ns.max(self.last_ns);
+        if self.first.is_none() {
+            self.first = Some((ts, byte_len));
+        }
+        self.last_ns = ts;
+        self.total_bytes += byte_len as u64;
+        self.count += 1;
+        self.samples.push_back((ts, byte_len));
+        self.window_bytes += byte_len as u64;
+        while let Some(&(oldest, len)) = self.samples.front() {
+            if ts - oldest <= self.window_ns {
+                break;
+            }
+            self.samples.pop_front();
+            self.window_bytes -= len as u64;
+        }
+    }
+    // Gbps over the completions within the window of the latest one. None until
+    // two completions with distinct timestamps fall in the window.
+    pub fn instant_gbps(&self) -> Option<f64> {
+        let &(oldest, len) = self.samples.front()?;
+        gbps(self.window_bytes - len as u64, self.last_ns - oldest)
+    }
+    // Gbps from the first completion recorded to the latest.
+    pub fn average_gbps(&self) -> Option<f64> {
+        let (first_ns, len) = self.first?;
+        gbps(self.total_bytes - len as u64, self.last_ns - first_ns)
+    }
+    #[inline(always)]
+    pub fn total_bytes(&self) -> u64 {
+        self.total_bytes
+    }
+    #[inline(always)]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+    pub fn reset(&mut self) {
+        *self = Self::new(Duration::from_nanos(self.window_ns));
+    }
+}
+
+fn gbps(bytes: u64, span_ns: u64) -> Option<f64> {
+    (span_ns > 0).then(|| bytes as f64 * 8.0 / span_ns as f64)
+}
@@ -0,0 +1,189 @@
+// A socket-like reliable message channel over one RC QP. It owns its CQs, a send
+// staging buffer and a RecvManager pool, and hides WR posting, completion draining
+// and receive reposting. Use `qp()` to drop down to the verbs underneath.
+use std::io::{Error as IOError, ErrorKind};
+use std::time::{Duration, Instant};
+
+use libc::c_void;
+
+use crate::ffi;
+use crate::ibv::{
+    random_psn_avoiding, IbvAccess, IbvContext, IbvCq, IbvEndpoint, IbvMr, IbvMtu, IbvPd, IbvQp,
+    IbvQpAttr, IbvQpBuilder, IbvSendWr, IbvWc, QpAttrMask, RecvManager,
+};
+
+// PSNs a new connection on a reset QP keeps clear of the previous one's.
+const RECONNECT_PSN_WINDOW: u32 = 1 << 22;
+
+#[derive(Clone, Copy, Debug)]
+pub struct ChannelOpts {
+    pub port_num: u8,
+    pub max_msg_size: u32,
+    // Receives kept posted; the peer must not have more sends than this in flight.
+    pub recv_depth: usize,
+    // How long `send` waits for its completion and `recv` for a message.
+    pub timeout: Duration,
+}
+
+impl Default for ChannelOpts {
+    fn default() -> Self {
+        Self {
+            port_num: 1,
+            max_msg_size: 4096,
+            recv_depth: 64,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+pub struct MessageChannel {
+    // Field order matters: the QP has to be destroyed before its CQs.
+    qp: IbvQp,
+    recv: RecvManager,
+    send_mr: IbvMr,
+    send_buf: Box<[u8]>,
+    send_cq: IbvCq,
+    recv_cq: IbvCq,
+    port_num: u8,
+    max_msg_size: u32,
+    timeout: Duration,
+}
+
+impl MessageChannel {
+    // Creates the channel with its QP in INIT and the receive pool posted. Exchange
+    // `local_endpoint` with the peer, then call `connect`.
+    pub fn new(context: &IbvContext, pd: &IbvPd, opts: ChannelOpts) -> Result<Self, IOError> {
+        let depth = opts.recv_depth.max(1);
+        let send_cq = IbvCq::new::<c_void>(context, 16, None, None, 0)?;
+        let recv_cq = IbvCq::new::<c_void>(context, depth as i32, None, None, 0)?;
+        let qp = IbvQpBuilder::new(&send_cq, &recv_cq)
+            .max_send_wr(1)
+            .max_recv_wr(depth as u32)
+            .build(pd)?;
+        qp.modify_reset2init(opts.port_num)?;
+        let recv = RecvManager::new(pd, &qp, depth, opts.max_msg_size)?;
+        let send_buf = vec![0_u8; opts.max_msg_size as usize].into_boxed_slice();
+        let send_mr = IbvMr::new(pd, &send_buf, IbvAccess::local())?;
+        Ok(Self {
+            qp,
+            recv,
+            send_mr,
+            send_buf,
+            send_cq,
+            recv_cq,
+            port_num: opts.port_num,
+            max_msg_size: opts.max_msg_size,
+            timeout: opts.timeout,
+        })
+    }
+    pub fn local_endpoint(&self, context: &IbvContext, psn: u32) -> Result<IbvEndpoint, IOError> {
+        IbvEndpoint::new(context, &self.qp, self.port_num, psn)
+    }
+    // The local endpoint with a freshly drawn PSN, see `random_psn`.
+    pub fn fresh_endpoint(&self, context: &IbvContext) -> Result<IbvEndpoint, IOError> {
+        IbvEndpoint::with_random_psn(context, &self.qp, self.port_num)
+    }
+    // Takes the QP back to INIT for a new connection, e.g. after a link flap, and
+    // returns the endpoint to send to the peer. The QP keeps its number, so the PSN
+    // is rotated: with the old one, stale packets still in the fabric could be
+    // accepted by the new connection. Finish with `connect` as for a new channel.
+    pub fn reset(&mut self, context: &IbvContext) -> Result<IbvEndpoint, IOError> {
+        let (last, _) = self.qp.query(QpAttrMask::IBV_QP_SQ_PSN.0)?;
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RESET;
+        self.qp.modify(&qp_attr, QpAttrMask::IBV_QP_STATE)?;
+        for cq in [&self.send_cq, &self.recv_cq] {
+            let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 16];
+            while !cq
+                .poll(&mut wcs)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?
+                .is_empty()
+            {}
+        }
+        self.qp.modify_reset2init(self.port_num)?;
+        self.recv.reset();
+        self.recv.refill(&self.qp)?;
+        let psn = random_psn_avoiding(last.sq_psn, RECONNECT_PSN_WINDOW);
+        self.local_endpoint(context, psn)
+    }
+    pub fn connect(&self, local: &IbvEndpoint, remote: &IbvEndpoint) -> Result<(), IOError> {
+        let mtu = IbvMtu::Mtu4096.min(local.mtu).min(remote.mtu);
+        self.qp
+            .modify_init2rtr_to(self.port_num, local, remote, mtu)?;
+        self.qp.modify_rtr2rts(local.psn)
+    }
+    // Sends `msg` and waits until the NIC reports it delivered. A send that times
+    // out is still on the SQ; `reset` the channel before sending again.
+    pub fn send(&mut self, msg: &[u8]) -> Result<(), IOError> {
+        if msg.len() > self.max_msg_size as usize {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes exceeds max_msg_size {}",
+                    msg.len(),
+                    self.max_msg_size
+                ),
+            ));
+        }
+        self.send_buf[..msg.len()].copy_from_slice(msg);
+        let mut sge = self.send_mr.sge(self.send_mr.addr(), msg.len() as u32)?;
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        wr.sg_list = &mut sge;
+        wr.num_sge = 1;
+        wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND;
+        wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        self.qp.post_send(&wr, std::ptr::addr_of_mut!(bad_wr))?;
+        let wc = self.wait_one(&self.send_cq)?;
+        if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS {
+            return Err(IOError::other(format!("send failed: {}", wc.status_str())));
+        }
+        Ok(())
+    }
+    // Blocks until the next message arrives, or fails with TimedOut.
+    pub fn recv(&self) -> Result<Vec<u8>, IOError> {
+        let wc = self.wait_one(&self.recv_cq)?;
+        self.recv.on_recv(&self.qp, &wc, |data| data.to_vec())
+    }
+    // Like `recv`, but copies into `buf` and returns the message length. A message
+    // larger than `buf` is dropped and reported as InvalidInput.
+    pub fn recv_into(&self, buf: &mut [u8]) -> Result<usize, IOError> {
+        let wc = self.wait_one(&self.recv_cq)?;
+        self.recv.on_recv(&self.qp, &wc, |data| {
+            if data.len() > buf.len() {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "message of {} bytes does not fit in {} byte buffer",
+                        data.len(),
+                        buf.len()
+                    ),
+                ));
+            }
+            buf[..data.len()].copy_from_slice(data);
+            Ok(data.len())
+        })?
+    }
+    pub fn qp(&self) -> &IbvQp {
+        &self.qp
+    }
+    fn wait_one(&self, cq: &IbvCq) -> Result<IbvWc, IOError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }];
+        loop {
+            let n = cq
+                .poll(&mut wcs)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?
+                .len();
+            if n > 0 {
+                return Ok(wcs[0]);
+            }
+            if Instant::now() >= deadline {
+                return Err(IOError::new(
+                    ErrorKind::TimedOut,
+                    format!("no completion within {:?}", self.timeout),
+                ));
+            }
+        }
+    }
+}
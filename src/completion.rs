@@ -0,0 +1,311 @@
+// Per-operation completion notification: each send is posted with a callback,
+// or as a future, keyed by a wr_id the wrapper assigns, so callers don't keep
+// their own wr_id bookkeeping. Completions are dispatched as the send CQ is
+// drained. CqDispatcher does the same per connection for a CQ shared by many QPs,
+// and MultiCqPoller drains several CQs fairly in one loop.
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io::{Error as IOError, ErrorKind};
+use std::ops::Range;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use crate::ffi;
+use crate::ibv::{IbvCq, IbvQp, IbvSge, IbvWc, IbvWcBuffer, PostedWr, RemoteToken};
+
+type Callback = Box<dyn FnOnce(Result<IbvWc, IOError>) + Send>;
+
+// Maps a completion to the result handed to callbacks and futures.
+fn wc_result(wc: IbvWc) -> Result<IbvWc, IOError> {
+    if wc.status == ffi::ibv_wc_status::IBV_WC_SUCCESS {
+        Ok(wc)
+    } else {
+        Err(IOError::other(format!(
+            "work request {} failed: {}",
+            wc.wr_id,
+            wc.status_str()
+        )))
+    }
+}
+
+pub struct CallbackQp {
+    // The QP goes first so it is destroyed before the CQ it reports to.
+    qp: IbvQp,
+    send_cq: IbvCq,
+    next_wr_id: AtomicU64,
+    pending: Mutex<HashMap<u64, Callback>>,
+}
+
+impl CallbackQp {
+    // `send_cq` must be the QP's send CQ and must not be polled by anyone else,
+    // or completions go missing.
+    pub fn new(qp: IbvQp, send_cq: IbvCq) -> Result<Self, IOError> {
+        if unsafe { (*qp.as_raw()).send_cq } != send_cq.as_mut_ptr() {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "send_cq is not the QP's send CQ",
+            ));
+        }
+        Ok(Self {
+            qp,
+            send_cq,
+            next_wr_id: AtomicU64::new(0),
+            pending: Mutex::new(HashMap::new()),
+        })
+    }
+    #[inline(always)]
+    pub fn qp(&self) -> &IbvQp {
+        &self.qp
+    }
+    // Posts `wr` signaled, under a fresh wr_id, and calls `callback` with its
+    // completion from whichever `poll` drains it. A failed post drops the callback
+    // without calling it.
+    pub fn post_with<F>(&self, mut wr: PostedWr, callback: F) -> Result<u64, IOError>
+    where
+        F: FnOnce(Result<IbvWc, IOError>) + Send + 'static,
+    {
+        let wr_id = self.next_wr_id.fetch_add(1, Ordering::Relaxed);
+        wr.set_wr_id(wr_id);
+        let flags = wr.send_flags().0 | ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        let wr = wr.flags(ffi::ibv_send_flags(flags));
+        // Registered first: the completion may be polled before `post` returns.
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(wr_id, Box::new(callback));
+        if let Err(err) = self.qp.post(&wr) {
+            self.pending.lock().unwrap().remove(&wr_id);
+            return Err(err);
+        }
+        Ok(wr_id)
+    }
+    // Posts `wr` and returns a future resolving to its completion.
+    pub fn post_async(&self, wr: PostedWr) -> Result<CompletionFuture<'_>, IOError> {
+        let slot = Arc::new(Mutex::new(None));
+        let filled = slot.clone();
+        self.post_with(wr, move |res| *filled.lock().unwrap() = Some(res))?;
+        Ok(CompletionFuture { qp: self, slot })
+    }
+    pub fn send_async(&self, sges: Vec<IbvSge>) -> Result<CompletionFuture<'_>, IOError> {
+        self.post_async(PostedWr::send(0, sges))
+    }
+    pub fn write_async(
+        &self,
+        sges: Vec<IbvSge>,
+        remote: &RemoteToken,
+    ) -> Result<CompletionFuture<'_>, IOError> {
+        self.post_async(PostedWr::write(0, sges, remote))
+    }
+    pub fn read_async(
+        &self,
+        sges: Vec<IbvSge>,
+        remote: &RemoteToken,
+    ) -> Result<CompletionFuture<'_>, IOError> {
+        self.post_async(PostedWr::read(0, sges, remote))
+    }
+    // Drains the send CQ once and runs the callbacks of what it found; returns how
+    // many completions were dispatched. Callbacks run without the internal lock
+    // held, so they may post again.
+    pub fn poll(&self) -> Result<usize, IOError> {
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 16];
+        let polled = self
+            .send_cq
+            .poll(&mut wcs)
+            .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+        let ready: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            polled
+                .iter()
+                .filter_map(|wc| pending.remove(&wc.wr_id).map(|cb| (cb, *wc)))
+                .collect()
+        };
+        let n = ready.len();
+        for (callback, wc) in ready {
+            callback(wc_result(wc));
+        }
+        Ok(n)
+    }
+    // Operations posted but not completed yet.
+    pub fn in_flight(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+}
+
+// Resolves once the operation's completion has been drained. Polling the future
+// drains the CQ itself and, while the completion is outstanding, wakes itself
+// right away: the QP has no event source for an executor to wait on, so awaiting
+// busy-polls like the rest of this crate.
+pub struct CompletionFuture<'a> {
+    qp: &'a CallbackQp,
+    slot: Arc<Mutex<Option<Result<IbvWc, IOError>>>>,
+}
+
+impl Future for CompletionFuture<'_> {
+    type Output = Result<IbvWc, IOError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Some(res) = self.slot.lock().unwrap().take() {
+            return Poll::Ready(res);
+        }
+        if let Err(err) = self.qp.poll() {
+            return Poll::Ready(Err(err));
+        }
+        if let Some(res) = self.slot.lock().unwrap().take() {
+            return Poll::Ready(res);
+        }
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+type Handler = Box<dyn FnMut(&IbvWc) + Send>;
+
+// Routes the completions of a shared CQ to per-connection handlers: by qp_num
+// first, then by wr_id range, then to the fallback. Handlers see failed
+// completions too; qp_num and wr_id are valid on those.
+#[derive(Default)]
+pub struct CqDispatcher {
+    by_qp: HashMap<u32, Handler>,
+    // Checked in registration order.
+    by_wr_id: Vec<(Range<u64>, Handler)>,
+    fallback: Option<Handler>,
+}
+
+impl CqDispatcher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // Replaces any handler already registered for `qp_num`.
+    pub fn register_qp<F>(&mut self, qp_num: u32, handler: F)
+    where
+        F: FnMut(&IbvWc) + Send + 'static,
+    {
+        self.by_qp.insert(qp_num, Box::new(handler));
+    }
+    pub fn unregister_qp(&mut self, qp_num: u32) -> bool {
+        self.by_qp.remove(&qp_num).is_some()
+    }
+    // For completions of QPs without a handler of their own whose wr_id falls in
+    // `wr_ids`, e.g. when connections are told apart by wr_id prefix.
+    pub fn register_wr_range<F>(&mut self, wr_ids: Range<u64>, handler: F)
+    where
+        F: FnMut(&IbvWc) + Send + 'static,
+    {
+        self.by_wr_id.push((wr_ids, Box::new(handler)));
+    }
+    // Completions nothing else claims; without one they are dropped and counted as
+    // unrouted by `dispatch`.
+    pub fn set_fallback<F>(&mut self, handler: F)
+    where
+        F: FnMut(&IbvWc) + Send + 'static,
+    {
+        self.fallback = Some(Box::new(handler));
+    }
+    // Polls `cq` once into `buf` and hands every completion to its handler.
+    // Returns how many were polled and how many of those had no handler.
+    pub fn dispatch(
+        &mut self,
+        cq: &IbvCq,
+        buf: &mut IbvWcBuffer,
+    ) -> Result<(usize, usize), IOError> {
+        let wcs = buf.poll_into(cq)?;
+        let mut unrouted = 0;
+        for wc in wcs {
+            if !self.route(wc) {
+                unrouted += 1;
+            }
+        }
+        Ok((wcs.len(), unrouted))
+    }
+    fn route(&mut self, wc: &IbvWc) -> bool {
+        if let Some(handler) = self.by_qp.get_mut(&wc.qp_num) {
+            handler(wc);
+            return true;
+        }
+        if let Some((_, handler)) = self
+            .by_wr_id
+            .iter_mut()
+            .find(|(wr_ids, _)| wr_ids.contains(&wc.wr_id))
+        {
+            handler(wc);
+            return true;
+        }
+        match self.fallback.as_mut() {
+            Some(handler) => {
+                handler(wc);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+// Round-robins over several CQs, e.g. separate control and data CQs or one per
+// rail. Each pass takes at most `batch` completions from every CQ, starting one CQ
+// further along than the pass before, so a busy CQ can't starve the others.
+pub struct MultiCqPoller<'a> {
+    cqs: Vec<&'a IbvCq>,
+    batch: usize,
+    next: usize,
+    ready: VecDeque<(usize, IbvWc)>,
+}
+
+impl<'a> MultiCqPoller<'a> {
+    pub fn new(cqs: &[&'a IbvCq]) -> Self {
+        Self::with_batch(cqs, 16)
+    }
+    pub fn with_batch(cqs: &[&'a IbvCq], batch: usize) -> Self {
+        Self {
+            cqs: cqs.to_vec(),
+            batch: batch.max(1),
+            next: 0,
+            ready: VecDeque::new(),
+        }
+    }
+    // Runs one pass over the CQs, appending `(cq_index, wc)` pairs to `out`, and
+    // returns how many were added.
+    pub fn poll(&mut self, out: &mut Vec<(usize, IbvWc)>) -> Result<usize, IOError> {
+        let mut wcs = vec![unsafe { std::mem::zeroed::<IbvWc>() }; self.batch];
+        let before = out.len();
+        let n = self.cqs.len();
+        for i in 0..n {
+            let idx = (self.next + i) % n;
+            let polled = self.cqs[idx]
+                .poll(&mut wcs)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+            out.extend(polled.iter().map(|wc| (idx, *wc)));
+        }
+        if n > 0 {
+            self.next = (self.next + 1) % n;
+        }
+        Ok(out.len() - before)
+    }
+    // Completions until a whole pass comes back empty.
+    pub fn drain(&mut self) -> MultiCqDrain<'_, 'a> {
+        MultiCqDrain { poller: self }
+    }
+}
+
+pub struct MultiCqDrain<'p, 'a> {
+    poller: &'p mut MultiCqPoller<'a>,
+}
+
+impl Iterator for MultiCqDrain<'_, '_> {
+    type Item = Result<(usize, IbvWc), IOError>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.poller.ready.is_empty() {
+            let mut out = Vec::new();
+            match self.poller.poll(&mut out) {
+                Ok(0) => return None,
+                Ok(_) => self.poller.ready.extend(out),
+                // What was polled before the failing CQ is handed out next.
+                Err(err) => {
+                    self.poller.ready.extend(out);
+                    return Some(Err(err));
+                }
+            }
+        }
+        self.poller.ready.pop_front().map(Ok)
+    }
+}
@@ -0,0 +1,126 @@
+// One owner for a device's resources, destroyed in the order verbs requires.
+// Dropping e.g. a PD while an MR on it is alive fails with EBUSY, and with the
+// resources spread over struct fields that order is easy to get wrong.
+use std::io::Error as IOError;
+
+use crate::ibv::{
+    IbvAccess, IbvAh, IbvCompChannel, IbvContext, IbvCq, IbvMr, IbvPd, IbvQp, IbvSrq,
+};
+
+pub struct RdmaContext {
+    // Torn down in `Drop` front to back: QPs, SRQs, AHs, MRs, CQs, channels, then
+    // PD and context (which drop as fields, in that order).
+    qps: Vec<IbvQp>,
+    srqs: Vec<IbvSrq>,
+    ahs: Vec<IbvAh>,
+    mrs: Vec<IbvMr>,
+    cqs: Vec<IbvCq>,
+    channels: Vec<IbvCompChannel>,
+    pd: IbvPd,
+    context: IbvContext,
+}
+
+impl RdmaContext {
+    pub fn new(context: IbvContext, pd: IbvPd) -> Self {
+        Self {
+            qps: Vec::new(),
+            srqs: Vec::new(),
+            ahs: Vec::new(),
+            mrs: Vec::new(),
+            cqs: Vec::new(),
+            channels: Vec::new(),
+            pd,
+            context,
+        }
+    }
+    #[inline(always)]
+    pub fn context(&self) -> &IbvContext {
+        &self.context
+    }
+    #[inline(always)]
+    pub fn pd(&self) -> &IbvPd {
+        &self.pd
+    }
+    // The add_* methods take ownership and return the index to look it up with.
+    pub fn add_channel(&mut self, channel: IbvCompChannel) -> usize {
+        self.channels.push(channel);
+        self.channels.len() - 1
+    }
+    pub fn add_cq(&mut self, cq: IbvCq) -> usize {
+        self.cqs.push(cq);
+        self.cqs.len() - 1
+    }
+    pub fn add_mr(&mut self, mr: IbvMr) -> usize {
+        self.mrs.push(mr);
+        self.mrs.len() - 1
+    }
+    pub fn add_srq(&mut self, srq: IbvSrq) -> usize {
+        self.srqs.push(srq);
+        self.srqs.len() - 1
+    }
+    pub fn add_ah(&mut self, ah: IbvAh) -> usize {
+        self.ahs.push(ah);
+        self.ahs.len() - 1
+    }
+    pub fn add_qp(&mut self, qp: IbvQp) -> usize {
+        self.qps.push(qp);
+        self.qps.len() - 1
+    }
+    pub fn create_cq(&mut self, cqe: i32) -> Result<usize, IOError> {
+        let cq = IbvCq::new::<()>(&self.context, cqe, None, None, 0)?;
+        Ok(self.add_cq(cq))
+    }
+    pub fn reg_mr(&mut self, region: &[u8], access: IbvAccess) -> Result<usize, IOError> {
+        let mr = IbvMr::new(&self.pd, region, access)?;
+        Ok(self.add_mr(mr))
+    }
+    #[inline(always)]
+    pub fn channel(&self, index: usize) -> &IbvCompChannel {
+        &self.channels[index]
+    }
+    #[inline(always)]
+    pub fn cq(&self, index: usize) -> &IbvCq {
+        &self.cqs[index]
+    }
+    #[inline(always)]
+    pub fn mr(&self, index: usize) -> &IbvMr {
+        &self.mrs[index]
+    }
+    #[inline(always)]
+    pub fn srq(&self, index: usize) -> &IbvSrq {
+        &self.srqs[index]
+    }
+    #[inline(always)]
+    pub fn ah(&self, index: usize) -> &IbvAh {
+        &self.ahs[index]
+    }
+    #[inline(always)]
+    pub fn qp(&self, index: usize) -> &IbvQp {
+        &self.qps[index]
+    }
+    #[inline(always)]
+    pub fn qps(&self) -> &[IbvQp] {
+        &self.qps
+    }
+    #[inline(always)]
+    pub fn cqs(&self) -> &[IbvCq] {
+        &self.cqs
+    }
+    #[inline(always)]
+    pub fn mrs(&self) -> &[IbvMr] {
+        &self.mrs
+    }
+}
+
+impl Drop for RdmaContext {
+    fn drop(&mut self) {
+        // Vec drops its elements in order, so clearing one collection at a time
+        // is enough; the remaining fields then drop in declaration order.
+        self.qps.clear();
+        self.srqs.clear();
+        self.ahs.clear();
+        self.mrs.clear();
+        self.cqs.clear();
+        self.channels.clear();
+    }
+}
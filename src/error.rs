@@ -6,4 +6,154 @@ pub enum IbvContextError{
    NoDevice,
    #[error("OpenDeviceError")]
    OpenDeviceError, 
-}
\ No newline at end of file
+}
+
+#[derive(Error, Debug)]
+pub enum IbvCqExError {
+    // The device has no completion_timestamp_mask, so timestamp wc_flags can't be honoured.
+    #[error("TimestampsUnsupported")]
+    TimestampsUnsupported,
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+// A failed verb, carrying the original errno plus a hint for the common pitfalls.
+// It is returned wrapped in an io::Error of the same kind; get the errno back with
+// `verbs_errno`.
+#[derive(Error, Debug)]
+#[error("{verb}(): {source}{}", fmt_hint(.hint))]
+pub struct VerbsError {
+    pub verb: &'static str,
+    pub source: std::io::Error,
+    pub hint: Option<&'static str>,
+}
+
+impl VerbsError {
+    pub fn errno(&self) -> Option<i32> {
+        self.source.raw_os_error()
+    }
+}
+
+fn fmt_hint(hint: &Option<&'static str>) -> String {
+    hint.map(|h| format!(" (hint: {})", h)).unwrap_or_default()
+}
+
+fn hint(verb: &str, errno: i32) -> Option<&'static str> {
+    let hint = match (verb, errno) {
+        ("ibv_modify_qp", libc::EINVAL) => {
+            "check attr_mask matches the target state: RESET->INIT needs STATE|PKEY_INDEX|PORT|ACCESS_FLAGS, \
+             INIT->RTR needs STATE|AV|PATH_MTU|DEST_QPN|RQ_PSN|MAX_DEST_RD_ATOMIC|MIN_RNR_TIMER, \
+             RTR->RTS needs STATE|TIMEOUT|RETRY_CNT|RNR_RETRY|SQ_PSN|MAX_QP_RD_ATOMIC; \
+             also verify the QP is in the expected current state, port_num exists, \
+             path_mtu does not exceed the port's max_mtu and the AH carries a GRH on RoCE"
+        }
+        ("ibv_modify_qp", libc::ENOMEM) => "the provider could not allocate resources for the transition",
+        ("ibv_reg_mr", libc::EINVAL) => {
+            "remote write and remote atomic access require IBV_ACCESS_LOCAL_WRITE"
+        }
+        ("ibv_reg_mr", libc::ENOMEM) | ("ibv_reg_mr", libc::EPERM) => {
+            "pinned memory limit reached: raise RLIMIT_MEMLOCK (ulimit -l)"
+        }
+        ("ibv_reg_mr", libc::EFAULT) => {
+            "the range is not fully mapped, or write access was requested on read-only memory"
+        }
+        ("ibv_create_qp", libc::EINVAL) => {
+            "requested caps exceed device limits (max_qp_wr, max_sge, max_inline_data) or the CQs belong to another context"
+        }
+        ("ibv_create_qp", libc::ENOMEM) => "too many QPs, or the requested queue depths are too large",
+        ("ibv_create_cq", libc::EINVAL) => "cqe exceeds the device's max_cqe",
+        ("ibv_post_send", libc::ENOMEM) | ("ibv_post_recv", libc::ENOMEM) => {
+            "the work queue is full: reap completions before posting more"
+        }
+        ("ibv_post_send", libc::EINVAL) | ("ibv_post_recv", libc::EINVAL) => {
+            "num_sge exceeds the QP's max_sge, the opcode is invalid for this QP type, or the QP is not in a state that allows posting"
+        }
+        _ => return None,
+    };
+    Some(hint)
+}
+
+// Decorates `err` from `verb` with a VerbsError, keeping its ErrorKind.
+pub fn verbs_error(verb: &'static str, err: std::io::Error) -> std::io::Error {
+    let hint = err.raw_os_error().and_then(|errno| hint(verb, errno));
+    std::io::Error::new(
+        err.kind(),
+        VerbsError {
+            verb,
+            source: err,
+            hint,
+        },
+    )
+}
+
+// The errno behind `err`, whether or not it was decorated by `verbs_error`.
+pub fn verbs_errno(err: &std::io::Error) -> Option<i32> {
+    err.raw_os_error().or_else(|| {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<VerbsError>())
+            .and_then(VerbsError::errno)
+    })
+}
+
+// A WR gathering or scattering more SGEs than the QP was created with, which the
+// provider would reject with a bare EINVAL. Returned wrapped in an InvalidInput
+// io::Error.
+#[derive(Error, Debug)]
+#[error("{given} SGEs exceed the QP's max_sge of {max}")]
+pub struct TooManySges {
+    pub given: usize,
+    pub max: u32,
+}
+
+// A local buffer that doesn't fit in the MR it is supposed to be in; posting it
+// would fail the WR with IBV_WC_LOC_PROT_ERR. Returned wrapped in an InvalidInput
+// io::Error.
+#[derive(Error, Debug)]
+#[error("[{addr:#x}, +{len}) is outside mr [{mr_addr:#x}, +{mr_length})")]
+pub struct SgeOutOfRange {
+    pub addr: u64,
+    pub len: u64,
+    pub mr_addr: u64,
+    pub mr_length: u64,
+}
+
+// Errnos a verb may return under transient resource pressure.
+pub fn is_retryable(err: &std::io::Error) -> bool {
+    matches!(
+        verbs_errno(err),
+        Some(libc::EAGAIN) | Some(libc::ENOMEM) | Some(libc::EBUSY) | Some(libc::EINTR)
+    )
+}
+
+// Runs `f` up to `attempts` times, sleeping `backoff` (doubled after each try)
+// between tries. Only `is_retryable` errors are retried; anything else, e.g.
+// EINVAL, is returned at once.
+pub fn with_retry<T, F>(
+    attempts: usize,
+    backoff: std::time::Duration,
+    mut f: F,
+) -> Result<T, std::io::Error>
+where
+    F: FnMut() -> Result<T, std::io::Error>,
+{
+    let mut delay = backoff;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(err) if attempt < attempts && is_retryable(&err) => {
+                std::thread::sleep(delay);
+                delay = delay.saturating_mul(2);
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ReplicationError {
+    #[error("replication to target {target} failed: {status}")]
+    TargetFailed { target: usize, status: &'static str },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
@@ -0,0 +1,104 @@
+// Detects RoCE GID table changes. Adding or removing an IP or VLAN rewrites the
+// table, so a cached sgid index can silently start naming another address or none
+// at all. Register the entries in use with `watch` and check them with `poll`,
+// periodically or after `wait_gid_change` returns.
+use std::io::Error as IOError;
+
+use crate::ffi;
+use crate::ibv::{IbvContext, IbvGid};
+
+pub struct GidChange {
+    pub port_num: u8,
+    pub index: i32,
+    pub old: IbvGid,
+    // None if the entry is now empty or can no longer be queried.
+    pub new: Option<IbvGid>,
+}
+
+impl GidChange {
+    #[inline(always)]
+    pub fn is_removed(&self) -> bool {
+        self.new.is_none()
+    }
+}
+
+struct Watched {
+    port_num: u8,
+    index: i32,
+    gid: IbvGid,
+}
+
+#[derive(Default)]
+pub struct GidMonitor {
+    watched: Vec<Watched>,
+}
+
+impl GidMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // Starts tracking (port_num, index) and returns the GID currently stored there.
+    pub fn watch(
+        &mut self,
+        context: &IbvContext,
+        port_num: u8,
+        index: i32,
+    ) -> Result<IbvGid, IOError> {
+        let gid = context.query_gid(port_num, index)?;
+        self.watched
+            .retain(|w| !(w.port_num == port_num && w.index == index));
+        self.watched.push(Watched {
+            port_num,
+            index,
+            gid,
+        });
+        Ok(gid)
+    }
+    pub fn unwatch(&mut self, port_num: u8, index: i32) {
+        self.watched
+            .retain(|w| !(w.port_num == port_num && w.index == index));
+    }
+    // Re-reads every watched entry and reports those that differ from the last
+    // snapshot. The snapshot is updated, so each change is reported once.
+    pub fn poll(&mut self, context: &IbvContext) -> Vec<GidChange> {
+        let mut changes = Vec::new();
+        for w in self.watched.iter_mut() {
+            let new = context
+                .query_gid(w.port_num, w.index)
+                .ok()
+                .filter(|gid| !gid.is_zero());
+            let unchanged = match new {
+                Some(gid) => gid.octets() == w.gid.octets(),
+                None => w.gid.is_zero(),
+            };
+            if unchanged {
+                continue;
+            }
+            changes.push(GidChange {
+                port_num: w.port_num,
+                index: w.index,
+                old: w.gid,
+                new,
+            });
+            w.gid = new.unwrap_or(IbvGid { raw: [0; 16] });
+        }
+        changes
+    }
+    // Blocks on the context's async event queue until the device reports a GID
+    // change, then polls. Other async events are acknowledged and dropped, so only
+    // use this when nothing else consumes the context's async events.
+    pub fn wait_gid_change(&mut self, context: &IbvContext) -> Result<Vec<GidChange>, IOError> {
+        loop {
+            let mut event = unsafe { std::mem::zeroed::<ffi::ibv_async_event>() };
+            let ret = unsafe { ffi::ibv_get_async_event(context.as_mut_ptr(), &mut event) };
+            if ret != 0 {
+                return Err(IOError::last_os_error());
+            }
+            let event_type = event.event_type;
+            unsafe { ffi::ibv_ack_async_event(&mut event) };
+            if event_type == ffi::ibv_event_type::IBV_EVENT_GID_CHANGE {
+                return Ok(self.poll(context));
+            }
+        }
+    }
+}
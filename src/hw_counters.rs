@@ -0,0 +1,78 @@
+// Transport error counters from /sys/class/infiniband/<dev>/ports/<port>/hw_counters/.
+// The verbs counters API only attaches to flows, and QP-granular statistics are
+// only reachable through the rdma netlink interface (`rdma statistic qp bind`),
+// which isn't bound here; so these are per port. To pin retransmits on one
+// connection, take a snapshot, exercise that QP alone, and look at the delta.
+use std::collections::BTreeMap;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::PathBuf;
+
+use crate::ibv::IbvContext;
+
+// Counters for the usual RC retransmit suspects; 0 where the driver lacks one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PortCounters {
+    // NAKs sent for packets arriving out of order, i.e. the peer retransmits.
+    pub out_of_sequence: u64,
+    // NAK-sequence errors received, each one triggering a retransmit here.
+    pub packet_seq_err: u64,
+    // Sends that failed after exhausting their RNR retries.
+    pub rnr_nak_retry_err: u64,
+    // Every counter the driver exposes, including the ones above.
+    pub all: BTreeMap<String, u64>,
+}
+
+impl PortCounters {
+    // Fails with NotFound if the driver exposes no hw_counters for the port.
+    pub fn read(context: &IbvContext, port_num: u8) -> Result<Self, IOError> {
+        let dir = PathBuf::from(format!(
+            "{}/ports/{}/hw_counters",
+            context.ibdev_path(),
+            port_num
+        ));
+        let entries = std::fs::read_dir(&dir).map_err(|err| {
+            IOError::new(
+                ErrorKind::NotFound,
+                format!("no hw_counters for port {}: {}", port_num, err),
+            )
+        })?;
+        let mut all = BTreeMap::new();
+        for entry in entries.filter_map(|e| e.ok()) {
+            let value = std::fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|v| v.trim().parse().ok());
+            if let Some(value) = value {
+                all.insert(entry.file_name().to_string_lossy().into_owned(), value);
+            }
+        }
+        let get = |name: &str| all.get(name).copied().unwrap_or(0);
+        Ok(Self {
+            out_of_sequence: get("out_of_sequence"),
+            packet_seq_err: get("packet_seq_err"),
+            rnr_nak_retry_err: get("rnr_nak_retry_err"),
+            all,
+        })
+    }
+    // What changed since `earlier`, a snapshot of the same port.
+    pub fn delta(&self, earlier: &PortCounters) -> PortCounters {
+        Self {
+            out_of_sequence: self.out_of_sequence.wrapping_sub(earlier.out_of_sequence),
+            packet_seq_err: self.packet_seq_err.wrapping_sub(earlier.packet_seq_err),
+            rnr_nak_retry_err: self
+                .rnr_nak_retry_err
+                .wrapping_sub(earlier.rnr_nak_retry_err),
+            all: self
+                .all
+                .iter()
+                .map(|(name, &value)| {
+                    let before = earlier.all.get(name).copied().unwrap_or(0);
+                    (name.clone(), value.wrapping_sub(before))
+                })
+                .collect(),
+        }
+    }
+    // Whether any of the retransmit-related counters is non-zero.
+    pub fn has_retransmits(&self) -> bool {
+        self.out_of_sequence != 0 || self.packet_seq_err != 0 || self.rnr_nak_retry_err != 0
+    }
+}
@@ -1,14 +1,27 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
 use std::ffi::{CStr, CString};
-use std::io::Error as IOError;
+use std::io::{Error as IOError, ErrorKind};
+use std::mem::ManuallyDrop;
+use std::net::{AddrParseError, Ipv4Addr, Ipv6Addr};
+use std::ops::{Deref, DerefMut};
 use std::os::raw::c_int;
+use std::os::unix::io::RawFd;
 use std::ptr::NonNull;
 use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 use libc::c_void;
+use num_enum::{IntoPrimitive, TryFromPrimitive};
 
-use crate::error::IbvContextError;
+use crate::error::{
+    verbs_errno, verbs_error, IbvContextError, IbvCqExError, SgeOutOfRange, TooManySges,
+};
 use crate::ffi;
 use crate::ffi::ibv_access_flags;
+use crate::numa::{self, NumaPolicy};
 pub type IbvDeviceAttr = ffi::ibv_device_attr;
 pub type IbvPortAttr = ffi::ibv_port_attr;
 pub type IbvGid = ffi::ibv_gid;
@@ -17,6 +30,332 @@ pub type IbvQpInitAttr = ffi::ibv_qp_init_attr;
 pub type IbvQpAttr = ffi::ibv_qp_attr;
 pub type IbvRecvWr = ffi::ibv_recv_wr;
 pub type IbvSendWr = ffi::ibv_send_wr;
+pub type IbvAccess = ffi::ibv_access_flags;
+pub type PortCapFlags = ffi::ibv_port_cap_flags;
+pub type PortCapFlags2 = ffi::ibv_port_cap_flags2;
+pub type RawPacketCaps = ffi::ibv_raw_packet_caps;
+pub type IbvAhAttr = ffi::ibv_ah_attr;
+pub type IbvGrh = ffi::ibv_grh;
+pub type IbvAsyncEvent = ffi::ibv_async_event;
+pub type IbvSge = ffi::ibv_sge;
+pub type IbvQpInitAttrEx = ffi::ibv_qp_init_attr_ex;
+pub type IbvSrqInitAttrEx = ffi::ibv_srq_init_attr_ex;
+pub type IbvEce = ffi::ibv_ece;
+pub type IbvQpCap = ffi::ibv_qp_cap;
+pub type IbvDeviceAttrEx = ffi::ibv_device_attr_ex;
+pub type IbvCqInitAttrEx = ffi::ibv_cq_init_attr_ex;
+pub type WcFlags = ffi::ibv_create_cq_wc_flags;
+pub type QpAttrMask = ffi::ibv_qp_attr_mask;
+
+// bindgen gives its bitfield enums the bit operators but no way to test for a set
+// of flags.
+macro_rules! impl_contains {
+    ($($flags:ty),*) => {
+        $(
+            impl $flags {
+                #[inline(always)]
+                pub fn contains(self, other: Self) -> bool {
+                    self.0 & other.0 == other.0
+                }
+            }
+        )*
+    };
+}
+impl_contains!(IbvAccess, PortCapFlags, PortCapFlags2, RawPacketCaps);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum IbvMtu {
+    Mtu256 = ffi::ibv_mtu::IBV_MTU_256,
+    Mtu512 = ffi::ibv_mtu::IBV_MTU_512,
+    Mtu1024 = ffi::ibv_mtu::IBV_MTU_1024,
+    Mtu2048 = ffi::ibv_mtu::IBV_MTU_2048,
+    Mtu4096 = ffi::ibv_mtu::IBV_MTU_4096,
+}
+
+// Ordered by size, so `local.min(remote)` picks the MTU both peers support. The
+// raw ibv_mtu value converts with `u32::from` / `IbvMtu::try_from`.
+impl IbvMtu {
+    pub fn to_bytes(self) -> u32 {
+        match self {
+            IbvMtu::Mtu256 => 256,
+            IbvMtu::Mtu512 => 512,
+            IbvMtu::Mtu1024 => 1024,
+            IbvMtu::Mtu2048 => 2048,
+            IbvMtu::Mtu4096 => 4096,
+        }
+    }
+    pub fn from_bytes(bytes: u32) -> Option<Self> {
+        match bytes {
+            256 => Some(IbvMtu::Mtu256),
+            512 => Some(IbvMtu::Mtu512),
+            1024 => Some(IbvMtu::Mtu1024),
+            2048 => Some(IbvMtu::Mtu2048),
+            4096 => Some(IbvMtu::Mtu4096),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum IbvQpType {
+    Rc = ffi::ibv_qp_type::IBV_QPT_RC,
+    Uc = ffi::ibv_qp_type::IBV_QPT_UC,
+    Ud = ffi::ibv_qp_type::IBV_QPT_UD,
+    RawPacket = ffi::ibv_qp_type::IBV_QPT_RAW_PACKET,
+    XrcSend = ffi::ibv_qp_type::IBV_QPT_XRC_SEND,
+    XrcRecv = ffi::ibv_qp_type::IBV_QPT_XRC_RECV,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RdmaOp {
+    Send,
+    SendWithImm,
+    Write,
+    WriteWithImm,
+    Read,
+    AtomicCmpSwap,
+    AtomicFetchAdd,
+}
+
+impl RdmaOp {
+    fn from_wr_opcode(opcode: ffi::ibv_wr_opcode::Type) -> Option<Self> {
+        match opcode {
+            ffi::ibv_wr_opcode::IBV_WR_SEND => Some(RdmaOp::Send),
+            ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM => Some(RdmaOp::SendWithImm),
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE => Some(RdmaOp::Write),
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM => Some(RdmaOp::WriteWithImm),
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_READ => Some(RdmaOp::Read),
+            ffi::ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP => Some(RdmaOp::AtomicCmpSwap),
+            ffi::ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD => Some(RdmaOp::AtomicFetchAdd),
+            _ => None,
+        }
+    }
+    // UC has no responder resources, so no READ or atomics; UD and raw packet QPs
+    // only carry SENDs.
+    pub fn supported_on(self, qp_type: IbvQpType) -> bool {
+        match qp_type {
+            IbvQpType::Rc | IbvQpType::XrcSend => true,
+            IbvQpType::Uc => matches!(
+                self,
+                RdmaOp::Send | RdmaOp::SendWithImm | RdmaOp::Write | RdmaOp::WriteWithImm
+            ),
+            IbvQpType::Ud => matches!(self, RdmaOp::Send | RdmaOp::SendWithImm),
+            IbvQpType::RawPacket => self == RdmaOp::Send,
+            IbvQpType::XrcRecv => false,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum PathMigState {
+    Migrated = ffi::ibv_mig_state::IBV_MIG_MIGRATED,
+    Rearm = ffi::ibv_mig_state::IBV_MIG_REARM,
+    Armed = ffi::ibv_mig_state::IBV_MIG_ARMED,
+}
+
+// How far a device's atomics are atomic. With Hca, RDMA atomics are only atomic
+// against other atomics executed by the same HCA: a CPU reading or updating the
+// target location concurrently (including CPU atomics) may see torn or lost
+// updates. With Glob they are also atomic against the CPU and other HCAs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, IntoPrimitive, TryFromPrimitive)]
+#[repr(u32)]
+pub enum AtomicCap {
+    None = ffi::ibv_atomic_cap::IBV_ATOMIC_NONE,
+    Hca = ffi::ibv_atomic_cap::IBV_ATOMIC_HCA,
+    Glob = ffi::ibv_atomic_cap::IBV_ATOMIC_GLOB,
+}
+
+impl AtomicCap {
+    #[inline(always)]
+    pub fn supports_atomics(self) -> bool {
+        self != AtomicCap::None
+    }
+    // Whether the CPU may access locations targeted by RDMA atomics while they run.
+    #[inline(always)]
+    pub fn is_cpu_coherent(self) -> bool {
+        self == AtomicCap::Glob
+    }
+}
+
+// Canonical identity of a physical port, stable across device opens.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IbvPortId {
+    pub node_guid: u64,
+    pub port_num: u8,
+}
+
+// What one side of an RC connection tells the other.
+#[derive(Clone, Copy, Debug)]
+pub struct IbvEndpoint {
+    pub qpn: u32,
+    pub psn: u32,
+    pub lid: u16,
+    // Set on RoCE ports, which have no LIDs: the GID the peer has to target, and
+    // its index in the sender's own GID table (only meaningful locally).
+    pub gid: Option<IbvGid>,
+    pub gid_index: u8,
+    // The port's active MTU and largest message it accepts.
+    pub mtu: IbvMtu,
+    pub max_msg_sz: u32,
+}
+
+// The limits of one connection: the smaller of both sides' values.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NegotiatedLimits {
+    pub mtu: IbvMtu,
+    pub max_msg_sz: u32,
+}
+
+// PSNs are 24 bits wide.
+const PSN_MASK: u32 = 0x00ff_ffff;
+const MAX_PSN_WINDOW: u32 = (1 << 23) - 1;
+
+// A random starting PSN. Reusing the previous PSN when a QP number is reconnected
+// lets late packets of the old connection fall inside the new one's PSN window,
+// where the responder accepts them as fresh data; a random PSN makes that unlikely.
+pub fn random_psn() -> u32 {
+    let mut buf = [0_u8; 4];
+    let ret = unsafe { libc::getrandom(buf.as_mut_ptr() as *mut c_void, buf.len(), 0) };
+    if ret != buf.len() as isize {
+        // No entropy source; the clock is still better than a fixed PSN.
+        let nanos = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .subsec_nanos();
+        return nanos & PSN_MASK;
+    }
+    u32::from_ne_bytes(buf) & PSN_MASK
+}
+
+// `psn + n` modulo 2^24.
+#[inline(always)]
+pub fn psn_add(psn: u32, n: u32) -> u32 {
+    psn.wrapping_add(n) & PSN_MASK
+}
+
+// Orders two PSNs with serial number arithmetic: `a` is less than `b` if `b` is
+// at most 2^23 - 1 ahead of it going around the 24-bit space. Exactly half the
+// space apart the order is undefined; that case compares as Greater.
+pub fn psn_compare(a: u32, b: u32) -> std::cmp::Ordering {
+    let diff = b.wrapping_sub(a) & PSN_MASK;
+    if diff == 0 {
+        std::cmp::Ordering::Equal
+    } else if diff < 1 << 23 {
+        std::cmp::Ordering::Less
+    } else {
+        std::cmp::Ordering::Greater
+    }
+}
+
+// A `random_psn` whose window of `window` PSNs doesn't cover `last`, the send
+// PSN the QP reached on its previous connection, so that connection's late
+// packets can't be taken for the new one's. `window` is clamped to 2^23 - 1:
+// serial number arithmetic can't order PSNs further apart than that.
+pub fn random_psn_avoiding(last: u32, window: u32) -> u32 {
+    let window = window.min(MAX_PSN_WINDOW);
+    loop {
+        let psn = random_psn();
+        let covered = psn_compare(psn, last) != std::cmp::Ordering::Greater
+            && psn_compare(last, psn_add(psn, window)) == std::cmp::Ordering::Less;
+        if !covered {
+            return psn;
+        }
+    }
+}
+
+impl IbvEndpoint {
+    // Like `new` with a fresh `random_psn`; use this on every (re)connect.
+    pub fn with_random_psn(
+        context: &IbvContext,
+        qp: &IbvQp,
+        port_num: u8,
+    ) -> Result<Self, IOError> {
+        Self::new(context, qp, port_num, random_psn())
+    }
+    pub fn new(context: &IbvContext, qp: &IbvQp, port_num: u8, psn: u32) -> Result<Self, IOError> {
+        let port_attr = context.query_port(port_num)?;
+        let (gid, gid_index) = if port_attr.link_layer() == ffi::IBV_LINK_LAYER_ETHERNET as u8 {
+            let index = context.loopback_gid_index(port_num)?;
+            (Some(context.query_gid(port_num, index as i32)?), index)
+        } else {
+            (None, 0)
+        };
+        Ok(Self {
+            qpn: qp.qpn(),
+            psn,
+            lid: port_attr.lid(),
+            gid,
+            gid_index,
+            // the path MTU can't exceed what the port is currently running at
+            mtu: IbvMtu::try_from(port_attr.active_mtu()).unwrap_or(IbvMtu::Mtu256),
+            max_msg_sz: port_attr.max_msg_sz(),
+        })
+    }
+}
+// verbs.h reaches the extended verbs through the verbs_context wrapping each
+// ibv_context (verbs_get_ctx_op). Those helpers are static inline, so bindgen
+// doesn't see them and we redo the lookup here.
+const VERBS_ABI_IS_EXTENDED: *mut c_void = usize::MAX as *mut c_void;
+
+unsafe fn verbs_get_ctx<'a>(context: *mut ffi::ibv_context) -> Option<&'a ffi::verbs_context> {
+    if (*context).abi_compat != VERBS_ABI_IS_EXTENDED {
+        return None;
+    }
+    let offset = std::mem::offset_of!(ffi::verbs_context, context);
+    Some(&*((context as *mut u8).sub(offset) as *const ffi::verbs_context))
+}
+
+macro_rules! verbs_ctx_op {
+    ($context:expr, $op:ident) => {
+        unsafe { verbs_get_ctx($context) }.and_then(|vctx| {
+            let needed = std::mem::size_of::<ffi::verbs_context>()
+                - std::mem::offset_of!(ffi::verbs_context, $op);
+            if (vctx.sz as usize) < needed {
+                None
+            } else {
+                vctx.$op
+            }
+        })
+    };
+}
+
+// The static-inline verbs dispatch through `ibv_context.ops`, which providers may
+// leave partly unset; yields the op or an Unsupported error instead of panicking.
+macro_rules! ctx_ops_fn {
+    ($context:expr, $op:ident, $verb:expr) => {
+        unsafe { (*$context).ops.$op }.ok_or_else(|| unsupported_verb($verb))
+    };
+}
+
+fn query_device_ex(context: *mut ffi::ibv_context) -> Result<IbvDeviceAttrEx, IOError> {
+    let query_device_ex = verbs_ctx_op!(context, query_device_ex)
+        .ok_or_else(|| unsupported_verb("ibv_query_device_ex"))?;
+    let input = ffi::ibv_query_device_ex_input { comp_mask: 0 };
+    let mut device_attr = unsafe { std::mem::zeroed::<IbvDeviceAttrEx>() };
+    let ret = unsafe {
+        query_device_ex(
+            context,
+            &input,
+            &mut device_attr,
+            std::mem::size_of::<IbvDeviceAttrEx>() as u64,
+        )
+    };
+    if ret != 0 {
+        return Err(IOError::from_raw_os_error(ret));
+    }
+    Ok(device_attr)
+}
+
+fn unsupported_verb(verb: &str) -> IOError {
+    IOError::new(
+        ErrorKind::Unsupported,
+        format!("{}() is not supported by this provider", verb),
+    )
+}
+
 #[derive(Clone)]
 pub struct IbvContext {
     ibv_context: NonNull<ffi::ibv_context>,
@@ -75,8 +414,152 @@ impl IbvContext {
         }
         Ok(device_attr)
     }
+    pub fn query_device_ex(&self) -> Result<IbvDeviceAttrEx, IOError> {
+        query_device_ex(self.as_mut_ptr())
+    }
+    // Fails unless RDMA atomics on this device are coherent with CPU access (see
+    // AtomicCap), for applications that mix CPU and RDMA atomics on the same words.
+    pub fn check_cpu_coherent_atomics(&self) -> Result<(), IOError> {
+        match self.query_device()?.atomic_cap_enum() {
+            AtomicCap::Glob => Ok(()),
+            AtomicCap::Hca => Err(IOError::new(
+                ErrorKind::Unsupported,
+                "device atomics are HCA-ordered only: CPU access to locations targeted by \
+                 RDMA atomics is not atomic with respect to them",
+            )),
+            AtomicCap::None => Err(IOError::new(
+                ErrorKind::Unsupported,
+                "device has no atomic support",
+            )),
+        }
+    }
+    pub fn supports_hw_timestamps(&self) -> bool {
+        self.query_device_ex()
+            .map(|attr| attr.completion_timestamp_mask != 0)
+            .unwrap_or(false)
+    }
+    // The current raw HCA clock, the same one completion timestamps are taken from.
+    // Convert with `hw_clock_khz` (bench::hca_ticks_to_ns).
+    pub fn read_hw_clock(&self) -> Result<u64, IOError> {
+        let query_rt_values = verbs_ctx_op!(self.as_mut_ptr(), query_rt_values)
+            .ok_or_else(|| unsupported_verb("ibv_query_rt_values_ex"))?;
+        let mut values = unsafe { std::mem::zeroed::<ffi::ibv_values_ex>() };
+        values.comp_mask = ffi::ibv_values_mask::IBV_VALUES_MASK_RAW_CLOCK.0;
+        let ret = unsafe { query_rt_values(self.as_mut_ptr(), &mut values) };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_query_rt_values_ex",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        if values.comp_mask & ffi::ibv_values_mask::IBV_VALUES_MASK_RAW_CLOCK.0 == 0 {
+            return Err(unsupported_verb("ibv_query_rt_values_ex(RAW_CLOCK)"));
+        }
+        // Providers report the cycle count as a timespec, mostly in tv_nsec.
+        let clock = values.raw_clock;
+        Ok((clock.tv_sec as u64)
+            .wrapping_mul(1_000_000_000)
+            .wrapping_add(clock.tv_nsec as u64))
+    }
+    // Frequency of the clock `read_hw_clock` reads, in kHz; 0 if unknown.
+    pub fn hw_clock_khz(&self) -> Result<u64, IOError> {
+        Ok(self.query_device_ex()?.hca_core_clock)
+    }
+    // IBV_SEND_IP_CSUM if the device offloads TX checksums on `qp_type` QPs.
+    pub fn tx_csum_send_flag(&self, qp_type: IbvQpType) -> Result<ffi::ibv_send_flags, IOError> {
+        let attr = self.query_device_ex()?;
+        let cap_flags = attr.orig_attr.device_cap_flags;
+        let supported = match qp_type {
+            IbvQpType::Ud => cap_flags & ffi::ibv_device_cap_flags::IBV_DEVICE_UD_IP_CSUM.0 != 0,
+            IbvQpType::RawPacket => {
+                cap_flags & ffi::ibv_device_cap_flags::IBV_DEVICE_RAW_IP_CSUM.0 != 0
+                    || attr
+                        .raw_packet_caps()
+                        .contains(RawPacketCaps::IBV_RAW_PACKET_CAP_IP_CSUM)
+            }
+            _ => false,
+        };
+        if !supported {
+            return Err(IOError::new(
+                ErrorKind::Unsupported,
+                "the device does not offload IP checksums for this QP type",
+            ));
+        }
+        Ok(ffi::ibv_send_flags::IBV_SEND_IP_CSUM)
+    }
+    #[inline(always)]
+    pub fn async_fd(&self) -> RawFd {
+        unsafe { (*self.ibv_context.as_ptr()).async_fd }
+    }
+    // Waits up to `timeout` for an async event, returning None on timeout. The event
+    // is acknowledged before it is returned, so its element pointers are only valid
+    // while the caller still holds the QP/CQ/SRQ they refer to.
+    pub fn wait_async_event(&self, timeout: Duration) -> Result<Option<IbvAsyncEvent>, IOError> {
+        let mut pfd = libc::pollfd {
+            fd: self.async_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(c_int::MAX as u128) as c_int;
+        let ret = unsafe { libc::poll(&mut pfd, 1, timeout_ms) };
+        if ret < 0 {
+            return Err(IOError::last_os_error());
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+        let mut event = unsafe { std::mem::zeroed::<IbvAsyncEvent>() };
+        let ret = unsafe { ffi::ibv_get_async_event(self.ibv_context.as_ptr(), &mut event) };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        let copy = event;
+        unsafe { ffi::ibv_ack_async_event(&mut event) };
+        Ok(Some(copy))
+    }
+    // Waits until `qp`, attached to an SRQ and already moved to ERR, reports
+    // LAST_WQE_REACHED. Only then can it be destroyed without racing WQEs still
+    // draining from the SRQ; poll its CQ empty in between. Async events for other
+    // objects arriving meanwhile are discarded.
+    pub fn wait_last_wqe(&self, qp: &IbvQp, timeout: Duration) -> Result<(), IOError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.wait_async_event(remaining)? {
+                Some(event)
+                    if event.is_last_wqe_reached()
+                        && event.qp().map(NonNull::as_ptr) == Some(qp.as_raw()) =>
+                {
+                    return Ok(());
+                }
+                Some(_) => {}
+                None => {
+                    return Err(IOError::new(
+                        ErrorKind::TimedOut,
+                        "no IBV_EVENT_QP_LAST_WQE_REACHED before the timeout",
+                    ))
+                }
+            }
+        }
+    }
     pub fn query_port(&self, port_num: u8) -> Result<IbvPortAttr, IOError> {
         let mut port_attr = unsafe { std::mem::zeroed::<IbvPortAttr>() };
+        // As the inline ibv_query_port in verbs.h: the extended op fills the whole
+        // struct, the exported compat symbol stops before active_speed_ex.
+        if let Some(query_port) = verbs_ctx_op!(self.ibv_context.as_ptr(), query_port) {
+            let ret = unsafe {
+                query_port(
+                    self.ibv_context.as_ptr(),
+                    port_num,
+                    &mut port_attr,
+                    std::mem::size_of::<IbvPortAttr>() as _,
+                )
+            };
+            if ret != 0 {
+                return Err(IOError::from_raw_os_error(ret));
+            }
+            return Ok(port_attr);
+        }
         let ret = unsafe {
             ffi::ibv_query_port(
                 self.ibv_context.as_ptr(),
@@ -104,6 +587,109 @@ impl IbvContext {
         }
         Ok(gid)
     }
+    pub fn query_gid_type(&self, port_num: u8, index: u32) -> Result<ffi::ibv_gid_type, IOError> {
+        let mut entry = unsafe { std::mem::zeroed::<ffi::ibv_gid_entry>() };
+        let ret = unsafe {
+            ffi::_ibv_query_gid_ex(
+                self.ibv_context.as_ptr(),
+                port_num as u32,
+                index,
+                &mut entry,
+                0,
+                std::mem::size_of::<ffi::ibv_gid_entry>() as u64,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(entry.gid_type)
+    }
+    // Index of `gid` in the port's GID table, optionally also requiring a GID type
+    // (RoCE lists each address once per RoCE version).
+    pub fn find_gid_index(
+        &self,
+        port_num: u8,
+        gid: &IbvGid,
+        gid_type: Option<ffi::ibv_gid_type>,
+    ) -> Result<u8, IOError> {
+        let port_attr = self.query_port(port_num)?;
+        for index in 0..port_attr.gid_tbl_len().min(256) {
+            let entry = match self.query_gid(port_num, index) {
+                Ok(entry) => entry,
+                Err(_) => continue,
+            };
+            if unsafe { entry.raw != gid.raw } {
+                continue;
+            }
+            match gid_type {
+                Some(gid_type) if self.query_gid_type(port_num, index as u32)? != gid_type => {}
+                _ => return Ok(index as u8),
+            }
+        }
+        Err(IOError::new(
+            ErrorKind::NotFound,
+            format!("gid not found on port {}", port_num),
+        ))
+    }
+    // Effective limits of a connection from `local_port` to `remote`.
+    pub fn negotiated_limits(
+        &self,
+        local_port: u8,
+        remote: &IbvEndpoint,
+    ) -> Result<NegotiatedLimits, IOError> {
+        let port_attr = self.query_port(local_port)?;
+        let local_mtu = IbvMtu::try_from(port_attr.active_mtu()).unwrap_or(IbvMtu::Mtu256);
+        Ok(NegotiatedLimits {
+            mtu: local_mtu.min(remote.mtu),
+            max_msg_sz: port_attr.max_msg_sz().min(remote.max_msg_sz),
+        })
+    }
+    // Whether a QP on `port_num` can be connected to another QP on the same port,
+    // which the HCA then loops back internally: the port must be ACTIVE and have an
+    // address to target, a LID on InfiniBand (assigned by the SM) or a GID on RoCE.
+    pub fn supports_loopback(&self, port_num: u8) -> Result<bool, IOError> {
+        let port_attr = self.query_port(port_num)?;
+        if port_attr.state != ffi::ibv_port_state::IBV_PORT_ACTIVE {
+            return Ok(false);
+        }
+        if port_attr.link_layer == ffi::IBV_LINK_LAYER_ETHERNET as u8 {
+            return Ok(self.loopback_gid_index(port_num).is_ok());
+        }
+        Ok(port_attr.lid != 0)
+    }
+    // The GID a RoCE port uses to address itself: the first RoCEv2 entry, else the
+    // first non-empty one.
+    pub(crate) fn loopback_gid_index(&self, port_num: u8) -> Result<u8, IOError> {
+        let gid_tbl_len = self.query_port(port_num)?.gid_tbl_len().min(256);
+        let mut fallback = None;
+        for index in 0..gid_tbl_len {
+            match self.query_gid(port_num, index) {
+                Ok(gid) if !gid.is_zero() => {}
+                _ => continue,
+            }
+            if self.query_gid_type(port_num, index as u32).ok()
+                == Some(ffi::ibv_gid_type_IBV_GID_TYPE_ROCE_V2)
+            {
+                return Ok(index as u8);
+            }
+            fallback.get_or_insert(index as u8);
+        }
+        fallback.ok_or_else(|| {
+            IOError::new(
+                ErrorKind::NotFound,
+                format!("port {} has no usable GID", port_num),
+            )
+        })
+    }
+    // The RoCEv2 GID index carrying `addr`, i.e. the one to use as sgid_index when
+    // talking to IPv4 peers from that address.
+    pub fn find_ipv4_gid_index(&self, port_num: u8, addr: Ipv4Addr) -> Result<u8, IOError> {
+        self.find_gid_index(
+            port_num,
+            &IbvGid::from_ipv4(addr),
+            Some(ffi::ibv_gid_type_IBV_GID_TYPE_ROCE_V2),
+        )
+    }
     pub fn query_pkey(&self, port_num: u8, index: i32) -> Result<u16, IOError> {
         let mut pkey = 0_u16;
         let ret = unsafe {
@@ -119,9 +705,170 @@ impl IbvContext {
         }
         Ok(pkey)
     }
+    // Index of `pkey` (host order, membership bit included) in the port's P_Key table.
+    pub fn find_pkey_index(&self, port_num: u8, pkey: u16) -> Result<u16, IOError> {
+        let ret =
+            unsafe { ffi::ibv_get_pkey_index(self.ibv_context.as_ptr(), port_num, pkey.to_be()) };
+        if ret < 0 {
+            return Err(IOError::new(
+                ErrorKind::NotFound,
+                format!("pkey {:#06x} not found on port {}", pkey, port_num),
+            ));
+        }
+        Ok(ret as u16)
+    }
+    #[inline(always)]
+    pub fn node_type(&self) -> ffi::ibv_node_type::Type {
+        unsafe { (*(*self.ibv_context.as_ptr()).device).node_type }
+    }
+    // Transport of the device, using `port_num`'s link layer to tell RoCE from IB.
+    pub fn transport(&self, port_num: u8) -> Result<IbvTransport, IOError> {
+        let port_attr = self.query_port(port_num)?;
+        let transport_type = unsafe { (*(*self.ibv_context.as_ptr()).device).transport_type };
+        Ok(IbvTransport::classify(transport_type, port_attr.link_layer))
+    }
+    #[inline(always)]
+    pub fn node_guid(&self) -> u64 {
+        u64::from_be(unsafe { ffi::ibv_get_device_guid((*self.ibv_context.as_ptr()).device) })
+    }
+    // Opens the device with node GUID `node_guid` (host order, as `node_guid`
+    // returns it). Unlike the name, the GUID stays the same across a device reset.
+    pub fn open_by_guid(node_guid: u64) -> Result<Self, IOError> {
+        Self::open_matching(
+            |dev| u64::from_be(unsafe { ffi::ibv_get_device_guid(dev) }) == node_guid,
+            || format!("no device with node GUID {:#018x}", node_guid),
+        )
+    }
+    // Opens the device at PCI address `addr`, e.g. "0000:3b:00.0" or "3b:00.0"
+    // (domain 0 implied). Stable where device names are not, e.g. in containers.
+    pub fn open_by_pci_addr(addr: &str) -> Result<Self, IOError> {
+        let addr = addr.trim().to_ascii_lowercase();
+        let addr = if addr.matches(':').count() == 1 {
+            format!("0000:{}", addr)
+        } else {
+            addr
+        };
+        Self::open_matching(
+            |dev| {
+                let ibdev_path = unsafe { CStr::from_ptr((*dev).ibdev_path.as_ptr()) };
+                std::fs::canonicalize(format!("{}/device", ibdev_path.to_string_lossy()))
+                    .ok()
+                    .and_then(|path| path.file_name().map(|name| name.to_string_lossy() == addr))
+                    .unwrap_or(false)
+            },
+            || format!("no device at PCI address {}", addr),
+        )
+    }
+    fn open_matching<P, M>(mut pred: P, not_found: M) -> Result<Self, IOError>
+    where
+        P: FnMut(*mut ffi::ibv_device) -> bool,
+        M: FnOnce() -> String,
+    {
+        let mut num_devs: c_int = 0;
+        let dev_list_ptr = unsafe { ffi::ibv_get_device_list(&mut num_devs) };
+        if dev_list_ptr.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as usize) };
+        let dev = dev_list.iter().copied().find(|&dev| pred(dev));
+        let res = match dev {
+            None => Err(IOError::new(ErrorKind::NotFound, not_found())),
+            Some(dev) => match NonNull::new(unsafe { ffi::ibv_open_device(dev) }) {
+                Some(ibv_context) => Ok(Self { ibv_context }),
+                None => Err(IOError::last_os_error()),
+            },
+        };
+        unsafe { ffi::ibv_free_device_list(dev_list_ptr) };
+        res
+    }
+    // The port GUID, i.e. the interface id of GID 0.
+    pub fn port_guid(&self, port_num: u8) -> Result<u64, IOError> {
+        Ok(u64::from_be(self.query_gid(port_num, 0)?.interface_id()))
+    }
+    pub fn port_id(&self, port_num: u8) -> IbvPortId {
+        IbvPortId {
+            node_guid: self.node_guid(),
+            port_num,
+        }
+    }
+    // Whether (self, port_num) and (other, other_port) are the same physical port,
+    // even if the device was opened twice.
+    pub fn same_port(&self, port_num: u8, other: &IbvContext, other_port: u8) -> bool {
+        self.port_id(port_num) == other.port_id(other_port)
+    }
+    pub fn device_name(&self) -> String {
+        unsafe {
+            CStr::from_ptr(ffi::ibv_get_device_name(
+                (*self.ibv_context.as_ptr()).device,
+            ))
+        }
+        .to_string_lossy()
+        .into_owned()
+    }
+    // sysfs path of the device, e.g. /sys/class/infiniband/mlx5_0
+    pub fn ibdev_path(&self) -> String {
+        unsafe { CStr::from_ptr((*(*self.ibv_context.as_ptr()).device).ibdev_path.as_ptr()) }
+            .to_string_lossy()
+            .into_owned()
+    }
+    #[inline(always)]
+    pub fn num_comp_vectors(&self) -> i32 {
+        unsafe { (*self.ibv_context.as_ptr()).num_comp_vectors }
+    }
+    // CPUs handling completion vector `vector`'s interrupt; see
+    // numa::comp_vector_cpus.
+    pub fn comp_vector_cpus(&self, vector: i32) -> Vec<usize> {
+        numa::comp_vector_cpus(self, vector)
+    }
+    // NUMA node the device is attached to, None if unknown.
+    pub fn numa_node(&self) -> Option<i32> {
+        std::fs::read_to_string(format!("{}/device/numa_node", self.ibdev_path()))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+            .filter(|&node| node >= 0)
+    }
+    // PCI address (e.g. "0000:3b:00.0") of the device, None for devices not on PCI.
+    pub fn pci_address(&self) -> Option<String> {
+        let path = std::fs::canonicalize(format!("{}/device", self.ibdev_path())).ok()?;
+        Some(path.file_name()?.to_string_lossy().into_owned())
+    }
+    // Network interfaces backed by the device (sysfs device/net), e.g. the netdev
+    // of a RoCE port; empty for InfiniBand-only devices.
+    pub fn netdevs(&self) -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(format!("{}/device/net", self.ibdev_path()))
+            .map(|dir| {
+                dir.filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().into_owned())
+                    .collect()
+            })
+            .unwrap_or_default();
+        names.sort();
+        names
+    }
+    pub fn num_ports(&self) -> Result<u8, IOError> {
+        Ok(self.query_device()?.phys_port_cnt())
+    }
     pub fn as_mut_ptr(&self) -> *mut ffi::ibv_context {
         self.ibv_context.as_ptr()
     }
+    // Opens a context on the command fd of another process's context, received over
+    // a unix socket with SCM_RIGHTS; PDs and MRs that process created can then be
+    // imported with ImportedPd / ImportedMr by handle. libibverbs has no import for
+    // QPs or CQs, so those are created in the importing process.
+    pub fn import(cmd_fd: RawFd) -> Result<Self, IOError> {
+        let ibv_context = unsafe { ffi::ibv_import_device(cmd_fd) };
+        match NonNull::new(ibv_context) {
+            Some(ibv_context) => Ok(Self { ibv_context }),
+            None => Err(verbs_error("ibv_import_device", IOError::last_os_error())),
+        }
+    }
+    // The fd to hand to `import` in another process.
+    #[inline(always)]
+    pub fn cmd_fd(&self) -> RawFd {
+        unsafe { (*self.ibv_context.as_ptr()).cmd_fd }
+    }
 }
 
 impl Drop for IbvContext {
@@ -152,6 +899,22 @@ impl IbvPd {
             })
         }
     }
+    // Registers every region, one result per region in input order; a failure
+    // doesn't stop the rest of the batch.
+    pub fn reg_mr_batch(
+        &self,
+        regions: &[(&[u8], ibv_access_flags)],
+    ) -> Vec<Result<IbvMr, IOError>> {
+        regions
+            .iter()
+            .map(|&(region, access)| IbvMr::new(self, region, access))
+            .collect()
+    }
+    // What ImportedPd::import needs in another process.
+    #[inline(always)]
+    pub fn handle(&self) -> u32 {
+        unsafe { self.ibv_pd.as_ref().handle }
+    }
     pub fn as_mut_ptr(&self) -> *mut ffi::ibv_pd {
         self.ibv_pd.as_ptr()
     }
@@ -168,9 +931,169 @@ impl Drop for IbvPd {
 unsafe impl Send for IbvPd {}
 unsafe impl Sync for IbvPd {}
 
+// A PD owned by another process, imported into a context from IbvContext::import.
+// It derefs to IbvPd; dropping it only releases the local object, the PD itself
+// lives until its owner deallocates it.
+pub struct ImportedPd {
+    pd: ManuallyDrop<IbvPd>,
+}
+
+impl ImportedPd {
+    pub fn import(context: &IbvContext, handle: u32) -> Result<Self, IOError> {
+        let ibv_pd = unsafe { ffi::ibv_import_pd(context.as_mut_ptr(), handle) };
+        match NonNull::new(ibv_pd) {
+            Some(ibv_pd) => Ok(Self {
+                pd: ManuallyDrop::new(IbvPd { ibv_pd }),
+            }),
+            None => Err(verbs_error("ibv_import_pd", IOError::last_os_error())),
+        }
+    }
+}
+
+impl Deref for ImportedPd {
+    type Target = IbvPd;
+    fn deref(&self) -> &IbvPd {
+        &self.pd
+    }
+}
+
+impl Drop for ImportedPd {
+    fn drop(&mut self) {
+        unsafe { ffi::ibv_unimport_pd(self.pd.as_mut_ptr()) };
+    }
+}
+
+// A thread domain: resources created under it are promised to be used from a single
+// thread at a time, so the provider can skip its internal locking.
+pub struct IbvTd {
+    ibv_td: NonNull<ffi::ibv_td>,
+}
+
+impl IbvTd {
+    pub fn new(context: &IbvContext) -> Result<Self, IOError> {
+        let alloc_td = verbs_ctx_op!(context.as_mut_ptr(), alloc_td)
+            .ok_or_else(|| unsupported_verb("ibv_alloc_td"))?;
+        let mut init_attr = ffi::ibv_td_init_attr { comp_mask: 0 };
+        let ibv_td = unsafe { alloc_td(context.as_mut_ptr(), &mut init_attr) };
+        if ibv_td.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_td: NonNull::new_unchecked(ibv_td),
+            })
+        }
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_td {
+        self.ibv_td.as_ptr()
+    }
+}
+
+impl Drop for IbvTd {
+    fn drop(&mut self) {
+        let ret = match verbs_ctx_op!(self.ibv_td.as_ref().context, dealloc_td) {
+            Some(dealloc_td) => unsafe { dealloc_td(self.ibv_td.as_ptr()) },
+            None => libc::EOPNOTSUPP,
+        };
+        if ret != 0 {
+            panic!(
+                "ibv_dealloc_td(). errno: {}",
+                IOError::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+unsafe impl Send for IbvTd {}
+unsafe impl Sync for IbvTd {}
+
+// A PD bound to an optional thread domain. It derefs to IbvPd, so QPs and MRs can
+// be created in it directly. `pd` and `td` must outlive the parent domain.
+pub struct IbvParentDomain {
+    pd: IbvPd,
+    single_threaded: bool,
+}
+
+impl IbvParentDomain {
+    pub fn new(context: &IbvContext, pd: &IbvPd, td: Option<&IbvTd>) -> Result<Self, IOError> {
+        let alloc_parent_domain = verbs_ctx_op!(context.as_mut_ptr(), alloc_parent_domain)
+            .ok_or_else(|| unsupported_verb("ibv_alloc_parent_domain"))?;
+        let mut attr = unsafe { std::mem::zeroed::<ffi::ibv_parent_domain_init_attr>() };
+        attr.pd = pd.as_mut_ptr();
+        attr.td = td.map_or(std::ptr::null_mut(), IbvTd::as_mut_ptr);
+        let ibv_pd = unsafe { alloc_parent_domain(context.as_mut_ptr(), &mut attr) };
+        if ibv_pd.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                pd: IbvPd {
+                    ibv_pd: NonNull::new_unchecked(ibv_pd),
+                },
+                single_threaded: td.is_some(),
+            })
+        }
+    }
+    #[inline(always)]
+    pub fn has_td(&self) -> bool {
+        self.single_threaded
+    }
+}
+
+impl Deref for IbvParentDomain {
+    type Target = IbvPd;
+    fn deref(&self) -> &IbvPd {
+        &self.pd
+    }
+}
+
+// Hands out completion vectors round-robin so that CQs, and the interrupts behind
+// them, spread over the device's vectors.
+pub struct CompVectorAllocator {
+    vectors: Vec<i32>,
+    next: AtomicUsize,
+}
+
+impl CompVectorAllocator {
+    pub fn new(context: &IbvContext) -> Self {
+        Self::with_vectors((0..context.num_comp_vectors().max(1)).collect())
+    }
+    // Cycles over `vectors` only, e.g. the ones whose IRQs are affine to CPUs on the
+    // device's NUMA node (see /proc/irq/*/smp_affinity_list). `numa_local` picks
+    // those itself where the driver's IRQ names allow it.
+    pub fn with_vectors(vectors: Vec<i32>) -> Self {
+        Self {
+            vectors: if vectors.is_empty() { vec![0] } else { vectors },
+            next: AtomicUsize::new(0),
+        }
+    }
+    // Cycles over the vectors served on the device's NUMA node, falling back to
+    // all of them when those can't be determined.
+    pub fn numa_local(context: &IbvContext) -> Self {
+        match context.numa_node() {
+            Some(node) => match numa::local_comp_vectors(context, node) {
+                vectors if !vectors.is_empty() => Self::with_vectors(vectors),
+                _ => Self::new(context),
+            },
+            None => Self::new(context),
+        }
+    }
+    pub fn next(&self) -> i32 {
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        self.vectors[i % self.vectors.len()]
+    }
+    pub fn vectors(&self) -> &[i32] {
+        &self.vectors
+    }
+}
+
 #[derive(Clone)]
 pub struct IbvCq {
     ibv_cq: NonNull<ffi::ibv_cq>,
+    // ibv_cq doesn't keep the vector it was created on.
+    comp_vector: i32,
+    // A non-owning handle to the channel passed at creation. The caller keeps the
+    // channel itself alive; it can't be destroyed while this CQ exists anyway.
+    channel: Option<ManuallyDrop<IbvCompChannel>>,
 }
 
 impl IbvCq {
@@ -185,7 +1108,7 @@ impl IbvCq {
             Some(p) => p.as_ptr(),
             None => std::ptr::null_mut::<T>(),
         };
-        let channel = match channel {
+        let channel_ptr = match channel {
             Some(p) => p.ibv_comp_channel.as_ptr(),
             None => std::ptr::null_mut(),
         };
@@ -195,23 +1118,58 @@ impl IbvCq {
                 context.ibv_context.as_ptr(),
                 cqe,
                 cq_context as *mut c_void,
-                channel,
+                channel_ptr,
                 comp_vector,
             )
         };
         if ibv_cq.is_null() {
-            return Err(IOError::last_os_error());
+            return Err(verbs_error("ibv_create_cq", IOError::last_os_error()));
         }
         unsafe {
             Ok(Self {
                 ibv_cq: NonNull::new_unchecked(ibv_cq),
+                comp_vector,
+                channel: channel.map(|c| {
+                    ManuallyDrop::new(IbvCompChannel {
+                        ibv_comp_channel: c.ibv_comp_channel,
+                    })
+                }),
             })
         }
     }
+    // Like `new`, with the completion vector taken from `vectors`.
+    pub fn with_allocator(
+        context: &IbvContext,
+        cqe: i32,
+        channel: Option<&IbvCompChannel>,
+        vectors: &CompVectorAllocator,
+    ) -> Result<Self, IOError> {
+        Self::new::<c_void>(context, cqe, None, channel, vectors.next())
+    }
+    #[inline(always)]
+    pub fn comp_vector(&self) -> i32 {
+        self.comp_vector
+    }
+    #[inline(always)]
+    pub fn channel(&self) -> Option<&IbvCompChannel> {
+        self.channel.as_deref()
+    }
+    // fd of the completion channel the CQ reports events to, if any.
+    pub fn channel_fd(&self) -> Option<RawFd> {
+        let channel = unsafe { self.ibv_cq.as_ref().channel };
+        if channel.is_null() {
+            return None;
+        }
+        Some(unsafe { (*channel).fd })
+    }
 
+    // Not safe to call on the same CQ from several threads at once: most providers
+    // don't lock the CQ ring, so concurrent polls can hand out a completion twice or
+    // corrupt the consumer index. Share a CQ between threads through `SyncCq`.
     pub fn poll<'a>(&self, cqe_arr: &'a mut [IbvWc]) -> Result<&'a [IbvWc], ()> {
+        let ibv_poll_cq =
+            ctx_ops_fn!(self.ibv_cq.as_ref().context, poll_cq, "ibv_poll_cq").map_err(|_| ())?;
         let n = unsafe {
-            let ibv_poll_cq = (*(*self.ibv_cq.as_ptr()).context).ops.poll_cq.unwrap();
             ibv_poll_cq(
                 self.ibv_cq.as_ptr(),
                 cqe_arr.len() as i32,
@@ -224,19 +1182,127 @@ impl IbvCq {
         Ok(&mut cqe_arr[0..n as usize])
     }
 
-    pub fn resize(&self, cqe: i32) -> Result<(), IOError> {
-        let ret = unsafe { ffi::ibv_resize_cq(self.ibv_cq.as_ptr(), cqe) };
+    pub fn req_notify(&self, solicited_only: bool) -> Result<(), IOError> {
+        let ibv_req_notify_cq = ctx_ops_fn!(
+            self.ibv_cq.as_ref().context,
+            req_notify_cq,
+            "ibv_req_notify_cq"
+        )?;
+        let ret = unsafe { ibv_req_notify_cq(self.ibv_cq.as_ptr(), solicited_only as c_int) };
         if ret != 0 {
-            return Err(IOError::last_os_error());
+            return Err(IOError::from_raw_os_error(ret));
         }
         Ok(())
     }
-    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_cq {
-        self.ibv_cq.as_ptr()
+
+    pub fn ack_events(&self, nevents: u32) {
+        unsafe { ffi::ibv_ack_cq_events(self.ibv_cq.as_ptr(), nevents) };
     }
-}
 
-impl Drop for IbvCq {
+    // Waits on `channel` until completions are available and returns them. The CQ is
+    // drained, and only re-armed when empty; after arming it is drained once more
+    // before sleeping, so a completion landing between the poll and the re-arm
+    // can't be missed. `channel` must serve this CQ only: an event for another CQ
+    // is acknowledged on that CQ and returned as an error, since it would be lost
+    // to whoever waits on it.
+    pub fn blocking_poll<'a>(
+        &self,
+        channel: &IbvCompChannel,
+        buf: &'a mut [IbvWc],
+    ) -> Result<&'a [IbvWc], IOError> {
+        loop {
+            let n = self
+                .poll(buf)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?
+                .len();
+            if n > 0 {
+                return Ok(&buf[..n]);
+            }
+            self.req_notify(false)?;
+            let n = self
+                .poll(buf)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?
+                .len();
+            if n > 0 {
+                return Ok(&buf[..n]);
+            }
+            let cq = channel.get_cq_event()?;
+            unsafe { ffi::ibv_ack_cq_events(cq.as_ptr(), 1) };
+            if cq != self.ibv_cq {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    "completion channel delivered an event for another CQ",
+                ));
+            }
+        }
+    }
+
+    // `blocking_poll` on the channel the CQ was created with.
+    pub fn wait_poll<'a>(&self, buf: &'a mut [IbvWc]) -> Result<&'a [IbvWc], IOError> {
+        let channel = self.channel().ok_or_else(|| {
+            IOError::new(
+                ErrorKind::InvalidInput,
+                "the CQ was created without a completion channel",
+            )
+        })?;
+        self.blocking_poll(channel, buf)
+    }
+
+    // Entries the CQ actually has, at least what it was created or resized with.
+    #[inline(always)]
+    pub fn cqe(&self) -> i32 {
+        unsafe { self.ibv_cq.as_ref().cqe }
+    }
+    pub fn resize(&self, cqe: i32) -> Result<(), IOError> {
+        let ret = unsafe { ffi::ibv_resize_cq(self.ibv_cq.as_ptr(), cqe) };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+    // Interrupt moderation: a completion event is generated once `count`
+    // completions have accumulated or `period_us` microseconds have passed since
+    // the first of them. (0, 0) turns it off again. Fails with Unsupported on
+    // devices without CQ moderation and InvalidInput beyond their limits.
+    pub fn set_moderation(&self, count: u16, period_us: u16) -> Result<(), IOError> {
+        let context = unsafe { self.ibv_cq.as_ref().context };
+        let caps = query_device_ex(context)?.cq_mod_caps;
+        if caps.max_cq_count == 0 || caps.max_cq_period == 0 {
+            return Err(unsupported_verb("ibv_modify_cq"));
+        }
+        if count > caps.max_cq_count || period_us > caps.max_cq_period {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "moderation {}/{}us exceeds device limits {}/{}us",
+                    count, period_us, caps.max_cq_count, caps.max_cq_period
+                ),
+            ));
+        }
+        let modify_cq =
+            verbs_ctx_op!(context, modify_cq).ok_or_else(|| unsupported_verb("ibv_modify_cq"))?;
+        let mut attr = ffi::ibv_modify_cq_attr {
+            attr_mask: ffi::ibv_cq_attr_mask::IBV_CQ_ATTR_MODERATE.0,
+            moderate: ffi::ibv_moderate_cq {
+                cq_count: count,
+                cq_period: period_us,
+            },
+        };
+        let ret = unsafe { modify_cq(self.ibv_cq.as_ptr(), &mut attr) };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_cq",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_cq {
+        self.ibv_cq.as_ptr()
+    }
+}
+
+impl Drop for IbvCq {
     fn drop(&mut self) {
         let ret = unsafe { ffi::ibv_destroy_cq(self.ibv_cq.as_ptr()) };
         if ret != 0 {
@@ -248,6 +1314,506 @@ impl Drop for IbvCq {
 unsafe impl Send for IbvCq {}
 unsafe impl Sync for IbvCq {}
 
+// A CQ that may be drained from several threads: polls are serialized by a lock.
+pub struct SyncCq {
+    cq: IbvCq,
+    lock: Mutex<()>,
+}
+
+impl SyncCq {
+    pub fn new(cq: IbvCq) -> Self {
+        Self {
+            cq,
+            lock: Mutex::new(()),
+        }
+    }
+    // Waits for any other poller to finish, then polls.
+    pub fn poll<'a>(&self, cqe_arr: &'a mut [IbvWc]) -> Result<&'a [IbvWc], IOError> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.cq
+            .poll(cqe_arr)
+            .map_err(|_| IOError::other("ibv_poll_cq() failed"))
+    }
+    // Polls unless another thread is polling right now, in which case it returns
+    // None rather than waiting; that thread is reaping the completions anyway.
+    pub fn try_poll<'a>(&self, cqe_arr: &'a mut [IbvWc]) -> Option<Result<&'a [IbvWc], IOError>> {
+        let _guard = match self.lock.try_lock() {
+            Ok(guard) => guard,
+            Err(std::sync::TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(std::sync::TryLockError::WouldBlock) => return None,
+        };
+        Some(
+            self.cq
+                .poll(cqe_arr)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed")),
+        )
+    }
+    #[inline(always)]
+    pub fn req_notify(&self, solicited_only: bool) -> Result<(), IOError> {
+        self.cq.req_notify(solicited_only)
+    }
+    // The wrapped CQ, e.g. to create QPs on it. Don't poll it directly.
+    #[inline(always)]
+    pub fn cq(&self) -> &IbvCq {
+        &self.cq
+    }
+    pub fn into_inner(self) -> IbvCq {
+        self.cq
+    }
+}
+
+// A CQ created through ibv_create_cq_ex. Completions are read in place with
+// start_poll / next_poll / end_poll and the read_* getters instead of copying out
+// an ibv_wc; the getters are only valid between a successful start/next_poll and
+// end_poll, and only for fields requested in `wc_flags`. wr_id, status and the
+// opcode are always there, so IBV_WC_EX_WITH_BYTE_LEN alone gives the leanest poll.
+pub struct IbvCqEx {
+    ibv_cq_ex: NonNull<ffi::ibv_cq_ex>,
+    // The fields the provider fills in; reading any other one returns garbage.
+    wc_flags: WcFlags,
+}
+
+impl IbvCqEx {
+    pub fn new(
+        context: &IbvContext,
+        cqe: u32,
+        channel: Option<&IbvCompChannel>,
+        comp_vector: u32,
+        wc_flags: WcFlags,
+    ) -> Result<Self, IbvCqExError> {
+        let mut attr = unsafe { std::mem::zeroed::<IbvCqInitAttrEx>() };
+        attr.cqe = cqe;
+        attr.channel = channel.map_or(std::ptr::null_mut(), |c| c.as_mut_ptr());
+        attr.comp_vector = comp_vector;
+        attr.wc_flags = wc_flags.0 as u64;
+        Self::with_attr(context, &mut attr)
+    }
+    // Creates the CQ inside `parent_domain`. When the domain has a thread domain the
+    // CQ is also created single-threaded: the provider drops its CQ lock, so the CQ
+    // must then only be polled and armed from one thread at a time.
+    pub fn with_parent_domain(
+        context: &IbvContext,
+        cqe: u32,
+        channel: Option<&IbvCompChannel>,
+        comp_vector: u32,
+        wc_flags: WcFlags,
+        parent_domain: &IbvParentDomain,
+    ) -> Result<Self, IbvCqExError> {
+        let mut attr = unsafe { std::mem::zeroed::<IbvCqInitAttrEx>() };
+        attr.cqe = cqe;
+        attr.channel = channel.map_or(std::ptr::null_mut(), |c| c.as_mut_ptr());
+        attr.comp_vector = comp_vector;
+        attr.wc_flags = wc_flags.0 as u64;
+        attr.comp_mask = ffi::ibv_cq_init_attr_mask::IBV_CQ_INIT_ATTR_MASK_PD.0;
+        attr.parent_domain = parent_domain.as_mut_ptr();
+        if parent_domain.has_td() {
+            attr.comp_mask |= ffi::ibv_cq_init_attr_mask::IBV_CQ_INIT_ATTR_MASK_FLAGS.0;
+            attr.flags = ffi::ibv_create_cq_attr_flags::IBV_CREATE_CQ_ATTR_SINGLE_THREADED.0;
+        }
+        Self::with_attr(context, &mut attr)
+    }
+    pub fn with_attr(
+        context: &IbvContext,
+        attr: &mut IbvCqInitAttrEx,
+    ) -> Result<Self, IbvCqExError> {
+        let timestamps = ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP
+            | ffi::ibv_create_cq_wc_flags::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP_WALLCLOCK;
+        if attr.wc_flags & timestamps.0 as u64 != 0 && !context.supports_hw_timestamps() {
+            return Err(IbvCqExError::TimestampsUnsupported);
+        }
+        let create_cq_ex = verbs_ctx_op!(context.as_mut_ptr(), create_cq_ex)
+            .ok_or_else(|| unsupported_verb("ibv_create_cq_ex"))?;
+        let ibv_cq_ex = unsafe { create_cq_ex(context.as_mut_ptr(), attr as *mut _) };
+        if ibv_cq_ex.is_null() {
+            return Err(verbs_error("ibv_create_cq", IOError::last_os_error()).into());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_cq_ex: NonNull::new_unchecked(ibv_cq_ex),
+                wc_flags: ffi::ibv_create_cq_wc_flags(attr.wc_flags as u32),
+            })
+        }
+    }
+    #[inline(always)]
+    pub fn wc_flags(&self) -> WcFlags {
+        self.wc_flags
+    }
+    // Catches reads of fields the CQ was not created with, in debug builds.
+    #[inline(always)]
+    fn debug_check_field(&self, flag: WcFlags, field: &str) {
+        debug_assert!(
+            self.wc_flags.0 & flag.0 != 0,
+            "IbvCqEx: {} read but not requested in wc_flags",
+            field
+        );
+    }
+    // Returns false if the CQ is empty, in which case end_poll must not be called.
+    pub fn start_poll(&self) -> Result<bool, IOError> {
+        let mut attr = ffi::ibv_poll_cq_attr { comp_mask: 0 };
+        let ret = unsafe {
+            ((*self.ibv_cq_ex.as_ptr()).start_poll.unwrap())(self.ibv_cq_ex.as_ptr(), &mut attr)
+        };
+        match ret {
+            0 => Ok(true),
+            libc::ENOENT => Ok(false),
+            _ => Err(IOError::from_raw_os_error(ret)),
+        }
+    }
+    pub fn next_poll(&self) -> Result<bool, IOError> {
+        let ret =
+            unsafe { ((*self.ibv_cq_ex.as_ptr()).next_poll.unwrap())(self.ibv_cq_ex.as_ptr()) };
+        match ret {
+            0 => Ok(true),
+            libc::ENOENT => Ok(false),
+            _ => Err(IOError::from_raw_os_error(ret)),
+        }
+    }
+    #[inline(always)]
+    pub fn end_poll(&self) {
+        unsafe { ((*self.ibv_cq_ex.as_ptr()).end_poll.unwrap())(self.ibv_cq_ex.as_ptr()) }
+    }
+    #[inline(always)]
+    pub fn wr_id(&self) -> u64 {
+        unsafe { self.ibv_cq_ex.as_ref().wr_id }
+    }
+    #[inline(always)]
+    pub fn status(&self) -> ffi::ibv_wc_status::Type {
+        unsafe { self.ibv_cq_ex.as_ref().status }
+    }
+    #[inline(always)]
+    pub fn read_opcode(&self) -> ffi::ibv_wc_opcode::Type {
+        unsafe { ((*self.ibv_cq_ex.as_ptr()).read_opcode.unwrap())(self.ibv_cq_ex.as_ptr()) }
+    }
+    #[inline(always)]
+    pub fn read_byte_len(&self) -> u32 {
+        self.debug_check_field(WcFlags::IBV_WC_EX_WITH_BYTE_LEN, "byte_len");
+        unsafe { ((*self.ibv_cq_ex.as_ptr()).read_byte_len.unwrap())(self.ibv_cq_ex.as_ptr()) }
+    }
+    #[inline(always)]
+    pub fn read_qp_num(&self) -> u32 {
+        self.debug_check_field(WcFlags::IBV_WC_EX_WITH_QP_NUM, "qp_num");
+        unsafe { ((*self.ibv_cq_ex.as_ptr()).read_qp_num.unwrap())(self.ibv_cq_ex.as_ptr()) }
+    }
+    // Raw HCA clock ticks; see IbvDeviceAttrEx::hca_core_clock for the frequency.
+    #[inline(always)]
+    pub fn read_completion_ts(&self) -> u64 {
+        self.debug_check_field(
+            WcFlags::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP,
+            "completion_ts",
+        );
+        unsafe { ((*self.ibv_cq_ex.as_ptr()).read_completion_ts.unwrap())(self.ibv_cq_ex.as_ptr()) }
+    }
+    #[inline(always)]
+    pub fn read_completion_wallclock_ns(&self) -> u64 {
+        self.debug_check_field(
+            WcFlags::IBV_WC_EX_WITH_COMPLETION_TIMESTAMP_WALLCLOCK,
+            "completion_wallclock_ns",
+        );
+        unsafe {
+            ((*self.ibv_cq_ex.as_ptr())
+                .read_completion_wallclock_ns
+                .unwrap())(self.ibv_cq_ex.as_ptr())
+        }
+    }
+    // The current completion's free-running timestamp as wall-clock time, for
+    // correlating with other systems' logs; needs IBV_WC_EX_WITH_COMPLETION_TIMESTAMP.
+    pub fn completion_wallclock(&self, clock: &HcaClock<'_>) -> Result<SystemTime, IOError> {
+        clock.to_system_time(self.read_completion_ts())
+    }
+    // The plain CQ view, for ibv_create_qp and the other verbs that take an ibv_cq.
+    pub fn as_cq_ptr(&self) -> *mut ffi::ibv_cq {
+        self.ibv_cq_ex.as_ptr() as *mut ffi::ibv_cq
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_cq_ex {
+        self.ibv_cq_ex.as_ptr()
+    }
+}
+
+// Maps HCA clock ticks to CLOCK_REALTIME through an anchor pair of readings taken
+// back to back, re-read once it is older than `refresh` so the two clocks' drift
+// stays bounded.
+pub struct HcaClock<'a> {
+    context: &'a IbvContext,
+    khz: u64,
+    mask: u64,
+    refresh: Duration,
+    // (HCA ticks, realtime, when it was taken)
+    anchor: Mutex<(u64, SystemTime, Instant)>,
+}
+
+impl<'a> HcaClock<'a> {
+    pub fn new(context: &'a IbvContext, refresh: Duration) -> Result<Self, IOError> {
+        let attr = context.query_device_ex()?;
+        if attr.hca_core_clock == 0 || attr.completion_timestamp_mask == 0 {
+            return Err(IOError::new(
+                ErrorKind::Unsupported,
+                "the device reports no HCA clock frequency or timestamp mask",
+            ));
+        }
+        let anchor = Self::read_anchor(context)?;
+        Ok(Self {
+            context,
+            khz: attr.hca_core_clock,
+            mask: attr.completion_timestamp_mask,
+            refresh,
+            anchor: Mutex::new(anchor),
+        })
+    }
+    fn read_anchor(context: &IbvContext) -> Result<(u64, SystemTime, Instant), IOError> {
+        let ticks = context.read_hw_clock()?;
+        Ok((ticks, SystemTime::now(), Instant::now()))
+    }
+    // Ticks within half the timestamp range of the anchor, before or after it, map
+    // correctly across a counter wrap.
+    pub fn to_system_time(&self, ticks: u64) -> Result<SystemTime, IOError> {
+        let mut anchor = self.anchor.lock().unwrap();
+        if anchor.2.elapsed() >= self.refresh {
+            *anchor = Self::read_anchor(self.context)?;
+        }
+        let (anchor_ticks, anchor_time, _) = *anchor;
+        let delta = ticks.wrapping_sub(anchor_ticks) & self.mask;
+        let (before, delta) = if delta > self.mask / 2 {
+            (true, (self.mask - delta).wrapping_add(1) & self.mask)
+        } else {
+            (false, delta)
+        };
+        let ns = Duration::from_nanos((delta as u128 * 1_000_000 / self.khz as u128) as u64);
+        Ok(if before {
+            anchor_time - ns
+        } else {
+            anchor_time + ns
+        })
+    }
+}
+
+impl Drop for IbvCqEx {
+    fn drop(&mut self) {
+        let ret = unsafe { ffi::ibv_destroy_cq(self.as_cq_ptr()) };
+        if ret != 0 {
+            panic!("ibv_destroy_cq(). errno: {}", IOError::last_os_error());
+        }
+    }
+}
+unsafe impl Send for IbvCqEx {}
+unsafe impl Sync for IbvCqEx {}
+
+// Typed iteration over the completions returned by a poll.
+pub struct CompletionBatch<'a> {
+    iter: slice::Iter<'a, IbvWc>,
+}
+
+impl<'a> CompletionBatch<'a> {
+    pub fn new(wcs: &'a [IbvWc]) -> Self {
+        Self { iter: wcs.iter() }
+    }
+}
+
+impl<'a> From<&'a [IbvWc]> for CompletionBatch<'a> {
+    fn from(wcs: &'a [IbvWc]) -> Self {
+        Self::new(wcs)
+    }
+}
+
+impl<'a> Iterator for CompletionBatch<'a> {
+    type Item = Completion<'a>;
+    fn next(&mut self) -> Option<Completion<'a>> {
+        self.iter.next().map(Completion)
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.iter.size_hint()
+    }
+}
+
+impl ExactSizeIterator for CompletionBatch<'_> {}
+
+// What a completion reports, decoded from status, opcode and wc_flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionEvent {
+    SendDone {
+        wr_id: u64,
+    },
+    WriteDone {
+        wr_id: u64,
+    },
+    ReadDone {
+        wr_id: u64,
+    },
+    AtomicDone {
+        wr_id: u64,
+    },
+    RecvData {
+        wr_id: u64,
+        len: u32,
+    },
+    RecvWithImm {
+        wr_id: u64,
+        len: u32,
+        imm: u32,
+    },
+    // The peer's RDMA_WRITE_WITH_IMM landed; `len` bytes were written to the MR.
+    WriteWithImm {
+        wr_id: u64,
+        len: u32,
+        imm: u32,
+    },
+    // A tag-matching SRQ operation: tag list updates (TM_ADD, TM_DEL, TM_SYNC)
+    // complete on the SRQ's CQ, and so do messages, matched (TM_RECV, `len` bytes
+    // in the tag's buffer) or unexpected (TM_NO_TAG, landed in an SRQ receive).
+    TagMatching {
+        wr_id: u64,
+        opcode: ffi::ibv_wc_opcode::Type,
+        len: u32,
+    },
+    // On error only wr_id and status are valid; the opcode is undefined.
+    Error {
+        wr_id: u64,
+        status: ffi::ibv_wc_status::Type,
+    },
+    Other {
+        wr_id: u64,
+        opcode: ffi::ibv_wc_opcode::Type,
+    },
+}
+
+// The protection and access failures a completion can report, decoded so the
+// usual cause can be logged along with the wr_id. A RemoteAccess on an RDMA
+// operation usually means the peer's token went stale.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessError {
+    // IBV_WC_LOC_PROT_ERR: a local SGE isn't covered by the MR of its lkey.
+    LocalProtection,
+    // IBV_WC_LOC_ACCESS_ERR: an incoming write or atomic hit local memory without
+    // the matching remote access flag.
+    LocalAccess,
+    // IBV_WC_REM_ACCESS_ERR: the peer rejected the rkey or the range.
+    RemoteAccess,
+    // IBV_WC_REM_INV_REQ_ERR: the peer rejected the request itself.
+    RemoteInvalidRequest,
+    // IBV_WC_MW_BIND_ERR: a memory window bind was refused.
+    MwBind,
+}
+
+impl AccessError {
+    pub fn from_status(status: ffi::ibv_wc_status::Type) -> Option<Self> {
+        match status {
+            ffi::ibv_wc_status::IBV_WC_LOC_PROT_ERR => Some(AccessError::LocalProtection),
+            ffi::ibv_wc_status::IBV_WC_LOC_ACCESS_ERR => Some(AccessError::LocalAccess),
+            ffi::ibv_wc_status::IBV_WC_REM_ACCESS_ERR => Some(AccessError::RemoteAccess),
+            ffi::ibv_wc_status::IBV_WC_REM_INV_REQ_ERR => Some(AccessError::RemoteInvalidRequest),
+            ffi::ibv_wc_status::IBV_WC_MW_BIND_ERR => Some(AccessError::MwBind),
+            _ => None,
+        }
+    }
+    // The likely causes, for logs.
+    pub fn hint(self) -> &'static str {
+        match self {
+            AccessError::LocalProtection => {
+                "a local SGE lies outside its MR, or the lkey belongs to a deregistered MR or another PD"
+            }
+            AccessError::LocalAccess => {
+                "the local MR lacks the remote access flag the incoming operation needs"
+            }
+            AccessError::RemoteAccess => {
+                "the remote token is stale (MR deregistered or window invalidated), the range \
+                 exceeds the remote region, or the region lacks the requested remote access"
+            }
+            AccessError::RemoteInvalidRequest => {
+                "the peer can't execute the operation: message over its max_msg_sz, opcode not \
+                 allowed on the QP, or remote atomics not enabled"
+            }
+            AccessError::MwBind => "the memory window bind was invalid for its MR or QP",
+        }
+    }
+}
+
+impl std::fmt::Display for AccessError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self, self.hint())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Completion<'a>(&'a IbvWc);
+
+impl<'a> Completion<'a> {
+    #[inline(always)]
+    pub fn wr_id(&self) -> u64 {
+        self.0.wr_id
+    }
+    #[inline(always)]
+    pub fn status(&self) -> ffi::ibv_wc_status::Type {
+        self.0.status
+    }
+    #[inline(always)]
+    pub fn is_success(&self) -> bool {
+        self.0.status == ffi::ibv_wc_status::IBV_WC_SUCCESS
+    }
+    #[inline(always)]
+    pub fn status_str(&self) -> &'static str {
+        self.0.status_str()
+    }
+    #[inline(always)]
+    pub fn opcode(&self) -> ffi::ibv_wc_opcode::Type {
+        self.0.opcode
+    }
+    #[inline(always)]
+    pub fn byte_len(&self) -> u32 {
+        self.0.byte_len
+    }
+    #[inline(always)]
+    pub fn qp_num(&self) -> u32 {
+        self.0.qp_num
+    }
+    // Host-order immediate data, if the completion carries any.
+    #[inline(always)]
+    pub fn imm_data(&self) -> Option<u32> {
+        self.0.imm_data()
+    }
+    #[inline(always)]
+    pub fn imm_data_raw(&self) -> Option<u32> {
+        self.0.imm_data_raw()
+    }
+    #[inline(always)]
+    pub fn has_grh(&self) -> bool {
+        self.0.has_grh()
+    }
+    #[inline(always)]
+    pub fn classify(&self) -> CompletionEvent {
+        self.0.classify()
+    }
+    #[inline(always)]
+    pub fn access_error(&self) -> Option<AccessError> {
+        self.0.access_error()
+    }
+    #[inline(always)]
+    pub fn raw(&self) -> &'a IbvWc {
+        self.0
+    }
+}
+
+// A reusable completion buffer so the hot path neither allocates nor re-zeroes per poll.
+pub struct IbvWcBuffer {
+    wcs: Vec<IbvWc>,
+}
+
+impl IbvWcBuffer {
+    pub fn with_capacity(n: usize) -> Self {
+        Self {
+            wcs: vec![unsafe { std::mem::zeroed::<IbvWc>() }; n],
+        }
+    }
+    pub fn poll_into(&mut self, cq: &IbvCq) -> Result<&[IbvWc], IOError> {
+        match cq.poll(&mut self.wcs) {
+            Ok(wcs) => Ok(wcs),
+            Err(()) => Err(IOError::other("ibv_poll_cq() failed")),
+        }
+    }
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.wcs.len()
+    }
+}
+
 #[derive(Clone)]
 pub struct IbvCompChannel {
     ibv_comp_channel: NonNull<ffi::ibv_comp_channel>,
@@ -265,6 +1831,19 @@ impl IbvCompChannel {
             })
         }
     }
+    // Blocks until one of the CQs bound to this channel fires; the event must be
+    // acknowledged with `IbvCq::ack_events` on that CQ.
+    pub fn get_cq_event(&self) -> Result<NonNull<ffi::ibv_cq>, IOError> {
+        let mut cq = std::ptr::null_mut::<ffi::ibv_cq>();
+        let mut cq_context = std::ptr::null_mut::<c_void>();
+        let ret = unsafe {
+            ffi::ibv_get_cq_event(self.ibv_comp_channel.as_ptr(), &mut cq, &mut cq_context)
+        };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        unsafe { Ok(NonNull::new_unchecked(cq)) }
+    }
     pub fn as_mut_ptr(&self) -> *mut ffi::ibv_comp_channel {
         self.ibv_comp_channel.as_ptr()
     }
@@ -281,13 +1860,152 @@ impl Drop for IbvCompChannel {
     }
 }
 
+// Hardware counters from the flow-steering stats API. Each `attach` call appends a
+// counter point and returns its index in `read()`. Counters are bound at flow
+// creation through an IBV_FLOW_SPEC_ACTION_COUNT spec; core verbs have no way to
+// attach them to a QP directly.
+pub struct IbvCounters {
+    ibv_counters: NonNull<ffi::ibv_counters>,
+    ncounters: u32,
+}
+
+impl IbvCounters {
+    pub fn new(context: &IbvContext) -> Result<Self, IOError> {
+        let create_counters = verbs_ctx_op!(context.as_mut_ptr(), create_counters)
+            .ok_or_else(|| unsupported_verb("ibv_create_counters"))?;
+        let mut init_attr = ffi::ibv_counters_init_attr { comp_mask: 0 };
+        let ibv_counters = unsafe { create_counters(context.as_mut_ptr(), &mut init_attr) };
+        if ibv_counters.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_counters: NonNull::new_unchecked(ibv_counters),
+                ncounters: 0,
+            })
+        }
+    }
+    #[inline(always)]
+    fn context(&self) -> *mut ffi::ibv_context {
+        unsafe { self.ibv_counters.as_ref().context }
+    }
+    pub fn attach(
+        &mut self,
+        counter_desc: ffi::ibv_counter_description::Type,
+        flow: Option<NonNull<ffi::ibv_flow>>,
+    ) -> Result<u32, IOError> {
+        let attach = verbs_ctx_op!(self.context(), attach_counters_point_flow)
+            .ok_or_else(|| unsupported_verb("ibv_attach_counters_point_flow"))?;
+        let mut attr = ffi::ibv_counter_attach_attr {
+            counter_desc,
+            index: self.ncounters,
+            comp_mask: 0,
+        };
+        let flow = flow.map_or(std::ptr::null_mut(), NonNull::as_ptr);
+        let ret = unsafe { attach(self.ibv_counters.as_ptr(), &mut attr, flow) };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        self.ncounters += 1;
+        Ok(attr.index)
+    }
+    // Reads every attached counter from hardware, in attach order.
+    pub fn read(&self) -> Result<Vec<u64>, IOError> {
+        let read_counters = verbs_ctx_op!(self.context(), read_counters)
+            .ok_or_else(|| unsupported_verb("ibv_read_counters"))?;
+        let mut values = vec![0_u64; self.ncounters as usize];
+        let ret = unsafe {
+            read_counters(
+                self.ibv_counters.as_ptr(),
+                values.as_mut_ptr(),
+                self.ncounters,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(values)
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_counters {
+        self.ibv_counters.as_ptr()
+    }
+}
+
+impl Drop for IbvCounters {
+    fn drop(&mut self) {
+        let ret = match verbs_ctx_op!(self.context(), destroy_counters) {
+            Some(destroy_counters) => unsafe { destroy_counters(self.ibv_counters.as_ptr()) },
+            None => libc::EOPNOTSUPP,
+        };
+        if ret != 0 {
+            panic!(
+                "ibv_destroy_counters(). errno: {}",
+                IOError::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+unsafe impl Send for IbvCounters {}
+unsafe impl Sync for IbvCounters {}
+
 #[derive(Clone)]
 pub struct IbvMr {
     ibv_mr: NonNull<ffi::ibv_mr>,
+    // ibv_mr doesn't record the access flags, so keep them for later checks.
+    access: ibv_access_flags,
+}
+
+// The device's smallest page size, cached per device since querying it on every
+// registration would cost a syscall. None if the query fails.
+fn min_device_page_size(pd: &IbvPd) -> Option<u64> {
+    static CACHE: OnceLock<Mutex<HashMap<String, u64>>> = OnceLock::new();
+
+    let context = unsafe { (*pd.as_mut_ptr()).context };
+    let device = unsafe { CStr::from_ptr(ffi::ibv_get_device_name((*context).device)) }
+        .to_string_lossy()
+        .into_owned();
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(&page_size) = cache.lock().unwrap().get(&device) {
+        return Some(page_size);
+    }
+    let mut device_attr = unsafe { std::mem::zeroed::<IbvDeviceAttr>() };
+    if unsafe { ffi::ibv_query_device(context, &mut device_attr) } != 0 {
+        return None;
+    }
+    let page_size = device_attr.smallest_supported_page_size()?;
+    cache.lock().unwrap().insert(device, page_size);
+    Some(page_size)
+}
+
+// The kernel pins memory in system pages, which is fine whenever the device maps
+// those too. A device whose smallest page is larger (page_size_cap without the
+// system page size) can only map a region aligned to that page, and otherwise
+// fails the registration with a bare EINVAL; catch that here.
+fn check_mr_alignment(pd: &IbvPd, addr: *const c_void) -> Result<(), IOError> {
+    let system_page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+    match min_device_page_size(pd) {
+        Some(min) if min > system_page && addr as u64 & (min - 1) != 0 => Err(IOError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "region at {:p} is not aligned to the device's smallest page size {:#x} \
+                 (page_size_cap); allocate it {:#x}-aligned",
+                addr, min, min
+            ),
+        )),
+        _ => Ok(()),
+    }
 }
 
 impl IbvMr {
     pub fn new(pd: &IbvPd, region: &[u8], access: ibv_access_flags) -> Result<IbvMr, IOError> {
+        if access.contains(IbvAccess::IBV_ACCESS_ZERO_BASED) {
+            return IbvMrBuilder::new()
+                .region(region)
+                .access(access)
+                .register(pd);
+        }
+        check_mr_alignment(pd, region.as_ptr() as *const c_void)?;
         let ibv_mr = unsafe {
             ffi::ibv_reg_mr(
                 pd.ibv_pd.as_ptr(),
@@ -297,30 +2015,84 @@ impl IbvMr {
             )
         };
         if ibv_mr.is_null() {
-            return Err(IOError::last_os_error());
+            return Err(verbs_error("ibv_reg_mr", IOError::last_os_error()));
         }
         unsafe {
             Ok(IbvMr {
                 ibv_mr: NonNull::new_unchecked(ibv_mr),
+                access,
             })
         }
     }
+    // Registers base[offset..offset + len] only; `addr()` is then the start of that
+    // window, not of `base`.
+    pub fn new_subregion(
+        pd: &IbvPd,
+        base: &[u8],
+        offset: usize,
+        len: usize,
+        access: ibv_access_flags,
+    ) -> Result<IbvMr, IOError> {
+        let window = offset
+            .checked_add(len)
+            .and_then(|end| base.get(offset..end))
+            .ok_or_else(|| {
+                IOError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "subregion [{}, +{}) exceeds buffer of {} bytes",
+                        offset,
+                        len,
+                        base.len()
+                    ),
+                )
+            })?;
+        IbvMr::new(pd, window, access)
+    }
     pub fn new_raw(
         pd: &IbvPd,
         addr: *mut c_void,
         length: usize,
         access: i32,
     ) -> Result<IbvMr, IOError> {
+        let flags = ibv_access_flags(access as u32);
+        if flags.contains(IbvAccess::IBV_ACCESS_ZERO_BASED) {
+            return IbvMrBuilder::new()
+                .raw(addr, length)
+                .access(flags)
+                .register(pd);
+        }
+        check_mr_alignment(pd, addr)?;
         let ibv_mr = unsafe { ffi::ibv_reg_mr(pd.ibv_pd.as_ptr(), addr, length as u64, access) };
         if ibv_mr.is_null() {
-            return Err(IOError::last_os_error());
+            return Err(verbs_error("ibv_reg_mr", IOError::last_os_error()));
         }
         unsafe {
             Ok(IbvMr {
                 ibv_mr: NonNull::new_unchecked(ibv_mr),
+                access: ibv_access_flags(access as u32),
             })
         }
     }
+    // Registers the same memory again so peers need a new rkey to reach it, and
+    // returns that rkey. The old registration is dropped on return, so anything in
+    // flight under the old key fails with a remote access error.
+    pub fn rotate_rkey(&mut self, pd: &IbvPd) -> Result<u32, IOError> {
+        self.rotate_rkey_deferred(pd)?;
+        Ok(self.rkey())
+    }
+    // Like `rotate_rkey`, but hands back the previous registration instead of
+    // dropping it. Until the returned IbvMr is dropped both the old and the new rkey
+    // are live, which gives peers a window to switch over.
+    pub fn rotate_rkey_deferred(&mut self, pd: &IbvPd) -> Result<IbvMr, IOError> {
+        let fresh = IbvMr::new_raw(
+            pd,
+            self.va() as *mut c_void,
+            self.length() as usize,
+            self.access.0 as i32,
+        )?;
+        Ok(std::mem::replace(self, fresh))
+    }
     pub fn as_mut_ptr(&self) -> *mut ffi::ibv_mr {
         self.ibv_mr.as_ptr()
     }
@@ -333,6 +2105,10 @@ impl IbvMr {
         unsafe { self.ibv_mr.as_ref().lkey }
     }
     #[inline(always)]
+    pub fn access(&self) -> ibv_access_flags {
+        self.access
+    }
+    #[inline(always)]
     pub fn length(&self) -> u64 {
         unsafe { self.ibv_mr.as_ref().length }
     }
@@ -340,241 +2116,4111 @@ impl IbvMr {
     pub fn handle(&self) -> u32 {
         unsafe { self.ibv_mr.as_ref().handle }
     }
-}
-
-impl Drop for IbvMr {
-    fn drop(&mut self) {
-        let ret = unsafe { ffi::ibv_dereg_mr(self.ibv_mr.as_ptr()) };
-        if ret != 0 {
-            panic!("ibv_dereg_mr(). errno: {}", IOError::last_os_error());
+    // The address the MR is addressed by in SGEs and by peers: its virtual address,
+    // or 0 for a zero-based MR, whose bytes are then addressed by offset.
+    #[inline(always)]
+    pub fn addr(&self) -> u64 {
+        if self.is_zero_based() {
+            0
+        } else {
+            self.va()
         }
     }
-}
-unsafe impl Send for IbvMr {}
-unsafe impl Sync for IbvMr {}
-
-#[derive(Clone)]
-pub struct IbvQp {
+    // Where the registered memory lives in this process.
+    #[inline(always)]
+    pub fn va(&self) -> u64 {
+        unsafe { self.ibv_mr.as_ref().addr as u64 }
+    }
+    #[inline(always)]
+    pub fn is_zero_based(&self) -> bool {
+        self.access.contains(IbvAccess::IBV_ACCESS_ZERO_BASED)
+    }
+    // What a peer needs to RDMA into this MR.
+    pub fn remote_token(&self) -> RemoteToken {
+        RemoteToken {
+            addr: self.addr(),
+            rkey: self.rkey(),
+            length: self.length(),
+        }
+    }
+    // Whether [addr, addr + len) lies inside the registered region.
+    #[inline(always)]
+    pub fn covers(&self, addr: u64, len: u32) -> bool {
+        match (
+            addr.checked_add(len as u64),
+            self.addr().checked_add(self.length()),
+        ) {
+            (Some(end), Some(mr_end)) => addr >= self.addr() && end <= mr_end,
+            _ => false,
+        }
+    }
+    // Builds an SGE over [addr, addr + len), rejecting ranges outside the MR
+    // instead of letting the NIC fail the WR with a protection error.
+    pub fn sge(&self, addr: u64, len: u32) -> Result<IbvSge, IOError> {
+        if !self.covers(addr, len) {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                SgeOutOfRange {
+                    addr,
+                    len: len as u64,
+                    mr_addr: self.addr(),
+                    mr_length: self.length(),
+                },
+            ));
+        }
+        Ok(IbvSge {
+            addr,
+            length: len,
+            lkey: self.lkey(),
+        })
+    }
+}
+
+impl IbvMr {
+    // Implicit ODP: one MR over the whole address space (addr 0, length SIZE_MAX),
+    // so any pointer in the process can go into an SGE with this MR's lkey, and
+    // pages are faulted in by the NIC on first access.
+    pub fn new_implicit_odp(pd: &IbvPd, access: ibv_access_flags) -> Result<IbvMr, IOError> {
+        let device_attr = query_device_ex(unsafe { (*pd.as_mut_ptr()).context })?;
+        if !device_attr.supports_implicit_odp() {
+            return Err(IOError::new(
+                ErrorKind::Unsupported,
+                "device does not support implicit ODP (IBV_ODP_SUPPORT_IMPLICIT)",
+            ));
+        }
+        let access = access | ibv_access_flags::IBV_ACCESS_ON_DEMAND;
+        Self::new_raw(pd, std::ptr::null_mut(), usize::MAX, access.0 as i32)
+    }
+    // A null MR: data scattered to it is dropped and gathers from it read zeroes,
+    // e.g. to sink the GRH of a UD receive without a buffer for it. It covers no
+    // memory, so take its SGEs from `null_sge` rather than `sge`.
+    pub fn null(pd: &IbvPd) -> Result<IbvMr, IOError> {
+        let context = unsafe { (*pd.as_mut_ptr()).context };
+        let alloc_null_mr = verbs_ctx_op!(context, alloc_null_mr)
+            .ok_or_else(|| unsupported_verb("ibv_alloc_null_mr"))?;
+        let ibv_mr = unsafe { alloc_null_mr(pd.as_mut_ptr()) };
+        match NonNull::new(ibv_mr) {
+            Some(ibv_mr) => Ok(IbvMr {
+                ibv_mr,
+                access: ibv_access_flags::IBV_ACCESS_LOCAL_WRITE,
+            }),
+            None => Err(verbs_error("ibv_alloc_null_mr", IOError::last_os_error())),
+        }
+    }
+    // An SGE of `len` bytes on a null MR; the address is ignored.
+    #[inline(always)]
+    pub fn null_sge(&self, len: u32) -> IbvSge {
+        IbvSge {
+            addr: 0,
+            length: len,
+            lkey: self.lkey(),
+        }
+    }
+}
+
+impl IbvMr {
+    // Registers a hugepage-backed buffer, telling the driver via IBV_ACCESS_HUGETLB.
+    pub fn new_hugepage(
+        pd: &IbvPd,
+        region: &HugePageBuf,
+        access: ibv_access_flags,
+    ) -> Result<IbvMr, IOError> {
+        Self::new(pd, region, access.hugetlb())
+    }
+}
+
+enum MrSource<'a> {
+    Region(&'a [u8]),
+    Raw {
+        addr: *mut c_void,
+        length: usize,
+    },
+    DmaBuf {
+        fd: RawFd,
+        offset: u64,
+        length: usize,
+    },
+}
+
+// One entry point for the registration variants: pick a source, optionally an
+// IOVA and options, and `register` calls the verb that takes that combination.
+pub struct IbvMrBuilder<'a> {
+    source: Option<MrSource<'a>>,
+    iova: Option<u64>,
+    access: IbvAccess,
+}
+
+impl Default for IbvMrBuilder<'_> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> IbvMrBuilder<'a> {
+    // Local access only until `access` says otherwise.
+    pub fn new() -> Self {
+        Self {
+            source: None,
+            iova: None,
+            access: IbvAccess::local(),
+        }
+    }
+    pub fn region(mut self, region: &'a [u8]) -> Self {
+        self.source = Some(MrSource::Region(region));
+        self
+    }
+    // Memory the caller manages, e.g. from mmap; see `IbvMr::new_raw`.
+    pub fn raw(mut self, addr: *mut c_void, length: usize) -> Self {
+        self.source = Some(MrSource::Raw { addr, length });
+        self
+    }
+    // `length` bytes at `offset` into a dma-buf, e.g. GPU memory exported by its
+    // driver. Addressed from IOVA 0 unless `iova` is set.
+    pub fn dmabuf(mut self, fd: RawFd, offset: u64, length: usize) -> Self {
+        self.source = Some(MrSource::DmaBuf { fd, offset, length });
+        self
+    }
+    // The address peers and SGEs use for the start of the region, instead of its
+    // virtual address.
+    pub fn iova(mut self, iova: u64) -> Self {
+        self.iova = Some(iova);
+        self
+    }
+    // Replaces the access flags; the option methods below add to them.
+    pub fn access(mut self, access: IbvAccess) -> Self {
+        self.access = access;
+        self
+    }
+    // Lets the NIC reorder PCIe writes to this region. An optional flag: devices
+    // without support ignore it rather than failing.
+    pub fn relaxed_ordering(mut self) -> Self {
+        self.access |= IbvAccess::IBV_ACCESS_RELAXED_ORDERING;
+        self
+    }
+    pub fn on_demand(mut self) -> Self {
+        self.access |= IbvAccess::IBV_ACCESS_ON_DEMAND;
+        self
+    }
+    pub fn register(mut self, pd: &IbvPd) -> Result<IbvMr, IOError> {
+        let access = self.access;
+        // Zero-based is IOVA 0 under another name; the flag itself stays out of the
+        // verb and only tells `IbvMr::addr` to report offsets.
+        let zero_based = access.contains(IbvAccess::IBV_ACCESS_ZERO_BASED);
+        if zero_based {
+            if self.iova.is_some_and(|iova| iova != 0) {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    "a zero-based MR can't have a non-zero IOVA",
+                ));
+            }
+            self.iova = Some(0);
+        }
+        let verb_access = ibv_access_flags(access.0 & !IbvAccess::IBV_ACCESS_ZERO_BASED.0);
+        match self.source {
+            Some(MrSource::Region(region)) => check_mr_alignment(pd, region.as_ptr() as _)?,
+            Some(MrSource::Raw { addr, .. }) => check_mr_alignment(pd, addr)?,
+            _ => {}
+        }
+        let (verb, ibv_mr) = match self.source {
+            None => {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    "no memory to register: set region, raw or dmabuf",
+                ))
+            }
+            Some(MrSource::DmaBuf { fd, offset, length }) => {
+                let ibv_mr = unsafe {
+                    ffi::ibv_reg_dmabuf_mr(
+                        pd.as_mut_ptr(),
+                        offset,
+                        length as u64,
+                        self.iova.unwrap_or(0),
+                        fd,
+                        verb_access.0 as i32,
+                    )
+                };
+                ("ibv_reg_dmabuf_mr", ibv_mr)
+            }
+            Some(MrSource::Region(region)) => Self::reg_va(
+                pd,
+                region.as_ptr() as *mut c_void,
+                region.len(),
+                self.iova,
+                verb_access,
+            ),
+            Some(MrSource::Raw { addr, length }) => {
+                Self::reg_va(pd, addr, length, self.iova, verb_access)
+            }
+        };
+        match NonNull::new(ibv_mr) {
+            Some(ibv_mr) => Ok(IbvMr { ibv_mr, access }),
+            None => Err(verbs_error(verb, IOError::last_os_error())),
+        }
+    }
+    fn reg_va(
+        pd: &IbvPd,
+        addr: *mut c_void,
+        length: usize,
+        iova: Option<u64>,
+        access: IbvAccess,
+    ) -> (&'static str, *mut ffi::ibv_mr) {
+        // ibv_reg_mr_iova2 also masks optional flags such as relaxed ordering on
+        // devices that lack them.
+        if iova.is_some() || access.0 & IbvAccess::IBV_ACCESS_RELAXED_ORDERING.0 != 0 {
+            let iova = iova.unwrap_or(addr as u64);
+            let ibv_mr = unsafe {
+                ffi::ibv_reg_mr_iova2(pd.as_mut_ptr(), addr, length as u64, iova, access.0)
+            };
+            ("ibv_reg_mr", ibv_mr)
+        } else {
+            let ibv_mr =
+                unsafe { ffi::ibv_reg_mr(pd.as_mut_ptr(), addr, length as u64, access.0 as i32) };
+            ("ibv_reg_mr", ibv_mr)
+        }
+    }
+}
+
+impl Drop for IbvMr {
+    fn drop(&mut self) {
+        let ret = unsafe { ffi::ibv_dereg_mr(self.ibv_mr.as_ptr()) };
+        if ret != 0 {
+            panic!("ibv_dereg_mr(). errno: {}", IOError::last_os_error());
+        }
+    }
+}
+unsafe impl Send for IbvMr {}
+unsafe impl Sync for IbvMr {}
+
+impl IbvMr {
+    // A view of the MR for code that builds SGEs or tokens from it but must not
+    // own, deregister or rotate it; it can't outlive the registration.
+    #[inline(always)]
+    pub fn borrow(&self) -> MrRef<'_> {
+        MrRef {
+            mr: self,
+            addr: self.addr(),
+            length: self.length(),
+        }
+    }
+}
+
+// A borrowed window onto an MR, the whole of it or a part handed to a
+// sub-component with `slice`. SGEs and tokens built from it stay inside the window.
+#[derive(Clone, Copy)]
+pub struct MrRef<'a> {
+    mr: &'a IbvMr,
+    addr: u64,
+    length: u64,
+}
+
+impl<'a> MrRef<'a> {
+    // The `len` bytes at `offset` into this view.
+    pub fn slice(&self, offset: u64, len: u64) -> Result<MrRef<'a>, IOError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.length => Ok(MrRef {
+                mr: self.mr,
+                addr: self.addr + offset,
+                length: len,
+            }),
+            _ => Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "slice [{}, +{}) exceeds view of {} bytes",
+                    offset, len, self.length
+                ),
+            )),
+        }
+    }
+    #[inline(always)]
+    pub fn lkey(&self) -> u32 {
+        self.mr.lkey()
+    }
+    #[inline(always)]
+    pub fn rkey(&self) -> u32 {
+        self.mr.rkey()
+    }
+    #[inline(always)]
+    pub fn addr(&self) -> u64 {
+        self.addr
+    }
+    #[inline(always)]
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+    #[inline(always)]
+    pub fn access(&self) -> ibv_access_flags {
+        self.mr.access()
+    }
+    pub fn sge(&self, addr: u64, len: u32) -> Result<IbvSge, IOError> {
+        let inside = addr
+            .checked_add(len as u64)
+            .is_some_and(|end| addr >= self.addr && end <= self.addr + self.length);
+        if !inside {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                SgeOutOfRange {
+                    addr,
+                    len: len as u64,
+                    mr_addr: self.addr,
+                    mr_length: self.length,
+                },
+            ));
+        }
+        self.mr.sge(addr, len)
+    }
+    // An SGE over the whole view.
+    pub fn full_sge(&self) -> Result<IbvSge, IOError> {
+        let len = u32::try_from(self.length).map_err(|_| {
+            IOError::new(
+                ErrorKind::InvalidInput,
+                "view longer than an SGE can describe",
+            )
+        })?;
+        self.sge(self.addr, len)
+    }
+    pub fn remote_token(&self) -> RemoteToken {
+        RemoteToken {
+            addr: self.addr,
+            rkey: self.rkey(),
+            length: self.length,
+        }
+    }
+}
+
+// An MR registered by another process, imported by handle into a PD imported from
+// it. ibv_mr doesn't carry the access flags, so the importer passes the ones the
+// owner registered with. Dropping it leaves the registration in place.
+pub struct ImportedMr {
+    mr: ManuallyDrop<IbvMr>,
+}
+
+impl ImportedMr {
+    pub fn import(pd: &IbvPd, handle: u32, access: ibv_access_flags) -> Result<Self, IOError> {
+        let ibv_mr = unsafe { ffi::ibv_import_mr(pd.as_mut_ptr(), handle) };
+        match NonNull::new(ibv_mr) {
+            Some(ibv_mr) => Ok(Self {
+                mr: ManuallyDrop::new(IbvMr { ibv_mr, access }),
+            }),
+            None => Err(verbs_error("ibv_import_mr", IOError::last_os_error())),
+        }
+    }
+}
+
+impl Deref for ImportedMr {
+    type Target = IbvMr;
+    fn deref(&self) -> &IbvMr {
+        &self.mr
+    }
+}
+
+impl Drop for ImportedMr {
+    fn drop(&mut self) {
+        unsafe { ffi::ibv_unimport_mr(self.mr.ibv_mr.as_ptr()) };
+    }
+}
+
+// Setting the high bit of a UD send's remote_qkey makes the HCA use the sending
+// QP's own Q_Key instead.
+pub const QKEY_USE_QP: u32 = 0x8000_0000;
+
+pub struct IbvAh {
+    ibv_ah: NonNull<ffi::ibv_ah>,
+}
+
+impl IbvAh {
+    pub fn new(pd: &IbvPd, attr: &IbvAhAttr) -> Result<Self, IOError> {
+        let mut attr = *attr;
+        let ibv_ah = unsafe { ffi::ibv_create_ah(pd.ibv_pd.as_ptr(), &mut attr) };
+        if ibv_ah.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_ah: NonNull::new_unchecked(ibv_ah),
+            })
+        }
+    }
+    // An AH addressing the sender of the UD datagram `wc` completed, for replying.
+    // `grh` is the header the HCA placed in front of the payload; see
+    // `IbvGrh::from_recv_buf`. It is ignored for completions without IBV_WC_GRH.
+    pub fn from_wc(pd: &IbvPd, port_num: u8, wc: &IbvWc, grh: &IbvGrh) -> Result<Self, IOError> {
+        let mut wc = *wc;
+        let mut grh = *grh;
+        let ibv_ah =
+            unsafe { ffi::ibv_create_ah_from_wc(pd.ibv_pd.as_ptr(), &mut wc, &mut grh, port_num) };
+        if ibv_ah.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_ah: NonNull::new_unchecked(ibv_ah),
+            })
+        }
+    }
+    // A signaled UD SEND to `remote_qpn` through this AH. Pass QKEY_USE_QP as
+    // `remote_qkey` to send with the local QP's Q_Key.
+    pub fn send_wr(
+        &self,
+        wr_id: u64,
+        sg_list: &mut [IbvSge],
+        remote_qpn: u32,
+        remote_qkey: u32,
+    ) -> IbvSendWr {
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        wr.wr_id = wr_id;
+        wr.sg_list = sg_list.as_mut_ptr();
+        wr.num_sge = sg_list.len() as i32;
+        wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND;
+        wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        wr.wr.ud = ffi::ibv_send_wr__bindgen_ty_2__bindgen_ty_3 {
+            ah: self.ibv_ah.as_ptr(),
+            remote_qpn,
+            remote_qkey,
+        };
+        wr
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_ah {
+        self.ibv_ah.as_ptr()
+    }
+}
+
+impl Drop for IbvAh {
+    fn drop(&mut self) {
+        let ret = unsafe { ffi::ibv_destroy_ah(self.ibv_ah.as_ptr()) };
+        if ret != 0 {
+            panic!("ibv_destroy_ah(). errno: {}", IOError::last_os_error());
+        }
+    }
+}
+unsafe impl Send for IbvAh {}
+unsafe impl Sync for IbvAh {}
+
+// A remotely accessible region: the (addr, rkey, length) triple peers exchange so
+// they can target each other's memory with RDMA reads, writes and atomics.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RemoteToken {
+    pub addr: u64,
+    pub rkey: u32,
+    pub length: u64,
+}
+
+impl RemoteToken {
+    pub const WIRE_SIZE: usize = 20;
+
+    // addr, rkey, length; big endian.
+    pub fn to_wire(&self) -> [u8; Self::WIRE_SIZE] {
+        let mut buf = [0_u8; Self::WIRE_SIZE];
+        buf[0..8].copy_from_slice(&self.addr.to_be_bytes());
+        buf[8..12].copy_from_slice(&self.rkey.to_be_bytes());
+        buf[12..20].copy_from_slice(&self.length.to_be_bytes());
+        buf
+    }
+    pub fn from_wire(buf: &[u8; Self::WIRE_SIZE]) -> Self {
+        Self {
+            addr: u64::from_be_bytes(buf[0..8].try_into().unwrap()),
+            rkey: u32::from_be_bytes(buf[8..12].try_into().unwrap()),
+            length: u64::from_be_bytes(buf[12..20].try_into().unwrap()),
+        }
+    }
+    // The local side of a read or write of this whole region: an SGE over
+    // [addr, addr + length) in `mr`, failing with SgeOutOfRange if the buffer
+    // doesn't fit in the MR. The token itself can only be checked by the peer; a
+    // stale one shows up as AccessError::RemoteAccess on the completion.
+    pub fn local_sge(&self, mr: &IbvMr, addr: u64) -> Result<IbvSge, IOError> {
+        let len = u32::try_from(self.length).map_err(|_| {
+            IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "remote region of {} bytes exceeds one WR's 4 GiB limit",
+                    self.length
+                ),
+            )
+        })?;
+        mr.sge(addr, len)
+    }
+    // The sub-range [offset, offset + len) of this region, checked against its bounds.
+    pub fn sge_for(&self, offset: u64, len: u64) -> Result<RemoteToken, IOError> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.length => Ok(RemoteToken {
+                addr: self.addr + offset,
+                rkey: self.rkey,
+                length: len,
+            }),
+            _ => Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "remote range [{}, +{}) exceeds region of {} bytes",
+                    offset, len, self.length
+                ),
+            )),
+        }
+    }
+}
+
+// A memory window: a narrower, separately revocable rkey over part of an MR.
+pub struct IbvMw {
+    ibv_mw: NonNull<ffi::ibv_mw>,
+}
+
+impl IbvMw {
+    pub fn new(pd: &IbvPd, mw_type: ffi::ibv_mw_type::Type) -> Result<Self, IOError> {
+        let ibv_mw = unsafe {
+            let ibv_alloc_mw = (*(*pd.as_mut_ptr()).context).ops.alloc_mw;
+            match ibv_alloc_mw {
+                Some(ibv_alloc_mw) => ibv_alloc_mw(pd.as_mut_ptr(), mw_type),
+                None => return Err(unsupported_verb("ibv_alloc_mw")),
+            }
+        };
+        if ibv_mw.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_mw: NonNull::new_unchecked(ibv_mw),
+            })
+        }
+    }
+    #[inline(always)]
+    pub fn rkey(&self) -> u32 {
+        unsafe { self.ibv_mw.as_ref().rkey }
+    }
+    // Binds this type 1 window to [addr, addr + length) of `mr` through `qp` and
+    // returns the window's new rkey. The bind is a signaled WR on the send queue;
+    // the rkey is only usable by the peer once its completion has been reaped.
+    pub fn bind(
+        &self,
+        qp: &IbvQp,
+        mr: &IbvMr,
+        wr_id: u64,
+        addr: u64,
+        length: u64,
+        mw_access: ibv_access_flags,
+    ) -> Result<u32, IOError> {
+        if unsafe { self.ibv_mw.as_ref().type_ } != ffi::ibv_mw_type::IBV_MW_TYPE_1 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "only type 1 windows are bound with ibv_bind_mw",
+            ));
+        }
+        if !mr.access().contains(ibv_access_flags::IBV_ACCESS_MW_BIND) {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "memory window bind needs an MR registered with IBV_ACCESS_MW_BIND, see IbvAccess::mw_bind",
+            ));
+        }
+        if length > u32::MAX as u64 || !mr.covers(addr, length as u32) {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("window [{:#x}, +{}) is outside the MR", addr, length),
+            ));
+        }
+        let mut mw_bind = ffi::ibv_mw_bind {
+            wr_id,
+            send_flags: ffi::ibv_send_flags::IBV_SEND_SIGNALED.0,
+            bind_info: ffi::ibv_mw_bind_info {
+                mr: mr.as_mut_ptr(),
+                addr,
+                length,
+                mw_access_flags: mw_access.0,
+            },
+        };
+        let ret = unsafe {
+            let ibv_bind_mw = (*self.ibv_mw.as_ref().context).ops.bind_mw;
+            match ibv_bind_mw {
+                Some(ibv_bind_mw) => {
+                    ibv_bind_mw(qp.as_mut_ptr(), self.ibv_mw.as_ptr(), &mut mw_bind)
+                }
+                None => return Err(unsupported_verb("ibv_bind_mw")),
+            }
+        };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(self.rkey())
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_mw {
+        self.ibv_mw.as_ptr()
+    }
+}
+
+impl Drop for IbvMw {
+    fn drop(&mut self) {
+        let ret = unsafe {
+            match (*self.ibv_mw.as_ref().context).ops.dealloc_mw {
+                Some(ibv_dealloc_mw) => ibv_dealloc_mw(self.ibv_mw.as_ptr()),
+                None => libc::EOPNOTSUPP,
+            }
+        };
+        if ret != 0 {
+            panic!(
+                "ibv_dealloc_mw(). errno: {}",
+                IOError::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+unsafe impl Send for IbvMw {}
+unsafe impl Sync for IbvMw {}
+
+// Two registrations of the same memory: `local` carries no remote rights and is
+// the one to use in send/recv SGEs, while only `remote.rkey()` is handed to peers.
+pub struct IbvMrPair {
+    local: IbvMr,
+    remote: IbvMr,
+}
+
+impl IbvMrPair {
+    pub fn new(
+        pd: &IbvPd,
+        region: &[u8],
+        local_access: ibv_access_flags,
+        remote_access: ibv_access_flags,
+    ) -> Result<IbvMrPair, IOError> {
+        let remote_flags = ibv_access_flags::IBV_ACCESS_REMOTE_READ
+            | ibv_access_flags::IBV_ACCESS_REMOTE_WRITE
+            | ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC;
+        if (local_access & remote_flags).0 != 0 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "local_access must not grant remote access",
+            ));
+        }
+        let local = IbvMr::new(pd, region, local_access)?;
+        let remote = IbvMr::new(pd, region, remote_access)?;
+        Ok(IbvMrPair { local, remote })
+    }
+    #[inline(always)]
+    pub fn local(&self) -> &IbvMr {
+        &self.local
+    }
+    #[inline(always)]
+    pub fn remote(&self) -> &IbvMr {
+        &self.remote
+    }
+    #[inline(always)]
+    pub fn lkey(&self) -> u32 {
+        self.local.lkey()
+    }
+    #[inline(always)]
+    pub fn rkey(&self) -> u32 {
+        self.remote.rkey()
+    }
+    pub fn remote_token(&self) -> RemoteToken {
+        self.remote.remote_token()
+    }
+}
+
+// An owned buffer that is locally registered for its whole life but only remotely
+// accessible between `export_token` and `revoke`: the remote MR is registered on
+// export and deregistered on revoke, which invalidates its rkey at the NIC.
+pub struct ProtectedRegion {
+    // MRs are declared before the buffer so they are deregistered first.
+    remote: Option<IbvMr>,
+    local: IbvMr,
+    buf: Box<[u8]>,
+}
+
+impl ProtectedRegion {
+    pub fn new(pd: &IbvPd, len: usize) -> Result<Self, IOError> {
+        let buf = vec![0_u8; len].into_boxed_slice();
+        let local = IbvMr::new(pd, &buf, IbvAccess::local())?;
+        Ok(ProtectedRegion {
+            remote: None,
+            local,
+            buf,
+        })
+    }
+    #[inline(always)]
+    pub fn local(&self) -> &IbvMr {
+        &self.local
+    }
+    // Registers the remote view if needed and returns its token; repeated calls
+    // hand out the same token until `revoke`.
+    pub fn export_token(
+        &mut self,
+        pd: &IbvPd,
+        remote_access: ibv_access_flags,
+    ) -> Result<RemoteToken, IOError> {
+        if let Some(remote) = &self.remote {
+            return Ok(remote.remote_token());
+        }
+        let remote = IbvMr::new(pd, &self.buf, remote_access)?;
+        let token = remote.remote_token();
+        self.remote = Some(remote);
+        Ok(token)
+    }
+    // Deregisters the remote view. Returns false if nothing was exported.
+    pub fn revoke(&mut self) -> bool {
+        self.remote.take().is_some()
+    }
+    #[inline(always)]
+    pub fn is_exported(&self) -> bool {
+        self.remote.is_some()
+    }
+    #[inline(always)]
+    pub fn data(&self) -> &[u8] {
+        &self.buf
+    }
+    #[inline(always)]
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+}
+
+// One MR per chunk of a fragmented buffer plus the SGE list covering all of them,
+// in chunk order, for a single multi-SGE send or RDMA. The list can't be longer
+// than the QP's max_send_sge.
+pub struct IbvMrSet {
+    mrs: Vec<IbvMr>,
+    sges: Vec<IbvSge>,
+}
+
+impl IbvMrSet {
+    pub fn register(
+        pd: &IbvPd,
+        chunks: &[&[u8]],
+        access: ibv_access_flags,
+    ) -> Result<IbvMrSet, IOError> {
+        let mut mrs = Vec::with_capacity(chunks.len());
+        let mut sges = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            if chunk.len() > u32::MAX as usize {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    "chunk longer than an SGE can describe",
+                ));
+            }
+            let mr = IbvMr::new(pd, chunk, access)?;
+            sges.push(mr.sge(mr.addr(), chunk.len() as u32)?);
+            mrs.push(mr);
+        }
+        Ok(IbvMrSet { mrs, sges })
+    }
+    #[inline(always)]
+    pub fn mrs(&self) -> &[IbvMr] {
+        &self.mrs
+    }
+    #[inline(always)]
+    pub fn sges(&self) -> &[IbvSge] {
+        &self.sges
+    }
+    // A copy of the SGE list, e.g. to point a WR's sg_list at.
+    pub fn to_sge_vec(&self) -> Vec<IbvSge> {
+        self.sges.clone()
+    }
+    pub fn total_len(&self) -> u64 {
+        self.sges.iter().map(|sge| sge.length as u64).sum()
+    }
+}
+
+// How to allocate a buffer so the device will register it, from
+// `IbvContext::best_registration_strategy`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RegistrationHint {
+    // Page size to back the buffer with: the system page, or a hugepage size the
+    // device maps and the kernel has pages of (allocate with HugePageBuf).
+    pub page_size: u64,
+    // Alignment of the buffer start: at least the device's smallest page.
+    pub alignment: u64,
+    pub hugepages: bool,
+    // Whether implicit ODP is an alternative to registering at all.
+    pub implicit_odp: bool,
+}
+
+impl std::fmt::Display for RegistrationHint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} pages of {:#x}, aligned to {:#x}{}",
+            if self.hugepages { "huge" } else { "system" },
+            self.page_size,
+            self.alignment,
+            if self.implicit_odp {
+                " (implicit ODP available)"
+            } else {
+                ""
+            }
+        )
+    }
+}
+
+// Free hugepages of `size` bytes the kernel has reserved.
+fn free_hugepages(size: u64) -> u64 {
+    std::fs::read_to_string(format!(
+        "/sys/kernel/mm/hugepages/hugepages-{}kB/free_hugepages",
+        size / 1024
+    ))
+    .ok()
+    .and_then(|n| n.trim().parse().ok())
+    .unwrap_or(0)
+}
+
+impl IbvContext {
+    // Recommends page size and alignment for a `len`-byte buffer to register:
+    // the largest hugepage size that the device maps, that `len` fills at least
+    // one page of and that has enough free pages reserved, else system pages.
+    // Either way the start is aligned to the device's smallest page, which
+    // IbvMr::new requires on devices that don't map system pages.
+    pub fn best_registration_strategy(&self, len: u64) -> Result<RegistrationHint, IOError> {
+        let attr = self.query_device()?;
+        if len > attr.max_mr_size() {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes exceed the device's max_mr_size {}",
+                    len,
+                    attr.max_mr_size()
+                ),
+            ));
+        }
+        let system_page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as u64;
+        let min_page = attr.smallest_supported_page_size().unwrap_or(system_page);
+        let huge = attr
+            .supported_page_sizes()
+            .into_iter()
+            .rev()
+            .filter(|&size| size > system_page && size <= len)
+            .find(|&size| free_hugepages(size) >= len.div_ceil(size));
+        let page_size = huge.unwrap_or(system_page);
+        let implicit_odp = self
+            .query_device_ex()
+            .map(|attr| attr.supports_implicit_odp())
+            .unwrap_or(false);
+        Ok(RegistrationHint {
+            page_size,
+            alignment: page_size.max(min_page),
+            hugepages: huge.is_some(),
+            implicit_odp,
+        })
+    }
+}
+
+/// An anonymous mapping backed by hugepages of `page_size` bytes (e.g. 2MB or 1GB).
+/// The pages must already be reserved through `/proc/sys/vm/nr_hugepages` or the
+/// kernel command line, otherwise `new` fails with ENOMEM.
+pub struct HugePageBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+}
+
+impl HugePageBuf {
+    pub fn new(len: usize, page_size: usize) -> Result<Self, IOError> {
+        assert!(
+            page_size.is_power_of_two(),
+            "page_size must be a power of two"
+        );
+        let len = len.div_ceil(page_size) * page_size;
+        let flags = libc::MAP_PRIVATE
+            | libc::MAP_ANONYMOUS
+            | libc::MAP_HUGETLB
+            | ((page_size.trailing_zeros() as c_int) << libc::MAP_HUGE_SHIFT);
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ptr: NonNull::new_unchecked(ptr as *mut u8),
+                len,
+            })
+        }
+    }
+}
+
+impl Deref for HugePageBuf {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for HugePageBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for HugePageBuf {
+    fn drop(&mut self) {
+        let ret = unsafe { libc::munmap(self.ptr.as_ptr() as *mut c_void, self.len) };
+        if ret != 0 {
+            panic!("munmap(). errno: {}", IOError::last_os_error());
+        }
+    }
+}
+unsafe impl Send for HugePageBuf {}
+unsafe impl Sync for HugePageBuf {}
+
+// Bytes a UD receive buffer must reserve in front of the payload for the GRH. The
+// HCA always consumes them, whether or not the datagram carried a GRH.
+pub const UD_GRH_SIZE: u32 = 40;
+
+// A shared receive queue. QPs created with it (`IbvQpBuilder::srq`) take their
+// receives from here instead of their own RQ.
+pub struct IbvSrq {
+    ibv_srq: NonNull<ffi::ibv_srq>,
+}
+
+impl IbvSrq {
+    pub fn new(pd: &IbvPd, max_wr: u32, max_sge: u32) -> Result<Self, IOError> {
+        let mut init_attr = unsafe { std::mem::zeroed::<ffi::ibv_srq_init_attr>() };
+        init_attr.attr.max_wr = max_wr;
+        init_attr.attr.max_sge = max_sge;
+        let ibv_srq = unsafe { ffi::ibv_create_srq(pd.ibv_pd.as_ptr(), &mut init_attr) };
+        if ibv_srq.is_null() {
+            return Err(IOError::last_os_error());
+        }
+        unsafe {
+            Ok(Self {
+                ibv_srq: NonNull::new_unchecked(ibv_srq),
+            })
+        }
+    }
+    // Creates the SRQ through ibv_create_srq_ex, e.g. an XRC SRQ; see
+    // IbvSrqInitAttrEx. The PD, XRC domain and CQ must outlive the SRQ.
+    pub fn new_ex(context: &IbvContext, init_attr: &mut IbvSrqInitAttrEx) -> Result<Self, IOError> {
+        let create_srq_ex = verbs_ctx_op!(context.as_mut_ptr(), create_srq_ex)
+            .ok_or_else(|| unsupported_verb("ibv_create_srq_ex"))?;
+        let ibv_srq = unsafe { create_srq_ex(context.as_mut_ptr(), init_attr as *mut _) };
+        if ibv_srq.is_null() {
+            return Err(verbs_error("ibv_create_srq_ex", IOError::last_os_error()));
+        }
+        unsafe {
+            Ok(Self {
+                ibv_srq: NonNull::new_unchecked(ibv_srq),
+            })
+        }
+    }
+    // A basic SRQ through the extended path, associated with `cq`. Completions of
+    // SRQ operations are polled from `cq` like any other and classify as usual;
+    // `cq` must outlive the SRQ.
+    pub fn with_cq(
+        context: &IbvContext,
+        pd: &IbvPd,
+        cq: &IbvCq,
+        max_wr: u32,
+        max_sge: u32,
+    ) -> Result<Self, IOError> {
+        let mut attr = IbvSrqInitAttrEx::new(max_wr, max_sge);
+        attr.set_srq_type(ffi::ibv_srq_type::IBV_SRQT_BASIC);
+        attr.set_pd(pd);
+        attr.set_cq(cq);
+        Self::new_ex(context, &mut attr)
+    }
+    // The number senders put in an XRC WR's remote SRQ number to reach this SRQ.
+    pub fn srq_num(&self) -> Result<u32, IOError> {
+        let context = unsafe { self.ibv_srq.as_ref().context };
+        let get_srq_num = verbs_ctx_op!(context, get_srq_num)
+            .ok_or_else(|| unsupported_verb("ibv_get_srq_num"))?;
+        let mut srq_num = 0;
+        let ret = unsafe { get_srq_num(self.ibv_srq.as_ptr(), &mut srq_num) };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(srq_num)
+    }
+    // The current max_wr, max_sge and srq_limit, as granted by the provider.
+    pub fn query(&self) -> Result<ffi::ibv_srq_attr, IOError> {
+        let mut attr = unsafe { std::mem::zeroed::<ffi::ibv_srq_attr>() };
+        let ret = unsafe { ffi::ibv_query_srq(self.ibv_srq.as_ptr(), &mut attr) };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(attr)
+    }
+    pub fn post_recv(
+        &self,
+        wr: &IbvRecvWr,
+        bad_wr: *const *const IbvRecvWr,
+    ) -> Result<(), IOError> {
+        let post_srq_recv = ctx_ops_fn!(
+            self.ibv_srq.as_ref().context,
+            post_srq_recv,
+            "ibv_post_srq_recv"
+        )?;
+        let ret = unsafe {
+            post_srq_recv(
+                self.ibv_srq.as_ptr(),
+                wr as *const _ as *mut _,
+                bad_wr as *mut _,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_post_srq_recv",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    // Posts one receive for UD QPs: `sge` must cover UD_GRH_SIZE bytes of GRH
+    // followed by room for the largest datagram, which lands at offset 40.
+    pub fn post_ud_recv(&self, wr_id: u64, sge: &IbvSge) -> Result<(), IOError> {
+        if sge.length <= UD_GRH_SIZE {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "UD receive buffer of {} bytes leaves no room after the {}-byte GRH",
+                    sge.length, UD_GRH_SIZE
+                ),
+            ));
+        }
+        let mut sge = *sge;
+        let mut wr = unsafe { std::mem::zeroed::<IbvRecvWr>() };
+        wr.wr_id = wr_id;
+        wr.sg_list = &mut sge;
+        wr.num_sge = 1;
+        let mut bad_wr = std::ptr::null::<IbvRecvWr>();
+        self.post_recv(&wr, std::ptr::addr_of_mut!(bad_wr))
+    }
+    // Arms the SRQ limit event: IBV_EVENT_SRQ_LIMIT_REACHED fires once fewer than
+    // `limit` receives remain posted.
+    pub fn set_limit(&self, limit: u32) -> Result<(), IOError> {
+        let mut attr = unsafe { std::mem::zeroed::<ffi::ibv_srq_attr>() };
+        attr.srq_limit = limit;
+        let ret = unsafe {
+            ffi::ibv_modify_srq(
+                self.ibv_srq.as_ptr(),
+                &mut attr,
+                ffi::ibv_srq_attr_mask::IBV_SRQ_LIMIT.0 as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(())
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_srq {
+        self.ibv_srq.as_ptr()
+    }
+}
+
+impl Drop for IbvSrq {
+    fn drop(&mut self) {
+        let ret = unsafe { ffi::ibv_destroy_srq(self.ibv_srq.as_ptr()) };
+        if ret != 0 {
+            panic!(
+                "ibv_destroy_srq(). errno: {}",
+                IOError::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+unsafe impl Send for IbvSrq {}
+unsafe impl Sync for IbvSrq {}
+
+// An XRC domain: groups XRC SRQs and the XRC receive QPs that feed them. Passing a
+// file descriptor shares the domain with other processes opening the same file.
+pub struct IbvXrcd {
+    ibv_xrcd: NonNull<ffi::ibv_xrcd>,
+}
+
+impl IbvXrcd {
+    // A domain private to this process.
+    pub fn new(context: &IbvContext) -> Result<Self, IOError> {
+        Self::open(context, -1, libc::O_CREAT)
+    }
+    // `fd` and `oflags` as for ibv_open_xrcd: an fd of -1 is a private domain,
+    // O_CREAT creates the domain behind `fd` if it doesn't exist yet.
+    pub fn open(context: &IbvContext, fd: i32, oflags: i32) -> Result<Self, IOError> {
+        let open_xrcd = verbs_ctx_op!(context.as_mut_ptr(), open_xrcd)
+            .ok_or_else(|| unsupported_verb("ibv_open_xrcd"))?;
+        let mut attr = unsafe { std::mem::zeroed::<ffi::ibv_xrcd_init_attr>() };
+        attr.comp_mask = ffi::ibv_xrcd_init_attr_mask::IBV_XRCD_INIT_ATTR_FD.0
+            | ffi::ibv_xrcd_init_attr_mask::IBV_XRCD_INIT_ATTR_OFLAGS.0;
+        attr.fd = fd;
+        attr.oflags = oflags;
+        let ibv_xrcd = unsafe { open_xrcd(context.as_mut_ptr(), &mut attr) };
+        if ibv_xrcd.is_null() {
+            return Err(verbs_error("ibv_open_xrcd", IOError::last_os_error()));
+        }
+        unsafe {
+            Ok(Self {
+                ibv_xrcd: NonNull::new_unchecked(ibv_xrcd),
+            })
+        }
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_xrcd {
+        self.ibv_xrcd.as_ptr()
+    }
+}
+
+impl Drop for IbvXrcd {
+    fn drop(&mut self) {
+        let context = unsafe { self.ibv_xrcd.as_ref().context };
+        let ret = match verbs_ctx_op!(context, close_xrcd) {
+            Some(close_xrcd) => unsafe { close_xrcd(self.ibv_xrcd.as_ptr()) },
+            None => libc::EOPNOTSUPP,
+        };
+        if ret != 0 {
+            panic!(
+                "ibv_close_xrcd(). errno: {}",
+                IOError::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+unsafe impl Send for IbvXrcd {}
+unsafe impl Sync for IbvXrcd {}
+
+// What a connected QP settled on, for logging; see `IbvQp::connection_info`. The
+// Display form is a single line.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    pub qp_num: u32,
+    pub qp_type: Option<IbvQpType>,
+    pub state: ffi::ibv_qp_state::Type,
+    pub port_num: u8,
+    pub path_mtu: Option<IbvMtu>,
+    pub dest_qp_num: u32,
+    pub sq_psn: u32,
+    pub rq_psn: u32,
+    pub dlid: u16,
+    pub sl: u8,
+    // The injection rate limit of the primary path (ibv_rate); 0 means full port
+    // speed.
+    pub static_rate: u8,
+    // The destination GID and source GID index, on paths with a GRH.
+    pub dgid: Option<Ipv6Addr>,
+    pub sgid_index: u8,
+    pub timeout: Option<Duration>,
+    pub retry_cnt: u8,
+    pub rnr_retry: u8,
+    pub min_rnr_timer: Option<Duration>,
+    pub max_rd_atomic: u8,
+    pub max_dest_rd_atomic: u8,
+    pub path_mig_state: Option<PathMigState>,
+}
+
+fn qp_state_name(state: ffi::ibv_qp_state::Type) -> &'static str {
+    match state {
+        ffi::ibv_qp_state::IBV_QPS_RESET => "RESET",
+        ffi::ibv_qp_state::IBV_QPS_INIT => "INIT",
+        ffi::ibv_qp_state::IBV_QPS_RTR => "RTR",
+        ffi::ibv_qp_state::IBV_QPS_RTS => "RTS",
+        ffi::ibv_qp_state::IBV_QPS_SQD => "SQD",
+        ffi::ibv_qp_state::IBV_QPS_SQE => "SQE",
+        ffi::ibv_qp_state::IBV_QPS_ERR => "ERR",
+        _ => "UNKNOWN",
+    }
+}
+
+impl std::fmt::Display for ConnectionInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "qp {} ({:?}, {}) port {} -> qp {} lid {} sl {}",
+            self.qp_num,
+            self.qp_type,
+            qp_state_name(self.state),
+            self.port_num,
+            self.dest_qp_num,
+            self.dlid,
+            self.sl
+        )?;
+        if let Some(dgid) = self.dgid {
+            write!(f, " gid {} (sgid_index {})", dgid, self.sgid_index)?;
+        }
+        write!(
+            f,
+            " mtu {} sq_psn {} rq_psn {} timeout {:?} retry_cnt {} rnr_retry {} \
+             min_rnr_timer {:?} rd_atomic {}/{} static_rate {} path_mig {:?}",
+            self.path_mtu.map_or(0, IbvMtu::to_bytes),
+            self.sq_psn,
+            self.rq_psn,
+            self.timeout,
+            self.retry_cnt,
+            self.rnr_retry,
+            self.min_rnr_timer,
+            self.max_rd_atomic,
+            self.max_dest_rd_atomic,
+            self.static_rate,
+            self.path_mig_state
+        )
+    }
+}
+
+fn check_sges(num_sge: i32, max: u32) -> Result<(), IOError> {
+    if num_sge.max(0) as u32 > max {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            TooManySges {
+                given: num_sge as usize,
+                max,
+            },
+        ));
+    }
+    Ok(())
+}
+
+// What `IbvQp::shutdown` reaped. With `timed_out` set the markers never came back,
+// so WRs may still complete after it returns; `completions` holds what was
+// drained up to then.
+#[derive(Clone, Default)]
+pub struct QpShutdown {
+    pub completions: Vec<IbvWc>,
+    pub timed_out: bool,
+}
+
+#[derive(Clone)]
+pub struct IbvQp {
     ibv_qp: NonNull<ffi::ibv_qp>,
+    // Caps granted at creation, checked on every post.
+    max_send_sge: u32,
+    max_recv_sge: u32,
+    max_inline_data: u32,
+}
+impl IbvQp {
+    pub fn new(
+        pd: &IbvPd,
+        send_cq: &IbvCq,
+        recv_cq: &IbvCq,
+        sq_sig_all: i32,
+        max_send_wr: u32,
+        max_recv_wr: u32,
+        max_send_sge: u32,
+        max_recv_sge: u32,
+        max_inline_data: u32,
+    ) -> Result<Self, IOError> {
+        let mut qp_init_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_init_attr>() };
+        qp_init_attr.qp_type = ffi::ibv_qp_type::IBV_QPT_RC;
+        qp_init_attr.sq_sig_all = sq_sig_all; // set to 0 to avoid CQE for every SR
+        qp_init_attr.send_cq = send_cq.ibv_cq.as_ptr();
+        qp_init_attr.recv_cq = recv_cq.ibv_cq.as_ptr();
+        qp_init_attr.cap.max_send_wr = max_send_wr;
+        qp_init_attr.cap.max_recv_wr = max_recv_wr;
+        qp_init_attr.cap.max_send_sge = max_send_sge;
+        qp_init_attr.cap.max_recv_sge = max_recv_sge;
+        qp_init_attr.cap.max_inline_data = max_inline_data;
+        qp_init_attr.srq = std::ptr::null_mut();
+        let ibv_qp = unsafe { ffi::ibv_create_qp(pd.ibv_pd.as_ptr(), &mut qp_init_attr as *mut _) };
+        if ibv_qp.is_null() {
+            return Err(verbs_error("ibv_create_qp", IOError::last_os_error()));
+        }
+        unsafe {
+            Ok(Self {
+                ibv_qp: NonNull::new_unchecked(ibv_qp),
+                max_send_sge: qp_init_attr.cap.max_send_sge,
+                max_recv_sge: qp_init_attr.cap.max_recv_sge,
+                max_inline_data: qp_init_attr.cap.max_inline_data,
+            })
+        }
+    }
+    pub fn with_attr(pd: &IbvPd, qp_init_attr: &mut IbvQpInitAttr) -> Result<Self, IOError> {
+        let ibv_qp = unsafe { ffi::ibv_create_qp(pd.ibv_pd.as_ptr(), qp_init_attr as *mut _) };
+        if ibv_qp.is_null() {
+            return Err(verbs_error("ibv_create_qp", IOError::last_os_error()));
+        }
+        unsafe {
+            Ok(Self {
+                ibv_qp: NonNull::new_unchecked(ibv_qp),
+                max_send_sge: qp_init_attr.cap.max_send_sge,
+                max_recv_sge: qp_init_attr.cap.max_recv_sge,
+                max_inline_data: qp_init_attr.cap.max_inline_data,
+            })
+        }
+    }
+    // Creates the QP through ibv_create_qp_ex, in the PD set on `qp_init_attr`.
+    pub fn with_attr_ex(
+        context: &IbvContext,
+        qp_init_attr: &mut IbvQpInitAttrEx,
+    ) -> Result<Self, IOError> {
+        if qp_init_attr.comp_mask & ffi::ibv_qp_init_attr_mask::IBV_QP_INIT_ATTR_PD.0 == 0
+            || qp_init_attr.pd.is_null()
+        {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "ibv_create_qp_ex requires a PD, see IbvQpInitAttrEx::set_pd",
+            ));
+        }
+        let create_qp_ex = verbs_ctx_op!(context.as_mut_ptr(), create_qp_ex)
+            .ok_or_else(|| unsupported_verb("ibv_create_qp_ex"))?;
+        let ibv_qp = unsafe { create_qp_ex(context.as_mut_ptr(), qp_init_attr as *mut _) };
+        if ibv_qp.is_null() {
+            return Err(verbs_error("ibv_create_qp", IOError::last_os_error()));
+        }
+        unsafe {
+            Ok(Self {
+                ibv_qp: NonNull::new_unchecked(ibv_qp),
+                max_send_sge: qp_init_attr.cap.max_send_sge,
+                max_recv_sge: qp_init_attr.cap.max_recv_sge,
+                max_inline_data: qp_init_attr.cap.max_inline_data,
+            })
+        }
+    }
+    // Escape hatch for transitions and attribute changes the helpers below don't
+    // cover; only the fields selected by `mask` are read from `attr`.
+    pub fn modify(&self, attr: &IbvQpAttr, mask: QpAttrMask) -> Result<(), IOError> {
+        let mut attr = *attr;
+        let ret =
+            unsafe { ffi::ibv_modify_qp(self.ibv_qp.as_ptr(), &mut attr as *mut _, mask.0 as i32) };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    // The local ACK timeout; None means the QP waits forever (timeout 0).
+    pub fn timeout(&self) -> Result<Option<Duration>, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT.0)?;
+        Ok(timeout_to_duration(qp_attr.timeout))
+    }
+    // Changes the ACK timeout of a connected QP and returns the value actually
+    // programmed. The timeout may only be modified in SQD (RTS to RTS rejects it):
+    // call `modify_rts2sqd`, wait for IBV_EVENT_SQ_DRAINED, set it, then
+    // `modify_sqd2rts`. To pick it when connecting, use
+    // `modify_rtr2rts_with_timeout` instead.
+    pub fn set_timeout(&self, ack_timeout: Duration) -> Result<Duration, IOError> {
+        self.check_sqd("the ACK timeout")?;
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.timeout = checked_timeout_exp(ack_timeout)?;
+        self.modify(&qp_attr, ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT)?;
+        Ok(timeout_to_duration(qp_attr.timeout).unwrap_or_default())
+    }
+    pub fn rnr_timer(&self) -> Result<Duration, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER.0)?;
+        Ok(min_rnr_timer_to_duration(qp_attr.min_rnr_timer).unwrap_or_default())
+    }
+    // Sets the RNR NAK delay of an RTS QP and returns the value actually
+    // programmed. An RTR QP takes it only with the move to RTS, so it is rejected
+    // here.
+    pub fn set_rnr_timer(&self, min_rnr_timer: Duration) -> Result<Duration, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_STATE.0)?;
+        if qp_attr.qp_state != ffi::ibv_qp_state::IBV_QPS_RTS {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the RNR timer can only be changed in RTS, QP {} is in state {}",
+                    self.qpn(),
+                    qp_attr.qp_state
+                ),
+            ));
+        }
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.min_rnr_timer = min_rnr_timer_from_duration(min_rnr_timer);
+        self.modify(&qp_attr, ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER)?;
+        Ok(min_rnr_timer_to_duration(qp_attr.min_rnr_timer).unwrap_or_default())
+    }
+    // A peer's atomics on `mr` through this QP fail with a remote access error
+    // unless both carry IBV_ACCESS_REMOTE_ATOMIC; check before handing out the rkey.
+    pub fn check_remote_atomic(&self, mr: &IbvMr) -> Result<(), IOError> {
+        let missing = match (
+            self.access_flags()?
+                .contains(IbvAccess::IBV_ACCESS_REMOTE_ATOMIC),
+            mr.access().contains(IbvAccess::IBV_ACCESS_REMOTE_ATOMIC),
+        ) {
+            (true, true) => return Ok(()),
+            (false, true) => "the QP",
+            (true, false) => "the MR",
+            (false, false) => "the QP and the MR",
+        };
+        Err(IOError::new(
+            ErrorKind::PermissionDenied,
+            format!("{} lack IBV_ACCESS_REMOTE_ATOMIC", missing),
+        ))
+    }
+    pub fn access_flags(&self) -> Result<IbvAccess, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS.0)?;
+        Ok(ibv_access_flags(qp_attr.qp_access_flags))
+    }
+    // Changes the remote access the peer has through this QP, e.g. dropping
+    // IBV_ACCESS_REMOTE_WRITE to make it read-only, without a reconnect. Allowed in
+    // INIT, RTR and RTS; operations already in flight are not affected.
+    pub fn set_access_flags(&self, access: IbvAccess) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.qp_access_flags = access.0;
+        self.modify(&qp_attr, ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS)
+    }
+    pub fn modify_reset2init(&self, port_num: u8) -> Result<(), IOError> {
+        self.modify_reset2init_with_pkey(port_num, 0)
+    }
+    // `pkey_index` indexes the port's P_Key table; use `IbvContext::find_pkey_index`
+    // to look up the partition the QP should join.
+    pub fn modify_reset2init_with_pkey(
+        &self,
+        port_num: u8,
+        pkey_index: u16,
+    ) -> Result<(), IOError> {
+        // Kept permissive for compatibility: atomic access is granted even when the
+        // device can't execute atomics.
+        self.reset2init(port_num, pkey_index, IbvAccess::remote_all(), false)
+    }
+    // RESET->INIT granting the peer `access` through this QP. Asking for remote
+    // atomics on a device without atomic support fails here instead of at the
+    // peer's first atomic. The target MR also needs IBV_ACCESS_REMOTE_ATOMIC, see
+    // `check_remote_atomic`.
+    pub fn modify_reset2init_with_access(
+        &self,
+        port_num: u8,
+        pkey_index: u16,
+        access: IbvAccess,
+    ) -> Result<(), IOError> {
+        self.reset2init(port_num, pkey_index, access, true)
+    }
+    fn reset2init(
+        &self,
+        port_num: u8,
+        pkey_index: u16,
+        access: IbvAccess,
+        check_atomic: bool,
+    ) -> Result<(), IOError> {
+        let mut port_attr = unsafe { std::mem::zeroed::<IbvPortAttr>() };
+        let ret = unsafe {
+            ffi::ibv_query_port(
+                self.ibv_qp.as_ref().context,
+                port_num,
+                &mut port_attr as *mut _ as *mut ffi::_compat_ibv_port_attr,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        if pkey_index >= port_attr.pkey_tbl_len() {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "pkey_index {} out of range, port {} has {} P_Key entries",
+                    pkey_index,
+                    port_num,
+                    port_attr.pkey_tbl_len()
+                ),
+            ));
+        }
+        if check_atomic && access.contains(IbvAccess::IBV_ACCESS_REMOTE_ATOMIC) {
+            let mut device_attr = unsafe { std::mem::zeroed::<IbvDeviceAttr>() };
+            let ret =
+                unsafe { ffi::ibv_query_device(self.ibv_qp.as_ref().context, &mut device_attr) };
+            if ret != 0 {
+                return Err(IOError::last_os_error());
+            }
+            if !device_attr.atomic_cap_enum().supports_atomics() {
+                return Err(IOError::new(
+                    ErrorKind::Unsupported,
+                    "IBV_ACCESS_REMOTE_ATOMIC requested but the device has no atomic support",
+                ));
+            }
+        }
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_INIT;
+        qp_attr.pkey_index = pkey_index;
+        qp_attr.port_num = port_num;
+        qp_attr.qp_access_flags = access.0;
+
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PKEY_INDEX.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PORT.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS.0) as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn modify_init2rtr(
+        &self,
+
+        sl: u8,
+        port_num: u8,
+        remote_qpn: u32,
+        remote_psn: u32,
+        remote_lid: u16,
+    ) -> Result<(), IOError> {
+        self.modify_init2rtr_with_mtu(
+            sl,
+            port_num,
+            remote_qpn,
+            remote_psn,
+            remote_lid,
+            IbvMtu::Mtu1024,
+        )
+    }
+    pub fn modify_init2rtr_with_mtu(
+        &self,
+        sl: u8,
+        port_num: u8,
+        remote_qpn: u32,
+        remote_psn: u32,
+        remote_lid: u16,
+        path_mtu: IbvMtu,
+    ) -> Result<(), IOError> {
+        let ah_attr = IbvAhAttrBuilder::new(port_num)
+            .dlid(remote_lid)
+            .sl(sl)
+            .build();
+        self.modify_init2rtr_with_ah(remote_qpn, remote_psn, path_mtu, &ah_attr)
+    }
+    // RoCE / routed IB variant: the remote is addressed by GID through the GRH.
+    // `traffic_class` carries the DSCP bits, see `dscp_to_traffic_class`. The path
+    // MTU is the port's active MTU; use `modify_init2rtr_with_ah` to pick another.
+    pub fn modify_init2rtr_global(
+        &self,
+        port_num: u8,
+        remote_qpn: u32,
+        remote_psn: u32,
+        remote_gid: IbvGid,
+        sgid_index: u8,
+        traffic_class: u8,
+    ) -> Result<(), IOError> {
+        let mut port_attr = unsafe { std::mem::zeroed::<IbvPortAttr>() };
+        let ret = unsafe {
+            ffi::ibv_query_port(
+                self.ibv_qp.as_ref().context,
+                port_num,
+                &mut port_attr as *mut _ as *mut ffi::_compat_ibv_port_attr,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        let path_mtu = IbvMtu::try_from(port_attr.active_mtu()).unwrap_or(IbvMtu::Mtu1024);
+        let ah_attr = IbvAhAttrBuilder::new(port_num)
+            .global(remote_gid, sgid_index)
+            .traffic_class(traffic_class)
+            .build();
+        self.modify_init2rtr_with_ah(remote_qpn, remote_psn, path_mtu, &ah_attr)
+    }
+    // INIT -> RTR towards an exchanged endpoint: by GID when the local port is RoCE
+    // (`local.gid` set), by LID otherwise.
+    pub fn modify_init2rtr_to(
+        &self,
+        port_num: u8,
+        local: &IbvEndpoint,
+        remote: &IbvEndpoint,
+        path_mtu: IbvMtu,
+    ) -> Result<(), IOError> {
+        let ah_attr = IbvAhAttrBuilder::new(port_num);
+        let ah_attr = match (local.gid, remote.gid) {
+            (Some(_), Some(gid)) => ah_attr.global(gid, local.gid_index),
+            (Some(_), None) => {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    "RoCE port but the peer's endpoint carries no GID",
+                ))
+            }
+            (None, _) => ah_attr.dlid(remote.lid),
+        };
+        self.modify_init2rtr_with_ah(remote.qpn, remote.psn, path_mtu, &ah_attr.build())
+    }
+    pub fn modify_init2rtr_with_ah(
+        &self,
+        remote_qpn: u32,
+        remote_psn: u32,
+        path_mtu: IbvMtu,
+        ah_attr: &IbvAhAttr,
+    ) -> Result<(), IOError> {
+        // 0.64ms
+        self.modify_init2rtr_with_rnr_code(remote_qpn, remote_psn, path_mtu, ah_attr, 12)
+    }
+    // `min_rnr_timer` is the delay the peer is told to wait before retrying a SEND
+    // that found no receive posted; rounded to the nearest value the hardware can
+    // express, see `min_rnr_timer_from_duration`.
+    pub fn modify_init2rtr_with_rnr_timer(
+        &self,
+        remote_qpn: u32,
+        remote_psn: u32,
+        path_mtu: IbvMtu,
+        ah_attr: &IbvAhAttr,
+        min_rnr_timer: Duration,
+    ) -> Result<(), IOError> {
+        self.modify_init2rtr_with_rnr_code(
+            remote_qpn,
+            remote_psn,
+            path_mtu,
+            ah_attr,
+            min_rnr_timer_from_duration(min_rnr_timer),
+        )
+    }
+    fn modify_init2rtr_with_rnr_code(
+        &self,
+        remote_qpn: u32,
+        remote_psn: u32,
+        path_mtu: IbvMtu,
+        ah_attr: &IbvAhAttr,
+        min_rnr_timer: u8,
+    ) -> Result<(), IOError> {
+        self.check_av_link_layer(ah_attr)?;
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTR;
+        qp_attr.path_mtu = path_mtu.into();
+        qp_attr.dest_qp_num = remote_qpn;
+        qp_attr.rq_psn = remote_psn;
+        qp_attr.max_dest_rd_atomic = 1;
+        qp_attr.min_rnr_timer = min_rnr_timer;
+        qp_attr.ah_attr = *ah_attr;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_AV.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_DEST_QPN.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_RQ_PSN.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER.0) as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+
+    // Catches an address vector of the wrong kind for the port before the QP is
+    // moved: RoCE has no LIDs, so a LID-only AV there yields a QP that silently
+    // never connects. Skipped if the port can't be queried.
+    fn check_av_link_layer(&self, ah_attr: &IbvAhAttr) -> Result<(), IOError> {
+        let mut port_attr = unsafe { std::mem::zeroed::<IbvPortAttr>() };
+        let ret = unsafe {
+            ffi::ibv_query_port(
+                self.ibv_qp.as_ref().context,
+                ah_attr.port_num,
+                &mut port_attr as *mut _ as *mut ffi::_compat_ibv_port_attr,
+            )
+        };
+        if ret != 0 || ah_attr.is_global != 0 {
+            return Ok(());
+        }
+        if port_attr.link_layer == ffi::IBV_LINK_LAYER_ETHERNET as u8 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "LID-based transition called on Ethernet port {}; use the GID-based path \
+                     (modify_init2rtr_global or IbvAhAttrBuilder::global)",
+                    ah_attr.port_num
+                ),
+            ));
+        }
+        if ah_attr.dlid == 0 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "destination LID 0 on InfiniBand port {}: the peer's port has no LID \
+                     (is a subnet manager running?)",
+                    ah_attr.port_num
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn modify_rtr2rts(&self, psn: u32) -> Result<(), IOError> {
+        self.modify_rtr2rts_with_timeout_exp(psn, 14)
+    }
+    // `ack_timeout` is rounded to the nearest value the hardware can express, see
+    // `timeout_from_duration`.
+    pub fn modify_rtr2rts_with_timeout(
+        &self,
+        psn: u32,
+        ack_timeout: Duration,
+    ) -> Result<(), IOError> {
+        self.modify_rtr2rts_with_timeout_exp(psn, checked_timeout_exp(ack_timeout)?)
+    }
+    fn modify_rtr2rts_with_timeout_exp(&self, psn: u32, timeout: u8) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTS;
+        qp_attr.timeout = timeout;
+        qp_attr.retry_cnt = 7;
+        qp_attr.rnr_retry = 7;
+        qp_attr.sq_psn = psn;
+        qp_attr.max_rd_atomic = 1;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_RETRY_CNT.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_RNR_RETRY.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_SQ_PSN.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC.0) as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    // UC transitions. UC carries SEND and RDMA WRITE without acknowledgements, so
+    // there are no retries and no responder resources: RDMA READ and atomics are
+    // not available, and RTR/RTS omit the fields RC uses for them.
+    pub fn modify_reset2init_uc(&self, port_num: u8, pkey_index: u16) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_INIT;
+        qp_attr.pkey_index = pkey_index;
+        qp_attr.port_num = port_num;
+        qp_attr.qp_access_flags = ffi::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0
+            | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_WRITE.0;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PKEY_INDEX.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PORT.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS.0) as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn modify_init2rtr_uc(
+        &self,
+        remote_qpn: u32,
+        remote_psn: u32,
+        path_mtu: IbvMtu,
+        ah_attr: &IbvAhAttr,
+    ) -> Result<(), IOError> {
+        self.check_av_link_layer(ah_attr)?;
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTR;
+        qp_attr.path_mtu = path_mtu.into();
+        qp_attr.dest_qp_num = remote_qpn;
+        qp_attr.rq_psn = remote_psn;
+        qp_attr.ah_attr = *ah_attr;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_AV.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_DEST_QPN.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_RQ_PSN.0) as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn modify_rtr2rts_uc(&self, psn: u32) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTS;
+        qp_attr.sq_psn = psn;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0 | ffi::ibv_qp_attr_mask::IBV_QP_SQ_PSN.0)
+                    as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    // UD transitions. A datagram is only delivered when the Q_Key it carries matches
+    // the receiving QP's Q_Key, otherwise the receiver drops it silently.
+    pub fn modify_reset2init_ud(
+        &self,
+        port_num: u8,
+        pkey_index: u16,
+        qkey: u32,
+    ) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_INIT;
+        qp_attr.pkey_index = pkey_index;
+        qp_attr.port_num = port_num;
+        qp_attr.qkey = qkey;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PKEY_INDEX.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_PORT.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_QKEY.0) as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn modify_init2rtr_ud(&self) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTR;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                ffi::ibv_qp_attr_mask::IBV_QP_STATE.0 as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn modify_rtr2rts_ud(&self, psn: u32) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTS;
+        qp_attr.sq_psn = psn;
+        let ret = unsafe {
+            ffi::ibv_modify_qp(
+                self.ibv_qp.as_ptr(),
+                &mut qp_attr as *mut _,
+                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0 | ffi::ibv_qp_attr_mask::IBV_QP_SQ_PSN.0)
+                    as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_modify_qp",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    // Moves an RTS QP to SQD. The send queue stops taking new WRs but drains the
+    // ones already posted; IBV_EVENT_SQ_DRAINED is raised once it is empty.
+    pub fn modify_rts2sqd(&self) -> Result<(), IOError> {
+        self.modify_rts2sqd_notify(true)
+    }
+    // Like `modify_rts2sqd`, with the IBV_EVENT_SQ_DRAINED notification optional;
+    // without it the drain can only be seen by polling `query` for sq_draining.
+    pub fn modify_rts2sqd_notify(&self, notify: bool) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_SQD;
+        qp_attr.en_sqd_async_notify = notify as u8;
+        self.modify(
+            &qp_attr,
+            ffi::ibv_qp_attr_mask(
+                ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
+                    | ffi::ibv_qp_attr_mask::IBV_QP_EN_SQD_ASYNC_NOTIFY.0,
+            ),
+        )
+    }
+    // Waits up to `timeout` for this QP's IBV_EVENT_SQ_DRAINED after
+    // `modify_rts2sqd`; false on timeout. Other async events read from `context` in
+    // the meantime are acknowledged and dropped, so don't use it while something
+    // else handles the context's events.
+    pub fn wait_sq_drained(
+        &self,
+        context: &IbvContext,
+        timeout: Duration,
+    ) -> Result<bool, IOError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match context.wait_async_event(left)? {
+                None => return Ok(false),
+                Some(event) if event.is_sq_drained() && event.qp() == Some(self.ibv_qp) => {
+                    return Ok(true)
+                }
+                Some(_) if left.is_zero() => return Ok(false),
+                Some(_) => {}
+            }
+        }
+    }
+    pub fn modify_sqd2rts(&self) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTS;
+        self.modify(&qp_attr, ffi::ibv_qp_attr_mask::IBV_QP_STATE)
+    }
+    // Repoints a connected QP at a new primary path, e.g. after a GID change or a
+    // fabric reroute, without reconnecting. The address vector may only be modified
+    // in SQD: call `modify_rts2sqd`, wait for IBV_EVENT_SQ_DRAINED, update, then
+    // `modify_sqd2rts`. Providers may still refuse with EINVAL.
+    pub fn update_av(&self, ah_attr: &IbvAhAttr) -> Result<(), IOError> {
+        self.check_sqd("the address vector")?;
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        self.check_av_link_layer(ah_attr)?;
+        qp_attr.ah_attr = *ah_attr;
+        self.modify(&qp_attr, ffi::ibv_qp_attr_mask::IBV_QP_AV)
+    }
+    fn check_sqd(&self, what: &str) -> Result<(), IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_STATE.0)?;
+        if qp_attr.qp_state != ffi::ibv_qp_state::IBV_QPS_SQD {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} can only be changed in SQD, QP {} is in state {}",
+                    what,
+                    self.qpn(),
+                    qp_attr.qp_state
+                ),
+            ));
+        }
+        Ok(())
+    }
+    // Everything a connection negotiated, in one ibv_query_qp, e.g. to log it once
+    // the QP reaches RTS. Fields that don't apply to the QP's type or state read
+    // as the provider reports them, usually 0.
+    pub fn connection_info(&self) -> Result<ConnectionInfo, IOError> {
+        use ffi::ibv_qp_attr_mask as mask;
+        let (attr, _) = self.query(
+            mask::IBV_QP_STATE.0
+                | mask::IBV_QP_PORT.0
+                | mask::IBV_QP_AV.0
+                | mask::IBV_QP_PATH_MTU.0
+                | mask::IBV_QP_DEST_QPN.0
+                | mask::IBV_QP_SQ_PSN.0
+                | mask::IBV_QP_RQ_PSN.0
+                | mask::IBV_QP_TIMEOUT.0
+                | mask::IBV_QP_RETRY_CNT.0
+                | mask::IBV_QP_RNR_RETRY.0
+                | mask::IBV_QP_MIN_RNR_TIMER.0
+                | mask::IBV_QP_MAX_QP_RD_ATOMIC.0
+                | mask::IBV_QP_MAX_DEST_RD_ATOMIC.0
+                | mask::IBV_QP_PATH_MIG_STATE.0,
+        )?;
+        let ah = &attr.ah_attr;
+        Ok(ConnectionInfo {
+            qp_num: self.qpn(),
+            qp_type: self.qp_type(),
+            state: attr.qp_state,
+            port_num: attr.port_num,
+            path_mtu: IbvMtu::try_from(attr.path_mtu).ok(),
+            dest_qp_num: attr.dest_qp_num,
+            sq_psn: attr.sq_psn,
+            rq_psn: attr.rq_psn,
+            dlid: ah.dlid,
+            sl: ah.sl,
+            static_rate: ah.static_rate,
+            dgid: (ah.is_global != 0).then(|| ah.grh.dgid.to_ipv6()),
+            sgid_index: ah.grh.sgid_index,
+            timeout: timeout_to_duration(attr.timeout),
+            retry_cnt: attr.retry_cnt,
+            rnr_retry: attr.rnr_retry,
+            min_rnr_timer: min_rnr_timer_to_duration(attr.min_rnr_timer),
+            max_rd_atomic: attr.max_rd_atomic,
+            max_dest_rd_atomic: attr.max_dest_rd_atomic,
+            path_mig_state: PathMigState::try_from(attr.path_mig_state).ok(),
+        })
+    }
+    pub fn path_mig_state(&self) -> Result<PathMigState, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_PATH_MIG_STATE.0)?;
+        PathMigState::try_from(qp_attr.path_mig_state).map_err(|_| {
+            IOError::new(
+                ErrorKind::InvalidData,
+                format!("unknown path_mig_state {}", qp_attr.path_mig_state),
+            )
+        })
+    }
+    // Re-arms automatic path migration on an RTS QP, e.g. after a failover moved it
+    // to the alternate path. A valid alternate path must be loaded first; the state
+    // goes REARM -> ARMED once both sides have synchronised.
+    pub fn arm_path_migration(&self) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.path_mig_state = ffi::ibv_mig_state::IBV_MIG_REARM;
+        self.modify(&qp_attr, QpAttrMask::IBV_QP_PATH_MIG_STATE)
+    }
+    pub fn qkey(&self) -> Result<u32, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_QKEY.0)?;
+        Ok(qp_attr.qkey)
+    }
+    // Checks that this QP's Q_Key matches the one a peer advertised, since a mismatch
+    // is never reported: the datagrams just vanish.
+    pub fn check_qkey(&self, peer_qkey: u32) -> Result<(), IOError> {
+        let qkey = self.qkey()?;
+        if qkey != peer_qkey {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("qkey mismatch: local {:#x}, peer {:#x}", qkey, peer_qkey),
+            ));
+        }
+        Ok(())
+    }
+    // Drives RESET -> INIT -> RTR -> RTS. The path MTU is clamped to the smallest of
+    // `mtu` and both ports' MTUs; the MTU actually used is returned.
+    pub fn connect(
+        &self,
+        port_num: u8,
+        local: &IbvEndpoint,
+        remote: &IbvEndpoint,
+        mtu: IbvMtu,
+    ) -> Result<IbvMtu, IOError> {
+        let mtu = mtu.min(local.mtu).min(remote.mtu);
+        self.modify_reset2init(port_num)?;
+        self.modify_init2rtr_to(port_num, local, remote, mtu)?;
+        self.modify_rtr2rts(local.psn)?;
+        Ok(mtu)
+    }
+    #[inline(always)]
+    pub fn qpn(&self) -> u32 {
+        unsafe { self.ibv_qp.as_ref().qp_num }
+    }
+    pub fn query(&self, attr_mask: u32) -> Result<(IbvQpAttr, IbvQpInitAttr), IOError> {
+        let mut ibv_qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
+        let mut ibv_qp_init_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_init_attr>() };
+        let ret = unsafe {
+            ffi::ibv_query_qp(
+                self.ibv_qp.as_ptr(),
+                &mut ibv_qp_attr as *mut _,
+                attr_mask as i32,
+                &mut ibv_qp_init_attr as *mut _,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        return Ok((ibv_qp_attr, ibv_qp_init_attr));
+    }
+    // ECE options are exchanged out of band: query the local QP before INIT->RTR,
+    // send the result to the peer, and set the peer's accepted options here.
+    pub fn query_ece(&self) -> Result<IbvEce, IOError> {
+        let mut ece = unsafe { std::mem::zeroed::<IbvEce>() };
+        let ret = unsafe { ffi::ibv_query_ece(self.ibv_qp.as_ptr(), &mut ece) };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(ece)
+    }
+    pub fn set_ece(&self, ece: &IbvEce) -> Result<(), IOError> {
+        let mut ece = *ece;
+        let ret = unsafe { ffi::ibv_set_ece(self.ibv_qp.as_ptr(), &mut ece) };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
+        }
+        Ok(())
+    }
+    // Whether data of `op` lands in memory in order, e.g. whether the last byte of
+    // an RDMA write can be polled as an arrival flag before its completion. false
+    // when the provider can't tell; call it on a connected QP, as the answer may
+    // depend on the path.
+    pub fn data_in_order(&self, op: ffi::ibv_wr_opcode::Type, flags: u32) -> bool {
+        unsafe { ffi::ibv_query_qp_data_in_order(self.ibv_qp.as_ptr(), op, flags) != 0 }
+    }
+    // The ibv_query_qp_data_in_order_caps that hold for `op`: WHOLE_MSG for the
+    // whole message, ALIGNED_128_BYTES when only each aligned 128-byte block is
+    // written in order.
+    pub fn data_in_order_caps(&self, op: ffi::ibv_wr_opcode::Type) -> u32 {
+        let flags = ffi::ibv_query_qp_data_in_order_flags_IBV_QUERY_QP_DATA_IN_ORDER_RETURN_CAPS;
+        let ret = unsafe { ffi::ibv_query_qp_data_in_order(self.ibv_qp.as_ptr(), op, flags) };
+        ret.max(0) as u32
+    }
+    // Flushes the QP before it is dropped: moves it to ERROR so every outstanding WR
+    // completes with IBV_WC_WR_FLUSH_ERR, then drains the send and receive CQs until
+    // marker WRs posted behind them come back. Everything reaped on the way is
+    // returned, including completions of other QPs sharing the CQs, so their
+    // buffers can still be released or the completions routed on.
+    //
+    // Call it before dropping the QP: Drop destroys it right away, and WRs still
+    // outstanding then never complete, so their buffers can't be safely reused.
+    // A UD send needs an address handle, so no send marker is posted on UD QPs and
+    // only the receive side is waited for; their send completions may trail.
+    pub fn shutdown(&self, timeout: Duration) -> Result<QpShutdown, IOError> {
+        const SEND_MARKER: u64 = u64::MAX - 1;
+        const RECV_MARKER: u64 = u64::MAX - 2;
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_STATE.0)?;
+        if qp_attr.qp_state == ffi::ibv_qp_state::IBV_QPS_RESET {
+            return Ok(QpShutdown::default());
+        }
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_ERR;
+        self.modify(&qp_attr, QpAttrMask::IBV_QP_STATE)?;
+
+        let (send_cq, recv_cq, srq) = unsafe {
+            let qp = self.ibv_qp.as_ref();
+            (qp.send_cq, qp.recv_cq, qp.srq)
+        };
+        let mut send_pending = self.qp_type() != Some(IbvQpType::Ud);
+        if send_pending {
+            let mut send_wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+            send_wr.wr_id = SEND_MARKER;
+            send_wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND;
+            send_wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+            // A marker that fails to post (e.g. ENOMEM with the SQ full) would never
+            // complete, so its error is returned right away rather than after
+            // `timeout`.
+            let mut bad_send_wr = std::ptr::null::<IbvSendWr>();
+            self.post_send(&send_wr, std::ptr::addr_of_mut!(bad_send_wr))?;
+        }
+        // receives posted to an SRQ aren't owned by this QP, so there is nothing to flush
+        let mut recv_pending = srq.is_null();
+        if recv_pending {
+            let mut recv_wr = unsafe { std::mem::zeroed::<IbvRecvWr>() };
+            recv_wr.wr_id = RECV_MARKER;
+            let mut bad_recv_wr = std::ptr::null::<IbvRecvWr>();
+            self.post_recv(&recv_wr, std::ptr::addr_of_mut!(bad_recv_wr))?;
+        }
+
+        let poll_cq = ctx_ops_fn!(self.ibv_qp.as_ref().context, poll_cq, "ibv_poll_cq")?;
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 16];
+        let mut drained = Vec::new();
+        let start = Instant::now();
+        while send_pending || recv_pending {
+            if start.elapsed() > timeout {
+                return Ok(QpShutdown {
+                    completions: drained,
+                    timed_out: true,
+                });
+            }
+            for cq in [send_cq, recv_cq] {
+                let n = unsafe { poll_cq(cq, wcs.len() as i32, wcs.as_mut_ptr()) };
+                if n < 0 {
+                    return Err(IOError::other("ibv_poll_cq() failed"));
+                }
+                for wc in &wcs[..n as usize] {
+                    match wc.wr_id {
+                        SEND_MARKER if wc.qp_num == self.qpn() => send_pending = false,
+                        RECV_MARKER if wc.qp_num == self.qpn() => recv_pending = false,
+                        _ => drained.push(*wc),
+                    }
+                }
+            }
+        }
+        Ok(QpShutdown {
+            completions: drained,
+            timed_out: false,
+        })
+    }
+    // Liveness probe: a zero-byte RDMA read against `remote` on the peer.
+    // Returns the round-trip time, or an error if the transport reports the peer as
+    // unreachable or nothing completes within `timeout`. Completions for other WRs
+    // reaped from `cq` while waiting are dropped, so use an idle or dedicated CQ.
+    pub fn ping(
+        &self,
+        cq: &IbvCq,
+        remote: &RemoteToken,
+        timeout: Duration,
+    ) -> Result<Duration, IOError> {
+        const PING_WR_ID: u64 = u64::MAX;
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        wr.wr_id = PING_WR_ID;
+        wr.opcode = ffi::ibv_wr_opcode::IBV_WR_RDMA_READ;
+        wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        wr.wr.rdma.remote_addr = remote.addr;
+        wr.wr.rdma.rkey = remote.rkey;
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        let start = Instant::now();
+        self.post_send(&wr, std::ptr::addr_of_mut!(bad_wr))?;
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 1];
+        loop {
+            let polled = cq
+                .poll(&mut wcs)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+            if let Some(wc) = polled.iter().find(|wc| wc.wr_id == PING_WR_ID) {
+                if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS {
+                    return Err(IOError::new(
+                        ErrorKind::ConnectionAborted,
+                        format!("peer unreachable: {}", wc.status_str()),
+                    ));
+                }
+                return Ok(start.elapsed());
+            }
+            if start.elapsed() > timeout {
+                return Err(IOError::new(ErrorKind::TimedOut, "ping timed out"));
+            }
+        }
+    }
+    // Primes a freshly connected RC QP before the first real transfer: `rounds`
+    // zero-byte RDMA reads make the NIC resolve the path and load the QP and
+    // address contexts into its caches. Zero-length RDMA carries no R_Key check, so
+    // no memory of the peer's is needed. Returns each round's RTT; the first is the
+    // outlier being absorbed. The same CQ caveat as `ping` applies.
+    pub fn warmup(
+        &self,
+        cq: &IbvCq,
+        rounds: u32,
+        timeout: Duration,
+    ) -> Result<Vec<Duration>, IOError> {
+        let nowhere = RemoteToken {
+            addr: 0,
+            rkey: 0,
+            length: 0,
+        };
+        (0..rounds.max(1))
+            .map(|_| self.ping(cq, &nowhere, timeout))
+            .collect()
+    }
+    // Fails with TooManySges before calling the verb if any WR in the chain gathers
+    // more SGEs than the QP was created with. Posts to one queue must not run
+    // concurrently; share the QP between threads through SyncQp.
+    pub fn post_send(
+        &self,
+        wr: &IbvSendWr,
+        bad_wr: *const *const IbvSendWr,
+    ) -> Result<(), IOError> {
+        let mut next: *const IbvSendWr = wr;
+        while let Some(wr) = unsafe { next.as_ref() } {
+            check_sges(wr.num_sge, self.max_send_sge)?;
+            next = wr.next;
+        }
+        self.post_send_unchecked(wr, bad_wr)
+    }
+    fn post_send_unchecked(
+        &self,
+        wr: &IbvSendWr,
+        bad_wr: *const *const IbvSendWr,
+    ) -> Result<(), IOError> {
+        let ibv_post_send = ctx_ops_fn!(self.ibv_qp.as_ref().context, post_send, "ibv_post_send")?;
+        let ret = unsafe {
+            ibv_post_send(
+                self.ibv_qp.as_ptr(),
+                wr as *const _ as *mut _,
+                bad_wr as *mut _,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_post_send",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    #[inline(always)]
+    pub fn qp_type(&self) -> Option<IbvQpType> {
+        IbvQpType::try_from(unsafe { self.ibv_qp.as_ref().qp_type }).ok()
+    }
+    pub fn supports(&self, op: RdmaOp) -> bool {
+        self.qp_type()
+            .is_some_and(|qp_type| op.supported_on(qp_type))
+    }
+    // Rejects opcodes this QP type can't carry before the NIC does it less clearly.
+    fn check_opcode(&self, opcode: ffi::ibv_wr_opcode::Type) -> Result<(), IOError> {
+        match RdmaOp::from_wr_opcode(opcode) {
+            Some(op) if !self.supports(op) => Err(IOError::new(
+                ErrorKind::Unsupported,
+                format!("{:?} is not supported on a {:?} QP", op, self.qp_type()),
+            )),
+            _ => Ok(()),
+        }
+    }
+    // The caps the provider actually granted, which may exceed the requested ones.
+    pub fn cap(&self) -> Result<IbvQpCap, IOError> {
+        self.query_cap()
+    }
+    // The QP's current caps, read back from the provider on every call rather than
+    // taken from the creation attributes, so a change made by the driver on a state
+    // transition shows up here.
+    pub fn query_cap(&self) -> Result<IbvQpCap, IOError> {
+        let (qp_attr, _) = self.query(ffi::ibv_qp_attr_mask::IBV_QP_CAP.0)?;
+        Ok(qp_attr.cap)
+    }
+    // A SEND gathering `sges` into one message, e.g. a header and a body from two
+    // MRs. Returns the message length, which is the byte_len the receiver sees.
+    pub fn post_send_sges(
+        &self,
+        wr_id: u64,
+        sges: &[IbvSge],
+        flags: ffi::ibv_send_flags,
+    ) -> Result<u32, IOError> {
+        self.post_sge_list(
+            wr_id,
+            sges,
+            flags,
+            ffi::ibv_wr_opcode::IBV_WR_SEND,
+            None,
+            None,
+        )
+    }
+    // An RDMA READ of `remote` scattered over `sges`, which may span several local
+    // MRs and are filled in order. Their lengths must add up to `remote.length`.
+    pub fn post_rdma_read(
+        &self,
+        wr_id: u64,
+        sges: &[IbvSge],
+        remote: &RemoteToken,
+    ) -> Result<u32, IOError> {
+        self.post_sge_list(
+            wr_id,
+            sges,
+            ffi::ibv_send_flags::IBV_SEND_SIGNALED,
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_READ,
+            Some(remote),
+            None,
+        )
+    }
+    // An RDMA WRITE of `sges` to the start of `remote` carrying `imm_data` (host
+    // order). `sges` may be empty: a zero-length write with immediate moves no data
+    // and makes a cheap barrier or doorbell. Either way it consumes a receive WR at
+    // the peer, which gets an IBV_WC_RECV_RDMA_WITH_IMM completion with the
+    // immediate and byte_len set to the bytes written, 0 for the empty case.
+    pub fn post_write_with_imm(
+        &self,
+        wr_id: u64,
+        sges: &[IbvSge],
+        remote: &RemoteToken,
+        imm_data: u32,
+        flags: ffi::ibv_send_flags,
+    ) -> Result<u32, IOError> {
+        self.post_sge_list(
+            wr_id,
+            sges,
+            flags,
+            ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM,
+            Some(remote),
+            Some(imm_data),
+        )
+    }
+    fn post_sge_list(
+        &self,
+        wr_id: u64,
+        sges: &[IbvSge],
+        flags: ffi::ibv_send_flags,
+        opcode: ffi::ibv_wr_opcode::Type,
+        remote: Option<&RemoteToken>,
+        imm_data: Option<u32>,
+    ) -> Result<u32, IOError> {
+        self.check_opcode(opcode)?;
+        if sges.is_empty() && opcode != ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM {
+            return Err(IOError::new(ErrorKind::InvalidInput, "no SGEs given"));
+        }
+        check_sges(sges.len() as i32, self.max_send_sge)?;
+        let total = sges
+            .iter()
+            .try_fold(0u32, |acc, sge| acc.checked_add(sge.length))
+            .ok_or_else(|| IOError::new(ErrorKind::InvalidInput, "message longer than 4 GiB"))?;
+        if flags.0 & ffi::ibv_send_flags::IBV_SEND_INLINE.0 != 0 && total > self.max_inline_data {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "inline message of {} bytes exceeds max_inline_data {}",
+                    total, self.max_inline_data
+                ),
+            ));
+        }
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        wr.wr_id = wr_id;
+        if !sges.is_empty() {
+            wr.sg_list = sges.as_ptr() as *mut _;
+        }
+        wr.num_sge = sges.len() as i32;
+        wr.opcode = opcode;
+        wr.send_flags = flags.0;
+        if let Some(imm_data) = imm_data {
+            wr.__bindgen_anon_1.imm_data = imm_data.to_be();
+        }
+        if let Some(remote) = remote {
+            // A read scatters exactly the remote range; a write may fill a prefix.
+            let fits = if opcode == ffi::ibv_wr_opcode::IBV_WR_RDMA_READ {
+                total as u64 == remote.length
+            } else {
+                total as u64 <= remote.length
+            };
+            if !fits {
+                return Err(IOError::new(
+                    ErrorKind::InvalidInput,
+                    format!(
+                        "local SGEs cover {} bytes but the remote range is {} bytes",
+                        total, remote.length
+                    ),
+                ));
+            }
+            wr.wr.rdma.remote_addr = remote.addr;
+            wr.wr.rdma.rkey = remote.rkey;
+        }
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        self.post_send(&wr, std::ptr::addr_of_mut!(bad_wr))?;
+        Ok(total)
+    }
+    // Fails with TooManySges like `post_send`.
+    pub fn post_recv(
+        &self,
+        wr: &IbvRecvWr,
+        bad_wr: *const *const IbvRecvWr,
+    ) -> Result<(), IOError> {
+        let mut next: *const IbvRecvWr = wr;
+        while let Some(wr) = unsafe { next.as_ref() } {
+            check_sges(wr.num_sge, self.max_recv_sge)?;
+            next = wr.next;
+        }
+        let ibv_post_recv = ctx_ops_fn!(self.ibv_qp.as_ref().context, post_recv, "ibv_post_recv")?;
+        let ret = unsafe {
+            ibv_post_recv(
+                self.ibv_qp.as_ptr(),
+                wr as *const _ as *mut _,
+                bad_wr as *mut _,
+            )
+        };
+        if ret != 0 {
+            return Err(verbs_error(
+                "ibv_post_recv",
+                IOError::from_raw_os_error(ret),
+            ));
+        }
+        Ok(())
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_qp {
+        self.ibv_qp.as_ptr()
+    }
+    /// The raw QP for provider extensions such as mlx5dv. The pointer stays owned by
+    /// this `IbvQp`: it must not be destroyed, and must not be used after the `IbvQp`
+    /// is dropped.
+    #[inline(always)]
+    pub fn as_raw(&self) -> *mut ffi::ibv_qp {
+        self.ibv_qp.as_ptr()
+    }
+    /// Posts `wr` (and any WRs chained through `next`) as is, without the opcode and
+    /// SGE checks of the safe helpers, e.g. for provider-specific opcodes such as
+    /// IBV_WR_DRIVER1 that this crate doesn't model.
+    ///
+    /// # Safety
+    /// Every field of the chain is passed to the provider unchecked: the opcode and
+    /// its union members must be valid for this QP, and every `sg_list`, `next` and
+    /// AH pointer must stay valid until the call returns. The memory the SGEs
+    /// describe must stay registered until the WRs complete.
+    pub unsafe fn post_send_raw(&self, wr: &IbvSendWr) -> Result<(), IOError> {
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        self.post_send_unchecked(wr, std::ptr::addr_of_mut!(bad_wr))
+    }
+    // Posts a WR that owns its gather list; the same PostedWr can be posted again
+    // once its completion is reaped.
+    pub fn post(&self, wr: &PostedWr) -> Result<(), IOError> {
+        self.check_opcode(wr.wr.opcode)?;
+        let raw = wr.as_raw();
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        self.post_send(&raw, std::ptr::addr_of_mut!(bad_wr))
+    }
+    // Checks `wr` against this QP once and freezes it into a template for
+    // `post_template`.
+    pub fn send_template(&self, wr: PostedWr) -> Result<SendTemplate, IOError> {
+        self.check_opcode(wr.wr.opcode)?;
+        check_sges(wr.sges.len() as i32, self.max_send_sge)?;
+        let sges = wr.sges.into_boxed_slice();
+        let mut raw = wr.wr;
+        if !sges.is_empty() {
+            raw.sg_list = sges.as_ptr() as *mut _;
+        }
+        raw.num_sge = sges.len() as i32;
+        Ok(SendTemplate {
+            wr: raw,
+            sges,
+            qp_num: self.qpn(),
+        })
+    }
+    // Posts `template` under `wr_id` without rebuilding or rechecking the WR. As
+    // with `post`, the template can be posted again once its completion is reaped.
+    pub fn post_template(&self, template: &mut SendTemplate, wr_id: u64) -> Result<(), IOError> {
+        if template.qp_num != self.qpn() {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "template was built for QP {}, not QP {}",
+                    template.qp_num,
+                    self.qpn()
+                ),
+            ));
+        }
+        template.wr.wr_id = wr_id;
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        self.post_send_unchecked(&template.wr, std::ptr::addr_of_mut!(bad_wr))
+    }
+    pub fn post_recv_wr(&self, wr: &PostedRecvWr) -> Result<(), IOError> {
+        let raw = wr.as_raw();
+        let mut bad_wr = std::ptr::null::<IbvRecvWr>();
+        self.post_recv(&raw, std::ptr::addr_of_mut!(bad_wr))
+    }
+    // Posts `ops` as one linked chain behind a single doorbell, e.g. a write of the
+    // payload followed by a send-with-imm telling the peer it is there. Only the
+    // last op is signaled; its completion also retires the ones before it. If the
+    // provider rejects an op, the ones before it have still been posted.
+    pub fn post_send_chain(&self, ops: &[PostOp]) -> Result<(), IOError> {
+        if ops.is_empty() {
+            return Ok(());
+        }
+        // All gather lists share one allocation, which must not move once the WRs
+        // point into it.
+        let mut sges = Vec::with_capacity(ops.iter().map(|op| op.sges().len()).sum());
+        for op in ops {
+            sges.extend_from_slice(op.sges());
+        }
+        let mut wrs = Vec::with_capacity(ops.len());
+        let mut offset = 0;
+        for op in ops {
+            let mut wr = op.to_wr()?;
+            self.check_opcode(wr.opcode)?;
+            let num_sge = op.sges().len();
+            wr.sg_list = if num_sge == 0 {
+                std::ptr::null_mut()
+            } else {
+                sges[offset..].as_mut_ptr()
+            };
+            wr.num_sge = num_sge as i32;
+            offset += num_sge;
+            wrs.push(wr);
+        }
+        for i in 1..wrs.len() {
+            let next: *mut IbvSendWr = &mut wrs[i];
+            wrs[i - 1].next = next;
+        }
+        if let Some(last) = wrs.last_mut() {
+            last.send_flags |= ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        }
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        self.post_send(&wrs[0], std::ptr::addr_of_mut!(bad_wr))
+    }
+    /// Wraps a QP owned elsewhere without taking ownership; dropping the result
+    /// does not destroy the QP.
+    ///
+    /// # Safety
+    /// `qp` must point to a live QP created by libibverbs, and it must stay alive and
+    /// not be destroyed for as long as the returned value (or anything built from
+    /// it) is in use.
+    pub unsafe fn from_raw_borrowed(qp: NonNull<ffi::ibv_qp>) -> ManuallyDrop<IbvQp> {
+        let mut qp = IbvQp {
+            ibv_qp: qp,
+            max_send_sge: u32::MAX,
+            max_recv_sge: u32::MAX,
+            max_inline_data: u32::MAX,
+        };
+        // Unchecked if the caps can't be read back.
+        if let Ok(cap) = qp.query_cap() {
+            qp.max_send_sge = cap.max_send_sge;
+            qp.max_recv_sge = cap.max_recv_sge;
+            qp.max_inline_data = cap.max_inline_data;
+        }
+        ManuallyDrop::new(qp)
+    }
+}
+impl Drop for IbvQp {
+    fn drop(&mut self) {
+        let ret = unsafe { ffi::ibv_destroy_qp(self.ibv_qp.as_ptr()) };
+        if ret != 0 {
+            panic!(
+                "ibv_destroy_qp(). errno: {}",
+                IOError::from_raw_os_error(ret)
+            );
+        }
+    }
+}
+unsafe impl Send for IbvQp {}
+unsafe impl Sync for IbvQp {}
+
+// A QP that may be posted to from several threads. `IbvQp` is Sync for queries and
+// modifies, but providers don't lock the work queues on post: concurrent posts to
+// the same queue race on the WQE index and the doorbell. Here each queue has its
+// own lock, so sends and receives still go in parallel.
+pub struct SyncQp {
+    qp: IbvQp,
+    send_lock: Mutex<()>,
+    recv_lock: Mutex<()>,
+}
+
+impl SyncQp {
+    pub fn new(qp: IbvQp) -> Self {
+        Self {
+            qp,
+            send_lock: Mutex::new(()),
+            recv_lock: Mutex::new(()),
+        }
+    }
+    pub fn post_send(
+        &self,
+        wr: &IbvSendWr,
+        bad_wr: *const *const IbvSendWr,
+    ) -> Result<(), IOError> {
+        let _guard = self.send_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.qp.post_send(wr, bad_wr)
+    }
+    pub fn post(&self, wr: &PostedWr) -> Result<(), IOError> {
+        let _guard = self.send_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.qp.post(wr)
+    }
+    pub fn post_send_chain(&self, ops: &[PostOp]) -> Result<(), IOError> {
+        let _guard = self.send_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.qp.post_send_chain(ops)
+    }
+    pub fn post_recv(
+        &self,
+        wr: &IbvRecvWr,
+        bad_wr: *const *const IbvRecvWr,
+    ) -> Result<(), IOError> {
+        let _guard = self.recv_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.qp.post_recv(wr, bad_wr)
+    }
+    pub fn post_recv_wr(&self, wr: &PostedRecvWr) -> Result<(), IOError> {
+        let _guard = self.recv_lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.qp.post_recv_wr(wr)
+    }
+    // The wrapped QP, for queries and state changes. Don't post to it directly.
+    #[inline(always)]
+    pub fn qp(&self) -> &IbvQp {
+        &self.qp
+    }
+    pub fn into_inner(self) -> IbvQp {
+        self.qp
+    }
+}
+
+// A send WR together with the SGEs it points at, so the gather list can't be
+// freed while the WR is still in use.
+pub struct PostedWr {
+    wr: IbvSendWr,
+    sges: Vec<IbvSge>,
+}
+
+impl PostedWr {
+    fn with_opcode(wr_id: u64, opcode: ffi::ibv_wr_opcode::Type, sges: Vec<IbvSge>) -> Self {
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        wr.wr_id = wr_id;
+        wr.opcode = opcode;
+        wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        Self { wr, sges }
+    }
+    pub fn send(wr_id: u64, sges: Vec<IbvSge>) -> Self {
+        Self::with_opcode(wr_id, ffi::ibv_wr_opcode::IBV_WR_SEND, sges)
+    }
+    pub fn write(wr_id: u64, sges: Vec<IbvSge>, remote: &RemoteToken) -> Self {
+        let mut wr = Self::with_opcode(wr_id, ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE, sges);
+        wr.wr.wr.rdma.remote_addr = remote.addr;
+        wr.wr.wr.rdma.rkey = remote.rkey;
+        wr
+    }
+    pub fn read(wr_id: u64, sges: Vec<IbvSge>, remote: &RemoteToken) -> Self {
+        let mut wr = Self::with_opcode(wr_id, ffi::ibv_wr_opcode::IBV_WR_RDMA_READ, sges);
+        wr.wr.wr.rdma.remote_addr = remote.addr;
+        wr.wr.wr.rdma.rkey = remote.rkey;
+        wr
+    }
+    // Defaults to IBV_SEND_SIGNALED.
+    pub fn flags(mut self, flags: ffi::ibv_send_flags) -> Self {
+        self.wr.send_flags = flags.0;
+        self
+    }
+    // Turns SEND into SEND_WITH_IMM and RDMA_WRITE into RDMA_WRITE_WITH_IMM.
+    // Panics on any other opcode, none of which carries immediate data.
+    pub fn imm(mut self, imm_data: u32) -> Self {
+        use ffi::ibv_wr_opcode as op;
+        self.wr.opcode = match self.wr.opcode {
+            op::IBV_WR_SEND | op::IBV_WR_SEND_WITH_IMM => op::IBV_WR_SEND_WITH_IMM,
+            op::IBV_WR_RDMA_WRITE | op::IBV_WR_RDMA_WRITE_WITH_IMM => {
+                op::IBV_WR_RDMA_WRITE_WITH_IMM
+            }
+            other => panic!(
+                "PostedWr::imm on opcode {}: only SEND and RDMA_WRITE carry immediate data",
+                other
+            ),
+        };
+        self.wr.__bindgen_anon_1.imm_data = imm_data.to_be();
+        self
+    }
+    #[inline(always)]
+    pub fn send_flags(&self) -> ffi::ibv_send_flags {
+        ffi::ibv_send_flags(self.wr.send_flags)
+    }
+    #[inline(always)]
+    pub fn wr_id(&self) -> u64 {
+        self.wr.wr_id
+    }
+    #[inline(always)]
+    pub fn set_wr_id(&mut self, wr_id: u64) {
+        self.wr.wr_id = wr_id;
+    }
+    #[inline(always)]
+    pub fn sges(&self) -> &[IbvSge] {
+        &self.sges
+    }
+    #[inline(always)]
+    pub fn sges_mut(&mut self) -> &mut Vec<IbvSge> {
+        &mut self.sges
+    }
+    // The WR with sg_list pointing at the current SGEs; only valid while `self` is
+    // borrowed.
+    fn as_raw(&self) -> IbvSendWr {
+        let mut wr = self.wr;
+        // An empty Vec's pointer is dangling; zero-length WRs get a null list.
+        if !self.sges.is_empty() {
+            wr.sg_list = self.sges.as_ptr() as *mut _;
+        }
+        wr.num_sge = self.sges.len() as i32;
+        wr
+    }
+}
+unsafe impl Send for PostedWr {}
+unsafe impl Sync for PostedWr {}
+
+// A send WR built and checked once by `IbvQp::send_template`, for fixed-format
+// messages posted at high rate. The SGEs are boxed so sg_list stays valid when the
+// template moves.
+pub struct SendTemplate {
+    wr: IbvSendWr,
+    sges: Box<[IbvSge]>,
+    qp_num: u32,
+}
+
+impl SendTemplate {
+    #[inline(always)]
+    pub fn sges(&self) -> &[IbvSge] {
+        &self.sges
+    }
+    // Repoints SGE `idx` at `addr`, e.g. the next slot of a ring within the same MR.
+    // Its length and lkey stay as built.
+    #[inline(always)]
+    pub fn set_sge_addr(&mut self, idx: usize, addr: u64) {
+        self.sges[idx].addr = addr;
+    }
+    #[inline(always)]
+    pub fn set_imm_data(&mut self, imm_data: u32) {
+        self.wr.set_imm_data(imm_data);
+    }
+}
+unsafe impl Send for SendTemplate {}
+unsafe impl Sync for SendTemplate {}
+
+// One operation of a `post_send_chain`.
+#[derive(Clone, Copy, Debug)]
+pub enum PostOp<'a> {
+    Send {
+        wr_id: u64,
+        sges: &'a [IbvSge],
+        imm: Option<u32>,
+    },
+    Write {
+        wr_id: u64,
+        sges: &'a [IbvSge],
+        remote: RemoteToken,
+        imm: Option<u32>,
+    },
+    Read {
+        wr_id: u64,
+        sges: &'a [IbvSge],
+        remote: RemoteToken,
+    },
+    // The original remote value is written to `sge`, which must be 8 bytes, and
+    // `remote.addr` must be 8-byte aligned; the same holds for FetchAdd. Unless the
+    // target device's AtomicCap is Glob, the target's CPU must not touch the word
+    // while atomics on it are in flight.
+    CmpSwap {
+        wr_id: u64,
+        sge: &'a IbvSge,
+        remote: RemoteToken,
+        compare: u64,
+        swap: u64,
+    },
+    FetchAdd {
+        wr_id: u64,
+        sge: &'a IbvSge,
+        remote: RemoteToken,
+        add: u64,
+    },
+}
+
+impl PostOp<'_> {
+    fn sges(&self) -> &[IbvSge] {
+        match self {
+            PostOp::Send { sges, .. } | PostOp::Write { sges, .. } | PostOp::Read { sges, .. } => {
+                sges
+            }
+            PostOp::CmpSwap { sge, .. } | PostOp::FetchAdd { sge, .. } => {
+                std::slice::from_ref(*sge)
+            }
+        }
+    }
+    // The WR without its gather list and chain link, which the caller fills in.
+    fn to_wr(self) -> Result<IbvSendWr, IOError> {
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        match self {
+            PostOp::Send { wr_id, imm, .. } => {
+                wr.wr_id = wr_id;
+                wr.opcode = match imm {
+                    Some(imm) => {
+                        wr.__bindgen_anon_1.imm_data = imm.to_be();
+                        ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM
+                    }
+                    None => ffi::ibv_wr_opcode::IBV_WR_SEND,
+                };
+            }
+            PostOp::Write {
+                wr_id, remote, imm, ..
+            } => {
+                wr.wr_id = wr_id;
+                wr.opcode = match imm {
+                    Some(imm) => {
+                        wr.__bindgen_anon_1.imm_data = imm.to_be();
+                        ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE_WITH_IMM
+                    }
+                    None => ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE,
+                };
+                wr.wr.rdma.remote_addr = remote.addr;
+                wr.wr.rdma.rkey = remote.rkey;
+            }
+            PostOp::Read { wr_id, remote, .. } => {
+                wr.wr_id = wr_id;
+                wr.opcode = ffi::ibv_wr_opcode::IBV_WR_RDMA_READ;
+                wr.wr.rdma.remote_addr = remote.addr;
+                wr.wr.rdma.rkey = remote.rkey;
+            }
+            PostOp::CmpSwap {
+                wr_id,
+                sge,
+                remote,
+                compare,
+                swap,
+            } => {
+                check_atomic_op(sge, &remote)?;
+                wr.wr_id = wr_id;
+                wr.opcode = ffi::ibv_wr_opcode::IBV_WR_ATOMIC_CMP_AND_SWP;
+                wr.wr.atomic.remote_addr = remote.addr;
+                wr.wr.atomic.rkey = remote.rkey;
+                wr.wr.atomic.compare_add = compare;
+                wr.wr.atomic.swap = swap;
+            }
+            PostOp::FetchAdd {
+                wr_id,
+                sge,
+                remote,
+                add,
+            } => {
+                check_atomic_op(sge, &remote)?;
+                wr.wr_id = wr_id;
+                wr.opcode = ffi::ibv_wr_opcode::IBV_WR_ATOMIC_FETCH_AND_ADD;
+                wr.wr.atomic.remote_addr = remote.addr;
+                wr.wr.atomic.rkey = remote.rkey;
+                wr.wr.atomic.compare_add = add;
+            }
+        }
+        Ok(wr)
+    }
+}
+
+fn check_atomic_op(sge: &IbvSge, remote: &RemoteToken) -> Result<(), IOError> {
+    if sge.length != 8 || remote.addr & 7 != 0 {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "atomics need an 8-byte local buffer and an 8-byte aligned remote address, \
+                 got {} bytes at {:#x}",
+                sge.length, remote.addr
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub struct PostedRecvWr {
+    wr_id: u64,
+    sges: Vec<IbvSge>,
+}
+
+impl PostedRecvWr {
+    pub fn new(wr_id: u64, sges: Vec<IbvSge>) -> Self {
+        Self { wr_id, sges }
+    }
+    #[inline(always)]
+    pub fn wr_id(&self) -> u64 {
+        self.wr_id
+    }
+    #[inline(always)]
+    pub fn set_wr_id(&mut self, wr_id: u64) {
+        self.wr_id = wr_id;
+    }
+    #[inline(always)]
+    pub fn sges(&self) -> &[IbvSge] {
+        &self.sges
+    }
+    fn as_raw(&self) -> IbvRecvWr {
+        let mut wr = unsafe { std::mem::zeroed::<IbvRecvWr>() };
+        wr.wr_id = self.wr_id;
+        wr.sg_list = self.sges.as_ptr() as *mut _;
+        wr.num_sge = self.sges.len() as i32;
+        wr
+    }
+}
+
+fn device_attr_of(pd: &IbvPd) -> Result<IbvDeviceAttr, IOError> {
+    let mut device_attr = unsafe { std::mem::zeroed::<IbvDeviceAttr>() };
+    let ret = unsafe { ffi::ibv_query_device((*pd.as_mut_ptr()).context, &mut device_attr) };
+    if ret != 0 {
+        return Err(verbs_error("ibv_query_device", IOError::last_os_error()));
+    }
+    Ok(device_attr)
+}
+
+// WR depths `IbvQpBuilder::clamp_wr` lowered to the device maximum.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WrClamp {
+    pub requested_send_wr: u32,
+    pub requested_recv_wr: u32,
+    pub max_qp_wr: u32,
+}
+
+impl std::fmt::Display for WrClamp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "requested max_send_wr {} / max_recv_wr {}, clamped to device max_qp_wr {}",
+            self.requested_send_wr, self.requested_recv_wr, self.max_qp_wr
+        )
+    }
+}
+
+pub struct IbvQpBuilder {
+    attr: IbvQpInitAttr,
+    // Caps set explicitly, which `device_defaults` leaves alone.
+    send_sge_set: bool,
+    recv_sge_set: bool,
+    inline_set: bool,
+    numa: NumaPolicy,
+}
+
+// Upper bounds for `IbvQpBuilder::device_defaults`: devices advertise up to 30 SGEs
+// and ~1KB of inline data, but large values inflate every WQE.
+const DEFAULT_MAX_SGE: u32 = 4;
+const DEFAULT_MAX_INLINE: u32 = 256;
+
+// The RC ACK timeout is 4.096us * 2^timeout for timeout in 1..=31.
+const TIMEOUT_UNIT_NS: f64 = 4096.0;
+const MAX_TIMEOUT_EXP: u8 = 31;
+
+// Nearest timeout exponent to `d`, clamped to 1..=31 (8.192us to ~2.4h).
+pub fn timeout_from_duration(d: Duration) -> u8 {
+    let exp = (d.as_nanos() as f64 / TIMEOUT_UNIT_NS).log2().round();
+    exp.clamp(1.0, MAX_TIMEOUT_EXP as f64) as u8
+}
+
+pub fn timeout_to_duration(timeout: u8) -> Option<Duration> {
+    if timeout == 0 {
+        return None;
+    }
+    Some(Duration::from_nanos(
+        (TIMEOUT_UNIT_NS as u64) << timeout.min(MAX_TIMEOUT_EXP),
+    ))
+}
+
+// Like `timeout_from_duration`, but rejects durations more than a factor of two
+// outside the representable range instead of silently clamping them.
+fn checked_timeout_exp(d: Duration) -> Result<u8, IOError> {
+    let min = timeout_to_duration(1).unwrap();
+    let max = timeout_to_duration(MAX_TIMEOUT_EXP).unwrap();
+    if d < min / 2 || d > max * 2 {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "ACK timeout {:?} outside the supported range {:?}..={:?}",
+                d, min, max
+            ),
+        ));
+    }
+    Ok(timeout_from_duration(d))
+}
+
+// RNR NAK delays in microseconds, indexed by the 5-bit min_rnr_timer code. Code 0
+// is the longest delay, not the shortest.
+const RNR_TIMER_US: [u64; 32] = [
+    655_360, 10, 20, 30, 40, 60, 80, 120, 160, 240, 320, 480, 640, 960, 1_280, 1_920, 2_560, 3_840,
+    5_120, 7_680, 10_240, 15_360, 20_480, 30_720, 40_960, 61_440, 81_920, 122_880, 163_840,
+    245_760, 327_680, 491_520,
+];
+
+// Code of the table delay nearest to `d`, so durations outside 10us..=655.36ms
+// clamp to the shortest or longest.
+pub fn min_rnr_timer_from_duration(d: Duration) -> u8 {
+    let us = d.as_micros().min(u64::MAX as u128) as u64;
+    (0..RNR_TIMER_US.len())
+        .min_by_key(|&code| RNR_TIMER_US[code].abs_diff(us))
+        .unwrap_or(0) as u8
+}
+
+pub fn min_rnr_timer_to_duration(code: u8) -> Option<Duration> {
+    RNR_TIMER_US
+        .get(code as usize)
+        .map(|&us| Duration::from_micros(us))
+}
+
+// A CQ shared by `num_qps` QPs must hold every completion they can have
+// outstanding at once, i.e. all their send and receive WRs, or it overruns.
+pub fn recommended_cq_size(max_send_wr: u32, max_recv_wr: u32, num_qps: u32) -> i32 {
+    let cqe = (max_send_wr as u64 + max_recv_wr as u64) * num_qps as u64;
+    cqe.min(i32::MAX as u64) as i32
+}
+
+impl IbvQpBuilder {
+    pub fn new(send_cq: &IbvCq, recv_cq: &IbvCq) -> Self {
+        let mut builder = Self::without_cq();
+        builder.attr.set_send_cq(send_cq);
+        builder.attr.set_recv_cq(recv_cq);
+        builder
+    }
+    // A builder with no CQs yet; finish it with `build_with_cq`.
+    pub fn without_cq() -> Self {
+        let mut attr = unsafe { std::mem::zeroed::<IbvQpInitAttr>() };
+        attr.qp_type = ffi::ibv_qp_type::IBV_QPT_RC;
+        attr.cap.max_send_wr = 128;
+        attr.cap.max_recv_wr = 128;
+        attr.cap.max_send_sge = 1;
+        attr.cap.max_recv_sge = 1;
+        Self {
+            attr,
+            send_sge_set: false,
+            recv_sge_set: false,
+            inline_set: false,
+            numa: NumaPolicy::Any,
+        }
+    }
+    // Where `build_with_cq` puts the CQ's completion vector and `alloc_buffer` its
+    // memory.
+    pub fn numa_policy(mut self, numa: NumaPolicy) -> Self {
+        self.numa = numa;
+        self
+    }
+    // A buffer for this QP's data, placed according to the NUMA policy; register
+    // it with `IbvMr::new`.
+    pub fn alloc_buffer(
+        &self,
+        context: &IbvContext,
+        len: usize,
+    ) -> Result<numa::NumaBuffer, IOError> {
+        self.numa.alloc(context, len)
+    }
+    pub fn qp_type(mut self, qp_type: IbvQpType) -> Self {
+        self.attr.qp_type = qp_type.into();
+        self
+    }
+    // Receives come from `srq`; the QP's own RQ caps are then ignored. Works for RC
+    // and UD alike, e.g. many UD multicast QPs sharing one pool.
+    pub fn srq(mut self, srq: &IbvSrq) -> Self {
+        self.attr.srq = srq.as_mut_ptr();
+        self
+    }
+    pub fn sq_sig_all(mut self, sq_sig_all: bool) -> Self {
+        self.attr.sq_sig_all = sq_sig_all as i32;
+        self
+    }
+    pub fn max_send_wr(mut self, max_send_wr: u32) -> Self {
+        self.attr.cap.max_send_wr = max_send_wr;
+        self
+    }
+    pub fn max_recv_wr(mut self, max_recv_wr: u32) -> Self {
+        self.attr.cap.max_recv_wr = max_recv_wr;
+        self
+    }
+    pub fn max_send_sge(mut self, max_send_sge: u32) -> Self {
+        self.attr.cap.max_send_sge = max_send_sge;
+        self.send_sge_set = true;
+        self
+    }
+    pub fn max_recv_sge(mut self, max_recv_sge: u32) -> Self {
+        self.attr.cap.max_recv_sge = max_recv_sge;
+        self.recv_sge_set = true;
+        self
+    }
+    pub fn max_inline_data(mut self, max_inline_data: u32) -> Self {
+        self.attr.cap.max_inline_data = max_inline_data;
+        self.inline_set = true;
+        self
+    }
+    // Sets max_inline_data to the largest value the device accepts for this QP
+    // shape and returns it; see `probe_max_inline`.
+    pub fn auto_inline(mut self, pd: &IbvPd) -> Result<(Self, u32), IOError> {
+        let max_inline_data = probe_max_inline(pd, &self.attr)?;
+        self.attr.cap.max_inline_data = max_inline_data;
+        self.inline_set = true;
+        Ok((self, max_inline_data))
+    }
+    // Lowers max_send_wr/max_recv_wr to the device's max_qp_wr instead of letting
+    // `build` reject them; the returned WrClamp says what was asked for, if
+    // anything had to change.
+    pub fn clamp_wr(mut self, pd: &IbvPd) -> Result<(Self, Option<WrClamp>), IOError> {
+        let max_qp_wr = device_attr_of(pd)?.max_qp_wr.max(0) as u32;
+        let cap = &mut self.attr.cap;
+        if cap.max_send_wr <= max_qp_wr && cap.max_recv_wr <= max_qp_wr {
+            return Ok((self, None));
+        }
+        let clamp = WrClamp {
+            requested_send_wr: cap.max_send_wr,
+            requested_recv_wr: cap.max_recv_wr,
+            max_qp_wr,
+        };
+        cap.max_send_wr = cap.max_send_wr.min(max_qp_wr);
+        cap.max_recv_wr = cap.max_recv_wr.min(max_qp_wr);
+        Ok((self, Some(clamp)))
+    }
+    // Fills in the SGE and inline caps not set explicitly from what the device
+    // supports, clamped to DEFAULT_MAX_SGE / DEFAULT_MAX_INLINE, and returns the
+    // resulting caps.
+    pub fn device_defaults(
+        mut self,
+        context: &IbvContext,
+        pd: &IbvPd,
+    ) -> Result<(Self, ffi::ibv_qp_cap), IOError> {
+        let max_sge = (context.query_device()?.max_sge.max(1) as u32).min(DEFAULT_MAX_SGE);
+        if !self.send_sge_set {
+            self.attr.cap.max_send_sge = max_sge;
+        }
+        if !self.recv_sge_set {
+            self.attr.cap.max_recv_sge = max_sge;
+        }
+        if !self.inline_set {
+            self.attr.cap.max_inline_data =
+                probe_max_inline(pd, &self.attr)?.min(DEFAULT_MAX_INLINE);
+        }
+        let cap = self.attr.cap;
+        Ok((self, cap))
+    }
+    // Checks the configuration against the QP type and the device's limits, so a
+    // bad combination fails with a description instead of a bare EINVAL from
+    // ibv_create_qp. `build` and `build_with_cq` run it themselves.
+    pub fn validate(&self, pd: &IbvPd) -> Result<(), IOError> {
+        let invalid = |msg: String| Err(IOError::new(ErrorKind::InvalidInput, msg));
+        let qp_type = self.attr.qp_type;
+        if qp_type == ffi::ibv_qp_type::IBV_QPT_XRC_SEND
+            || qp_type == ffi::ibv_qp_type::IBV_QPT_XRC_RECV
+        {
+            return invalid(
+                "XRC QPs need an XRC domain, create them with IbvQp::with_attr_ex".into(),
+            );
+        }
+        if self.attr.send_cq.is_null() || self.attr.recv_cq.is_null() {
+            return invalid("no CQs set, use IbvQpBuilder::new or build_with_cq".into());
+        }
+        let device_attr = device_attr_of(pd)?;
+        let max_qp_wr = device_attr.max_qp_wr.max(0) as u32;
+        let max_sge = device_attr.max_sge.max(0) as u32;
+        let cap = &self.attr.cap;
+        if cap.max_send_wr > max_qp_wr {
+            return invalid(format!(
+                "max_send_wr {} exceeds device limit {} (see IbvQpBuilder::clamp_wr)",
+                cap.max_send_wr, max_qp_wr
+            ));
+        }
+        if cap.max_send_sge > max_sge {
+            return invalid(format!(
+                "max_send_sge {} exceeds device limit {}",
+                cap.max_send_sge, max_sge
+            ));
+        }
+        // With an SRQ the QP has no receive queue of its own and these are ignored.
+        if self.attr.srq.is_null() {
+            if cap.max_recv_wr > max_qp_wr {
+                return invalid(format!(
+                    "max_recv_wr {} exceeds device limit {} (see IbvQpBuilder::clamp_wr)",
+                    cap.max_recv_wr, max_qp_wr
+                ));
+            }
+            if cap.max_recv_sge > max_sge {
+                return invalid(format!(
+                    "max_recv_sge {} exceeds device limit {}",
+                    cap.max_recv_sge, max_sge
+                ));
+            }
+        }
+        if cap.max_inline_data > 0 {
+            let max_inline_data = probe_max_inline(pd, &self.attr)?;
+            if cap.max_inline_data > max_inline_data {
+                return invalid(format!(
+                    "max_inline_data {} exceeds device limit {} for this QP type and send queue",
+                    cap.max_inline_data, max_inline_data
+                ));
+            }
+        }
+        Ok(())
+    }
+    pub fn build(mut self, pd: &IbvPd) -> Result<IbvQp, IOError> {
+        self.validate(pd)?;
+        IbvQp::with_attr(pd, &mut self.attr)
+    }
+    // Creates one CQ of `recommended_cq_size` for both queues and the QP on it,
+    // replacing any CQs set before. The QP comes first in the tuple so that it
+    // drops before its CQ.
+    pub fn build_with_cq(
+        mut self,
+        context: &IbvContext,
+        pd: &IbvPd,
+    ) -> Result<(IbvQp, IbvCq), IOError> {
+        let cqe = recommended_cq_size(self.attr.cap.max_send_wr, self.attr.cap.max_recv_wr, 1);
+        let max_cqe = context.query_device()?.max_cqe;
+        if cqe > max_cqe {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "the QP needs a CQ of {} entries, the device allows {}",
+                    cqe, max_cqe
+                ),
+            ));
+        }
+        let comp_vector = self.numa.comp_vector(context);
+        let cq = IbvCq::new::<()>(context, cqe, None, None, comp_vector)?;
+        self.attr.set_send_cq(&cq);
+        self.attr.set_recv_cq(&cq);
+        self.validate(pd)?;
+        let qp = IbvQp::with_attr(pd, &mut self.attr)?;
+        Ok((qp, cq))
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct IbvConnectionParams {
+    pub qp_type: IbvQpType,
+    pub max_send_wr: u32,
+    pub max_recv_wr: u32,
+    pub max_send_sge: u32,
+    pub max_recv_sge: u32,
+    pub max_inline_data: u32,
+}
+
+impl Default for IbvConnectionParams {
+    fn default() -> Self {
+        Self {
+            qp_type: IbvQpType::Rc,
+            max_send_wr: 128,
+            max_recv_wr: 128,
+            max_send_sge: 1,
+            max_recv_sge: 1,
+            max_inline_data: 0,
+        }
+    }
+}
+
+// A QP together with a CQ sized for it: one entry per send and receive WR the QP
+// was actually granted, which can be more than requested.
+pub struct IbvConnection {
+    // The QP goes first so it is destroyed before its CQ.
+    qp: IbvQp,
+    cq: IbvCq,
+}
+
+impl IbvConnection {
+    // Creates the CQ and the QP on it and moves the QP to INIT on `port_num`.
+    pub fn new(pd: &IbvPd, port_num: u8, params: IbvConnectionParams) -> Result<Self, IOError> {
+        // Borrowed from the PD; closing it is not ours to do.
+        let context = ManuallyDrop::new(IbvContext {
+            ibv_context: NonNull::new(unsafe { (*pd.as_mut_ptr()).context })
+                .ok_or_else(|| IOError::new(ErrorKind::InvalidInput, "PD has no context"))?,
+        });
+        let (qp, cq) = IbvQpBuilder::without_cq()
+            .qp_type(params.qp_type)
+            .max_send_wr(params.max_send_wr)
+            .max_recv_wr(params.max_recv_wr)
+            .max_send_sge(params.max_send_sge)
+            .max_recv_sge(params.max_recv_sge)
+            .max_inline_data(params.max_inline_data)
+            .build_with_cq(&context, pd)?;
+        let cap = qp.query_cap()?;
+        let needed = recommended_cq_size(cap.max_send_wr, cap.max_recv_wr, 1);
+        if needed > cq.cqe() {
+            cq.resize(needed)?;
+        }
+        qp.modify_reset2init(port_num)?;
+        Ok(Self { qp, cq })
+    }
+    #[inline(always)]
+    pub fn qp(&self) -> &IbvQp {
+        &self.qp
+    }
+    #[inline(always)]
+    pub fn cq(&self) -> &IbvCq {
+        &self.cq
+    }
+    // The QP first, so that dropping the tuple in order still destroys it before
+    // the CQ.
+    pub fn into_parts(self) -> (IbvQp, IbvCq) {
+        (self.qp, self.cq)
+    }
+}
+
+// Upper bound for the inline probe; no current provider goes beyond this.
+const MAX_INLINE_PROBE: u32 = 4096;
+
+// Finds the largest max_inline_data a QP shaped like `attr` can be created with,
+// by binary search over throwaway QPs. The inline limit depends on the WQE size,
+// so results are cached per device, QP type and send queue shape.
+pub fn probe_max_inline(pd: &IbvPd, attr: &IbvQpInitAttr) -> Result<u32, IOError> {
+    type Key = (String, u32, u32, u32);
+    static CACHE: OnceLock<Mutex<HashMap<Key, u32>>> = OnceLock::new();
+
+    let device = unsafe {
+        CStr::from_ptr(ffi::ibv_get_device_name(
+            (*(*pd.as_mut_ptr()).context).device,
+        ))
+    }
+    .to_string_lossy()
+    .into_owned();
+    let key = (
+        device,
+        attr.qp_type,
+        attr.cap.max_send_wr,
+        attr.cap.max_send_sge,
+    );
+    let cache = CACHE.get_or_init(Default::default);
+    if let Some(&max_inline_data) = cache.lock().unwrap().get(&key) {
+        return Ok(max_inline_data);
+    }
+
+    let fits = |max_inline_data: u32| -> Result<bool, IOError> {
+        let mut attr = *attr;
+        attr.cap.max_inline_data = max_inline_data;
+        match IbvQp::with_attr(pd, &mut attr) {
+            Ok(_) => Ok(true),
+            Err(err) if matches!(verbs_errno(&err), Some(libc::EINVAL | libc::ENOMEM)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    };
+    if !fits(0)? {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            "QP creation fails even without inline data",
+        ));
+    }
+    let (mut lo, mut hi) = (0, MAX_INLINE_PROBE);
+    while lo < hi {
+        let mid = lo + (hi - lo).div_ceil(2);
+        if fits(mid)? {
+            lo = mid;
+        } else {
+            hi = mid - 1;
+        }
+    }
+    cache.lock().unwrap().insert(key, lo);
+    Ok(lo)
 }
-impl IbvQp {
-    pub fn new(
-        pd: &IbvPd,
-        send_cq: &IbvCq,
-        recv_cq: &IbvCq,
-        sq_sig_all: i32,
-        max_send_wr: u32,
-        max_recv_wr: u32,
-        max_send_sge: u32,
-        max_recv_sge: u32,
-        max_inline_data: u32,
-    ) -> Result<Self, IOError> {
-        let mut qp_init_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_init_attr>() };
-        qp_init_attr.qp_type = ffi::ibv_qp_type::IBV_QPT_RC;
-        qp_init_attr.sq_sig_all = sq_sig_all; // set to 0 to avoid CQE for every SR
-        qp_init_attr.send_cq = send_cq.ibv_cq.as_ptr();
-        qp_init_attr.recv_cq = recv_cq.ibv_cq.as_ptr();
-        qp_init_attr.cap.max_send_wr = max_send_wr;
-        qp_init_attr.cap.max_recv_wr = max_recv_wr;
-        qp_init_attr.cap.max_send_sge = max_send_sge;
-        qp_init_attr.cap.max_recv_sge = max_recv_sge;
-        qp_init_attr.cap.max_inline_data = max_inline_data;
-        qp_init_attr.srq = std::ptr::null_mut();
-        let ibv_qp = unsafe { ffi::ibv_create_qp(pd.ibv_pd.as_ptr(), &mut qp_init_attr as *mut _) };
+
+// A QP created through ibv_create_qp_ex, posted to with the ibv_wr_* API:
+// wr_start(), set_wr_id()/set_wr_flags(), one op plus its sge, then wr_complete().
+pub struct IbvQpEx {
+    qp: IbvQp,
+    qp_ex: NonNull<ffi::ibv_qp_ex>,
+    send_ops_flags: u64,
+}
+
+impl IbvQpEx {
+    pub fn with_attr(context: &IbvContext, attr: &mut IbvQpInitAttrEx) -> Result<Self, IOError> {
+        let create_qp_ex = verbs_ctx_op!(context.as_mut_ptr(), create_qp_ex)
+            .ok_or_else(|| unsupported_verb("ibv_create_qp_ex"))?;
+        attr.comp_mask |= ffi::ibv_qp_init_attr_mask::IBV_QP_INIT_ATTR_SEND_OPS_FLAGS.0;
+        let ibv_qp = unsafe { create_qp_ex(context.as_mut_ptr(), attr as *mut _) };
         if ibv_qp.is_null() {
             return Err(IOError::last_os_error());
         }
+        let qp = unsafe {
+            IbvQp {
+                ibv_qp: NonNull::new_unchecked(ibv_qp),
+                max_send_sge: attr.cap.max_send_sge,
+                max_recv_sge: attr.cap.max_recv_sge,
+                max_inline_data: attr.cap.max_inline_data,
+            }
+        };
+        let qp_ex = unsafe { ffi::ibv_qp_to_qp_ex(ibv_qp) };
+        if qp_ex.is_null() {
+            return Err(IOError::last_os_error());
+        }
         unsafe {
             Ok(Self {
-                ibv_qp: NonNull::new_unchecked(ibv_qp),
+                qp,
+                qp_ex: NonNull::new_unchecked(qp_ex),
+                send_ops_flags: attr.send_ops_flags,
             })
         }
     }
-    pub fn with_attr(pd: &IbvPd, qp_init_attr: &mut IbvQpInitAttr) -> Result<Self, IOError> {
-        let ibv_qp = unsafe { ffi::ibv_create_qp(pd.ibv_pd.as_ptr(), qp_init_attr as *mut _) };
-        if ibv_qp.is_null() {
-            return Err(IOError::last_os_error());
+    #[inline(always)]
+    fn enabled(&self, op: ffi::ibv_qp_create_send_ops_flags) -> Result<(), IOError> {
+        if self.send_ops_flags & op.0 as u64 == 0 {
+            return Err(IOError::new(
+                ErrorKind::Unsupported,
+                "operation not enabled in send_ops_flags at QP creation",
+            ));
         }
+        Ok(())
+    }
+    #[inline(always)]
+    pub fn wr_start(&self) {
+        unsafe { ((*self.qp_ex.as_ptr()).wr_start.unwrap())(self.qp_ex.as_ptr()) }
+    }
+    #[inline(always)]
+    pub fn set_wr_id(&self, wr_id: u64) {
+        unsafe { (*self.qp_ex.as_ptr()).wr_id = wr_id }
+    }
+    #[inline(always)]
+    pub fn set_wr_flags(&self, wr_flags: u32) {
+        unsafe { (*self.qp_ex.as_ptr()).wr_flags = wr_flags }
+    }
+    #[inline(always)]
+    pub fn set_sge(&self, lkey: u32, addr: u64, length: u32) {
         unsafe {
-            Ok(Self {
-                ibv_qp: NonNull::new_unchecked(ibv_qp),
-            })
+            ((*self.qp_ex.as_ptr()).wr_set_sge.unwrap())(self.qp_ex.as_ptr(), lkey, addr, length)
         }
     }
-    pub fn modify_reset2init(&self, port_num: u8) -> Result<(), IOError> {
-        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
-        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_INIT;
-        qp_attr.pkey_index = 0;
-        qp_attr.port_num = port_num;
-        qp_attr.qp_access_flags = ffi::ibv_access_flags::IBV_ACCESS_LOCAL_WRITE.0
-            | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_READ.0
-            | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_WRITE.0
-            | ffi::ibv_access_flags::IBV_ACCESS_REMOTE_ATOMIC.0;
-
-        let ret = unsafe {
-            ffi::ibv_modify_qp(
-                self.ibv_qp.as_ptr(),
-                &mut qp_attr as *mut _,
-                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_PKEY_INDEX.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_PORT.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_ACCESS_FLAGS.0) as i32,
+    // As with every RDMA atomic, CPU access to the target word is only safe on
+    // devices whose AtomicCap is Glob; see IbvContext::check_cpu_coherent_atomics.
+    // Core verbs only have full-width atomics: masked CAS/FAA exist solely as
+    // provider WQE opcodes (mlx5 MASKED_CS/MASKED_FA) that this crate doesn't bind.
+    pub fn atomic_cmp_swp(
+        &self,
+        remote_addr: u64,
+        rkey: u32,
+        compare: u64,
+        swap: u64,
+    ) -> Result<(), IOError> {
+        self.enabled(ffi::ibv_qp_create_send_ops_flags::IBV_QP_EX_WITH_ATOMIC_CMP_AND_SWP)?;
+        unsafe {
+            ((*self.qp_ex.as_ptr()).wr_atomic_cmp_swp.unwrap())(
+                self.qp_ex.as_ptr(),
+                rkey,
+                remote_addr,
+                compare,
+                swap,
             )
         };
-        if ret == -1 {
-            return Err(IOError::last_os_error());
-        }
         Ok(())
     }
-    pub fn modify_init2rtr(
+    pub fn atomic_fetch_add(&self, remote_addr: u64, rkey: u32, add: u64) -> Result<(), IOError> {
+        self.enabled(ffi::ibv_qp_create_send_ops_flags::IBV_QP_EX_WITH_ATOMIC_FETCH_AND_ADD)?;
+        unsafe {
+            ((*self.qp_ex.as_ptr()).wr_atomic_fetch_add.unwrap())(
+                self.qp_ex.as_ptr(),
+                rkey,
+                remote_addr,
+                add,
+            )
+        };
+        Ok(())
+    }
+    pub fn flush(
         &self,
-
-        sl: u8,
-        port_num: u8,
-        remote_qpn: u32,
-        remote_psn: u32,
-        remote_lid: u16,
+        remote_addr: u64,
+        rkey: u32,
+        len: usize,
+        placement_type: ffi::ibv_placement_type,
+        selectivity: ffi::ibv_selectivity_level,
     ) -> Result<(), IOError> {
-        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
-        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTR;
-        qp_attr.path_mtu = ffi::ibv_mtu::IBV_MTU_1024;
-        qp_attr.dest_qp_num = remote_qpn;
-        qp_attr.rq_psn = remote_psn;
-        qp_attr.max_dest_rd_atomic = 1;
-        qp_attr.min_rnr_timer = 12;
-        qp_attr.ah_attr.is_global = 0;
-        qp_attr.ah_attr.dlid = remote_lid;
-        qp_attr.ah_attr.sl = sl;
-        qp_attr.ah_attr.src_path_bits = 0;
-        qp_attr.ah_attr.port_num = port_num;
-        let ret = unsafe {
-            ffi::ibv_modify_qp(
-                self.ibv_qp.as_ptr(),
-                &mut qp_attr as *mut _,
-                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_AV.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_PATH_MTU.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_DEST_QPN.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_RQ_PSN.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_MAX_DEST_RD_ATOMIC.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_MIN_RNR_TIMER.0) as i32,
+        self.enabled(ffi::ibv_qp_create_send_ops_flags::IBV_QP_EX_WITH_FLUSH)?;
+        unsafe {
+            ((*self.qp_ex.as_ptr()).wr_flush.unwrap())(
+                self.qp_ex.as_ptr(),
+                rkey,
+                remote_addr,
+                len as u64,
+                placement_type as u8,
+                selectivity as u8,
             )
         };
-        if ret == -1 {
-            return Err(IOError::last_os_error());
+        Ok(())
+    }
+    // Posts a single signaled IBV_WR_FLUSH. Its completion means the preceding writes
+    // to `remote` (or its whole MR with IBV_FLUSH_MR) have
+    // reached the requested placement type on the target. FLUSH has no ibv_send_wr
+    // encoding, so it is only available on an extended QP created with
+    // IBV_QP_EX_WITH_FLUSH; see `IbvDeviceAttrEx::supports_flush`.
+    pub fn post_flush(
+        &self,
+        wr_id: u64,
+        remote: &RemoteToken,
+        placement_type: ffi::ibv_placement_type,
+        selectivity: ffi::ibv_selectivity_level,
+    ) -> Result<(), IOError> {
+        self.enabled(ffi::ibv_qp_create_send_ops_flags::IBV_QP_EX_WITH_FLUSH)?;
+        self.wr_start();
+        self.set_wr_id(wr_id);
+        self.set_wr_flags(ffi::ibv_send_flags::IBV_SEND_SIGNALED.0);
+        self.flush(
+            remote.addr,
+            remote.rkey,
+            remote.length as usize,
+            placement_type,
+            selectivity,
+        )?;
+        self.wr_complete()
+    }
+    pub fn wr_complete(&self) -> Result<(), IOError> {
+        let ret = unsafe { ((*self.qp_ex.as_ptr()).wr_complete.unwrap())(self.qp_ex.as_ptr()) };
+        if ret != 0 {
+            return Err(IOError::from_raw_os_error(ret));
         }
         Ok(())
     }
+    #[inline(always)]
+    pub fn wr_abort(&self) {
+        unsafe { ((*self.qp_ex.as_ptr()).wr_abort.unwrap())(self.qp_ex.as_ptr()) }
+    }
+    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_qp_ex {
+        self.qp_ex.as_ptr()
+    }
+}
 
-    pub fn modify_rtr2rts(&self, psn: u32) -> Result<(), IOError> {
-        let mut qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
-        qp_attr.qp_state = ffi::ibv_qp_state::IBV_QPS_RTS;
-        qp_attr.timeout = 14;
-        qp_attr.retry_cnt = 7;
-        qp_attr.rnr_retry = 7;
-        qp_attr.sq_psn = psn;
-        qp_attr.max_rd_atomic = 1;
-        let ret = unsafe {
-            ffi::ibv_modify_qp(
-                self.ibv_qp.as_ptr(),
-                &mut qp_attr as *mut _,
-                (ffi::ibv_qp_attr_mask::IBV_QP_STATE.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_TIMEOUT.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_RETRY_CNT.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_RNR_RETRY.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_SQ_PSN.0
-                    | ffi::ibv_qp_attr_mask::IBV_QP_MAX_QP_RD_ATOMIC.0) as i32,
-            )
-        };
-        if ret == -1 {
-            return Err(IOError::last_os_error());
+impl Deref for IbvQpEx {
+    type Target = IbvQp;
+    fn deref(&self) -> &IbvQp {
+        &self.qp
+    }
+}
+unsafe impl Send for IbvQpEx {}
+unsafe impl Sync for IbvQpEx {}
+
+// Keeps each send buffer (or an Arc to it) alive from post until its completion is
+// drained. Every tracked WR must be signaled, otherwise its entry is never released.
+pub struct OutstandingSends<T> {
+    inflight: HashMap<u64, T>,
+}
+
+impl<T> OutstandingSends<T> {
+    pub fn new() -> Self {
+        Self {
+            inflight: HashMap::new(),
+        }
+    }
+    pub fn post_send(&mut self, qp: &IbvQp, wr: &IbvSendWr, buf: T) -> Result<(), IOError> {
+        if self.inflight.contains_key(&wr.wr_id) {
+            return Err(IOError::new(
+                ErrorKind::AlreadyExists,
+                format!("wr_id {} is already in flight", wr.wr_id),
+            ));
         }
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        qp.post_send(wr, std::ptr::addr_of_mut!(bad_wr))?;
+        self.inflight.insert(wr.wr_id, buf);
         Ok(())
     }
+    // Hands the buffer back once the NIC is done with it.
+    pub fn complete(&mut self, wr_id: u64) -> Option<T> {
+        self.inflight.remove(&wr_id)
+    }
     #[inline(always)]
-    pub fn qpn(&self) -> u32 {
-        unsafe { self.ibv_qp.as_ref().qp_num }
+    pub fn len(&self) -> usize {
+        self.inflight.len()
     }
-    pub fn query(&self, attr_mask: u32) -> Result<(IbvQpAttr, IbvQpInitAttr), IOError> {
-        let mut ibv_qp_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_attr>() };
-        let mut ibv_qp_init_attr = unsafe { std::mem::zeroed::<ffi::ibv_qp_init_attr>() };
-        let ret = unsafe {
-            ffi::ibv_query_qp(
-                self.ibv_qp.as_ptr(),
-                &mut ibv_qp_attr as *mut _,
-                attr_mask as i32,
-                &mut ibv_qp_init_attr as *mut _,
-            )
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.inflight.is_empty()
+    }
+}
+
+impl<T> Default for OutstandingSends<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Opcodes are only valid on successful completions.
+fn is_write_imm_recv(wc: &IbvWc) -> bool {
+    wc.status == ffi::ibv_wc_status::IBV_WC_SUCCESS
+        && wc.opcode == ffi::ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM
+}
+
+// A fixed pool of equally sized receive slots carved out of one registered buffer.
+// The wr_id of each posted recv is its slot index, so a completion maps straight
+// back to the bytes it filled.
+//
+// At most `depth` slots are posted at a time; the rest wait in a free list. The
+// manager counts posted vs completed receives so it can warn (low-watermark
+// callback) or refill before the RQ runs dry and the peer starts getting RNR NAKs.
+//
+// On a UD QP each slot is preceded by UD_GRH_SIZE bytes for the GRH the HCA writes
+// in front of every datagram, so `slot_size` is always the payload capacity and
+// `data` returns the payload alone, whatever the QP type.
+pub struct RecvManager {
+    buf: Box<[u8]>,
+    mr: IbvMr,
+    slot_size: u32,
+    grh_size: u32,
+    depth: usize,
+    low_watermark: usize,
+    on_low_watermark: Option<Box<dyn Fn(usize) + Send + Sync>>,
+    auto_refill: bool,
+    state: Mutex<RecvState>,
+}
+
+#[derive(Default)]
+struct RecvState {
+    free: Vec<u64>,
+    posted: usize,
+    completed: u64,
+}
+
+impl RecvManager {
+    pub fn new(pd: &IbvPd, qp: &IbvQp, slots: usize, slot_size: u32) -> Result<Self, IOError> {
+        Self::with_depth(pd, qp, slots, slot_size, slots)
+    }
+    // Like `new`, but keeps only `depth` receives posted; the remaining slots are
+    // spares that let the RQ be refilled while the application still holds data.
+    pub fn with_depth(
+        pd: &IbvPd,
+        qp: &IbvQp,
+        slots: usize,
+        slot_size: u32,
+        depth: usize,
+    ) -> Result<Self, IOError> {
+        if slot_size == 0 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "RecvManager slot_size must be non-zero",
+            ));
+        }
+        let grh_size = match qp.qp_type() {
+            Some(IbvQpType::Ud) => UD_GRH_SIZE,
+            _ => 0,
         };
-        if ret != 0 {
-            return Err(IOError::last_os_error());
+        let buf = vec![0_u8; slots * (grh_size + slot_size) as usize].into_boxed_slice();
+        let mr = IbvMr::new(pd, &buf, IbvAccess::local())?;
+        let manager = RecvManager {
+            buf,
+            mr,
+            slot_size,
+            grh_size,
+            depth: depth.min(slots),
+            low_watermark: 0,
+            on_low_watermark: None,
+            auto_refill: false,
+            state: Mutex::new(RecvState {
+                free: (0..slots as u64).rev().collect(),
+                ..Default::default()
+            }),
+        };
+        manager.refill(qp)?;
+        Ok(manager)
+    }
+    #[inline(always)]
+    pub fn slots(&self) -> usize {
+        self.buf.len() / self.stride() as usize
+    }
+    // Bytes one slot takes in the buffer, GRH space included.
+    #[inline(always)]
+    fn stride(&self) -> u32 {
+        self.grh_size + self.slot_size
+    }
+    // `callback` gets the number of posted receives whenever a completion leaves
+    // fewer than `fraction * depth` of them posted.
+    pub fn set_low_watermark(
+        &mut self,
+        fraction: f64,
+        callback: impl Fn(usize) + Send + Sync + 'static,
+    ) {
+        self.low_watermark = (self.depth as f64 * fraction.clamp(0.0, 1.0)).ceil() as usize;
+        self.on_low_watermark = Some(Box::new(callback));
+    }
+    // In auto-refill mode released slots are batched in the free list and the RQ is
+    // topped back up to `depth` in one post once it falls below the low watermark;
+    // with no watermark set, every release refills. Otherwise each release reposts
+    // its slot immediately.
+    pub fn set_auto_refill(&mut self, auto_refill: bool) {
+        self.auto_refill = auto_refill;
+    }
+    pub fn posted(&self) -> usize {
+        self.state.lock().unwrap().posted
+    }
+    pub fn completed(&self) -> u64 {
+        self.state.lock().unwrap().completed
+    }
+    // The received payload for a recv completion: `byte_len` bytes, less the GRH on
+    // UD QPs, whose byte_len counts it. The slot stays owned by the application
+    // until `release` reposts it. Call it once per completion: it also accounts the
+    // receive as consumed.
+    //
+    // An RDMA write with immediate consumes a receive too, but its data went to the
+    // target MR and byte_len counts that; the slot is untouched and the payload
+    // returned here is empty. See `consume_write_imm`.
+    pub fn data(&self, wc: &IbvWc) -> Result<&[u8], IOError> {
+        let write_imm = is_write_imm_recv(wc);
+        // byte_len is undefined on failed completions.
+        let bad_len = wc.status == ffi::ibv_wc_status::IBV_WC_SUCCESS
+            && !write_imm
+            && (wc.byte_len > self.stride() || wc.byte_len < self.grh_size);
+        if wc.wr_id >= self.slots() as u64 || bad_len {
+            return Err(IOError::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "completion (wr_id {}, byte_len {}) does not belong to this RecvManager",
+                    wc.wr_id, wc.byte_len
+                ),
+            ));
         }
-        return Ok((ibv_qp_attr, ibv_qp_init_attr));
+        let posted = {
+            let mut state = self.state.lock().unwrap();
+            state.posted = state.posted.saturating_sub(1);
+            state.completed += 1;
+            state.posted
+        };
+        if posted < self.low_watermark {
+            if let Some(callback) = &self.on_low_watermark {
+                callback(posted);
+            }
+        }
+        if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS {
+            return Err(IOError::other(format!(
+                "recv wr_id {} failed: {}",
+                wc.wr_id,
+                wc.status_str()
+            )));
+        }
+        if write_imm {
+            return Ok(&[]);
+        }
+        let start = wc.wr_id as usize * self.stride() as usize;
+        Ok(&self.buf[start + self.grh_size as usize..start + wc.byte_len as usize])
     }
-    pub fn post_send(
-        &self,
-        wr: &IbvSendWr,
-        bad_wr: *const *const IbvSendWr,
-    ) -> Result<(), IOError> {
-        let ibv_post_send = unsafe { (*(*self.ibv_qp.as_ptr()).context).ops.post_send.unwrap() };
-        let ret = unsafe {
-            ibv_post_send(
-                self.ibv_qp.as_ptr(),
-                wr as *const _ as *mut _,
-                bad_wr as *mut _,
-            )
+    // Handles the receive consumed by a peer's RDMA write with immediate: accounts
+    // it, reposts the slot right away (nothing was written to it) and returns the
+    // host-order immediate and the number of bytes written to the target MR. Other
+    // completions are left alone and yield None, for `data` to handle.
+    pub fn consume_write_imm(&self, qp: &IbvQp, wc: &IbvWc) -> Result<Option<(u32, u32)>, IOError> {
+        if !is_write_imm_recv(wc) {
+            return Ok(None);
+        }
+        self.data(wc)?;
+        self.release(qp, wc.wr_id)?;
+        Ok(Some((wc.imm_data().unwrap_or_default(), wc.byte_len)))
+    }
+    // The GRH in front of a UD datagram, if the completion says one was received.
+    // Valid until the slot is released.
+    pub fn grh(&self, wc: &IbvWc) -> Option<IbvGrh> {
+        if self.grh_size == 0 || !wc.has_grh() || wc.wr_id >= self.slots() as u64 {
+            return None;
+        }
+        let start = wc.wr_id as usize * self.stride() as usize;
+        IbvGrh::from_recv_buf(&self.buf[start..start + self.grh_size as usize])
+    }
+    pub fn release(&self, qp: &IbvQp, wr_id: u64) -> Result<(), IOError> {
+        if wr_id >= self.slots() as u64 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("wr_id {} is not a slot of this RecvManager", wr_id),
+            ));
+        }
+        let refill = {
+            let mut state = self.state.lock().unwrap();
+            state.free.push(wr_id);
+            !self.auto_refill || self.low_watermark == 0 || state.posted < self.low_watermark
         };
-        if ret == -1 {
-            return Err(IOError::last_os_error());
+        if refill {
+            self.refill(qp)?;
         }
         Ok(())
     }
-    pub fn post_recv(
+    // Posts free slots, chained in a single post_recv, until `depth` are posted.
+    // Returns how many were posted.
+    pub fn refill(&self, qp: &IbvQp) -> Result<usize, IOError> {
+        let mut state = self.state.lock().unwrap();
+        let n = self
+            .depth
+            .saturating_sub(state.posted)
+            .min(state.free.len());
+        if n == 0 {
+            return Ok(0);
+        }
+        let keep = state.free.len() - n;
+        let slots = state.free.split_off(keep);
+        let mut sges = slots
+            .iter()
+            .map(|&slot| {
+                self.mr
+                    .sge(self.mr.addr() + slot * self.stride() as u64, self.stride())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut wrs = vec![unsafe { std::mem::zeroed::<IbvRecvWr>() }; n];
+        for i in (0..n).rev() {
+            wrs[i].wr_id = slots[i];
+            wrs[i].sg_list = &mut sges[i];
+            wrs[i].num_sge = 1;
+            if i + 1 < n {
+                wrs[i].next = &mut wrs[i + 1];
+            }
+        }
+        let mut bad_wr = std::ptr::null::<IbvRecvWr>();
+        if let Err(err) = qp.post_recv(&wrs[0], std::ptr::addr_of_mut!(bad_wr)) {
+            // Everything before bad_wr made it onto the RQ.
+            let ok = if bad_wr.is_null() {
+                0
+            } else {
+                unsafe { bad_wr.offset_from(wrs.as_ptr()) as usize }
+            };
+            state.posted += ok;
+            state.free.extend(&slots[ok..]);
+            return Err(err);
+        }
+        state.posted += n;
+        Ok(n)
+    }
+    // Forgets every posted receive and marks all slots free, for after the QP went
+    // through RESET, which discards the RQ without completions. The application must
+    // not hold any slot from `data` at this point.
+    pub fn reset(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.free = (0..self.slots() as u64).rev().collect();
+        state.posted = 0;
+    }
+    // Runs `f` over the received bytes and releases the slot afterwards. A failed
+    // completion releases its slot too before its error is returned, so the RQ
+    // doesn't shrink by one receive per error.
+    pub fn on_recv<R>(
         &self,
-        wr: &IbvRecvWr,
-        bad_wr: *const *const IbvRecvWr,
-    ) -> Result<(), IOError> {
-        let ibv_post_recv = unsafe { (*(*self.ibv_qp.as_ptr()).context).ops.post_recv.unwrap() };
-        let ret = unsafe {
-            ibv_post_recv(
-                self.ibv_qp.as_ptr(),
-                wr as *const _ as *mut _,
-                bad_wr as *mut _,
-            )
+        qp: &IbvQp,
+        wc: &IbvWc,
+        f: impl FnOnce(&[u8]) -> R,
+    ) -> Result<R, IOError> {
+        let data = match self.data(wc) {
+            Ok(data) => data,
+            Err(err) => {
+                if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS && wc.wr_id < self.slots() as u64
+                {
+                    // The completion's error is the one worth reporting.
+                    let _ = self.release(qp, wc.wr_id);
+                }
+                return Err(err);
+            }
         };
-        if ret == -1 {
-            return Err(IOError::last_os_error());
-        }
-        Ok(())
+        let ret = f(data);
+        self.release(qp, wc.wr_id)?;
+        Ok(ret)
     }
-    pub fn as_mut_ptr(&self) -> *mut ffi::ibv_qp {
-        self.ibv_qp.as_ptr()
+}
+
+// device_cap_flags_ex: the ibv_device_cap_flags bits in the low 32 bits and the
+// extended IB_UVERBS_DEVICE_* bits (ffi::ib_uverbs_device_cap_flags_*) above them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceCapFlagsEx(pub u64);
+
+impl DeviceCapFlagsEx {
+    #[inline(always)]
+    pub fn contains(self, flags: u64) -> bool {
+        self.0 & flags == flags
+    }
+    #[inline(always)]
+    pub fn contains_legacy(self, flags: ffi::ibv_device_cap_flags) -> bool {
+        self.contains(flags.0 as u64)
+    }
+    // The part that mirrors device_cap_flags of the plain device attr.
+    #[inline(always)]
+    pub fn legacy(self) -> ffi::ibv_device_cap_flags {
+        ffi::ibv_device_cap_flags(self.0 as u32)
+    }
+}
+
+impl IbvDeviceAttrEx {
+    #[inline(always)]
+    pub fn raw_packet_caps(&self) -> RawPacketCaps {
+        ffi::ibv_raw_packet_caps(self.raw_packet_caps)
+    }
+    #[inline(always)]
+    pub fn device_cap_flags_ex(&self) -> DeviceCapFlagsEx {
+        DeviceCapFlagsEx(self.device_cap_flags_ex)
+    }
+    // Raw packet QPs can scatter the Ethernet FCS into the receive buffer
+    // (IBV_WQ_FLAGS_SCATTER_FCS / IBV_QP_CREATE_SCATTER_FCS).
+    #[inline(always)]
+    pub fn supports_scatter_fcs(&self) -> bool {
+        self.raw_packet_caps()
+            .contains(RawPacketCaps::IBV_RAW_PACKET_CAP_SCATTER_FCS)
+    }
+    #[inline(always)]
+    pub fn supports_delay_drop(&self) -> bool {
+        self.raw_packet_caps()
+            .contains(RawPacketCaps::IBV_RAW_PACKET_CAP_DELAY_DROP)
+    }
+    #[inline(always)]
+    pub fn supports_cvlan_stripping(&self) -> bool {
+        self.raw_packet_caps()
+            .contains(RawPacketCaps::IBV_RAW_PACKET_CAP_CVLAN_STRIPPING)
+    }
+    #[inline(always)]
+    pub fn supports_implicit_odp(&self) -> bool {
+        self.odp_caps.general_caps & ffi::ibv_odp_general_caps::IBV_ODP_SUPPORT_IMPLICIT.0 as u64
+            != 0
+    }
+    pub fn supports_flush(&self, placement_type: ffi::ibv_placement_type) -> bool {
+        let cap = match placement_type {
+            ffi::ibv_placement_type_IBV_FLUSH_GLOBAL => {
+                ffi::ib_uverbs_device_cap_flags_IB_UVERBS_DEVICE_FLUSH_GLOBAL
+            }
+            ffi::ibv_placement_type_IBV_FLUSH_PERSISTENT => {
+                ffi::ib_uverbs_device_cap_flags_IB_UVERBS_DEVICE_FLUSH_PERSISTENT
+            }
+            _ => return false,
+        };
+        self.device_cap_flags_ex & cap != 0
     }
 }
-impl Drop for IbvQp {
-    fn drop(&mut self) {
-        let ret = unsafe { ffi::ibv_destroy_qp(self.ibv_qp.as_ptr()) };
-        if ret == -1 {
-            panic!("ibv_destroy_qp() error");
-        }
+
+const DEVICE_CAP_NAMES: &[(ffi::ibv_device_cap_flags, &str)] = &[
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_RESIZE_MAX_WR,
+        "RESIZE_MAX_WR",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_BAD_PKEY_CNTR,
+        "BAD_PKEY_CNTR",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_BAD_QKEY_CNTR,
+        "BAD_QKEY_CNTR",
+    ),
+    (ffi::ibv_device_cap_flags::IBV_DEVICE_RAW_MULTI, "RAW_MULTI"),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_AUTO_PATH_MIG,
+        "AUTO_PATH_MIG",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_CHANGE_PHY_PORT,
+        "CHANGE_PHY_PORT",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_UD_AV_PORT_ENFORCE,
+        "UD_AV_PORT_ENFORCE",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_CURR_QP_STATE_MOD,
+        "CURR_QP_STATE_MOD",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_SHUTDOWN_PORT,
+        "SHUTDOWN_PORT",
+    ),
+    (ffi::ibv_device_cap_flags::IBV_DEVICE_INIT_TYPE, "INIT_TYPE"),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_PORT_ACTIVE_EVENT,
+        "PORT_ACTIVE_EVENT",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_SYS_IMAGE_GUID,
+        "SYS_IMAGE_GUID",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_RC_RNR_NAK_GEN,
+        "RC_RNR_NAK_GEN",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_SRQ_RESIZE,
+        "SRQ_RESIZE",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_N_NOTIFY_CQ,
+        "N_NOTIFY_CQ",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_MEM_WINDOW,
+        "MEM_WINDOW",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_UD_IP_CSUM,
+        "UD_IP_CSUM",
+    ),
+    (ffi::ibv_device_cap_flags::IBV_DEVICE_XRC, "XRC"),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_MEM_MGT_EXTENSIONS,
+        "MEM_MGT_EXTENSIONS",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_MEM_WINDOW_TYPE_2A,
+        "MEM_WINDOW_TYPE_2A",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_MEM_WINDOW_TYPE_2B,
+        "MEM_WINDOW_TYPE_2B",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_RC_IP_CSUM,
+        "RC_IP_CSUM",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_RAW_IP_CSUM,
+        "RAW_IP_CSUM",
+    ),
+    (
+        ffi::ibv_device_cap_flags::IBV_DEVICE_MANAGED_FLOW_STEERING,
+        "MANAGED_FLOW_STEERING",
+    ),
+];
+
+impl std::fmt::Display for IbvDeviceAttr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.report())
     }
 }
-unsafe impl Send for IbvQp {}
-unsafe impl Sync for IbvQp {}
+
+// The GUIDs in ibv_device_attr are __be64, so their native bytes are already in
+// wire order.
+fn format_guid(bytes: [u8; 8]) -> String {
+    bytes
+        .chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<_>>()
+        .join(":")
+}
 
 impl IbvDeviceAttr {
+    // Up to the first NUL, or the whole buffer if there is none; invalid UTF-8 is
+    // replaced rather than panicking, as some vendors fill the field loosely.
     #[inline(always)]
-    pub fn fw_ver(&self) -> &str {
-        let mut i = 0;
-        while i < self.fw_ver.len() {
-            if self.fw_ver[i] as u8 == b'\0' {
-                break;
-            }
-            i += 1;
-        }
-        let s = unsafe { slice::from_raw_parts(self.fw_ver.as_ptr() as *const u8, i + 1) };
-        let cstr = CStr::from_bytes_with_nul(s).unwrap();
-        cstr.to_str().unwrap()
+    pub fn fw_ver(&self) -> Cow<'_, str> {
+        let bytes =
+            unsafe { slice::from_raw_parts(self.fw_ver.as_ptr() as *const u8, self.fw_ver.len()) };
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..len])
+    }
+    // Names of the set device_cap_flags bits, in bit order.
+    pub fn device_cap_names(&self) -> Vec<&'static str> {
+        DEVICE_CAP_NAMES
+            .iter()
+            .filter(|(flag, _)| self.device_cap_flags & flag.0 != 0)
+            .map(|&(_, name)| name)
+            .collect()
+    }
+    // A multi-line dump of the main limits and capabilities, for bug reports.
+    pub fn report(&self) -> String {
+        let atomic_cap = match self.atomic_cap {
+            ffi::ibv_atomic_cap::IBV_ATOMIC_NONE => "NONE",
+            ffi::ibv_atomic_cap::IBV_ATOMIC_HCA => "HCA",
+            ffi::ibv_atomic_cap::IBV_ATOMIC_GLOB => "GLOB",
+            _ => "unknown",
+        };
+        let rows: [(&str, String); 21] = [
+            ("fw_ver", self.fw_ver().into_owned()),
+            ("node_guid", self.node_guid_string()),
+            ("sys_image_guid", self.sys_image_guid_string()),
+            ("vendor_id", format!("{:#x}", self.vendor_id)),
+            ("vendor_part_id", self.vendor_part_id.to_string()),
+            ("hw_ver", format!("{:#x}", self.hw_ver)),
+            ("phys_port_cnt", self.phys_port_cnt.to_string()),
+            ("max_mr_size", format!("{:#x}", self.max_mr_size)),
+            ("page_size_cap", format!("{:#x}", self.page_size_cap)),
+            ("max_qp", self.max_qp.to_string()),
+            ("max_qp_wr", self.max_qp_wr.to_string()),
+            ("max_sge", self.max_sge.to_string()),
+            ("max_cq", self.max_cq.to_string()),
+            ("max_cqe", self.max_cqe.to_string()),
+            ("max_mr", self.max_mr.to_string()),
+            ("max_pd", self.max_pd.to_string()),
+            ("max_qp_rd_atom", self.max_qp_rd_atom.to_string()),
+            ("max_srq", self.max_srq.to_string()),
+            ("max_pkeys", self.max_pkeys.to_string()),
+            ("atomic_cap", atomic_cap.to_string()),
+            (
+                "device_cap_flags",
+                format!(
+                    "{:#010x} ({})",
+                    self.device_cap_flags,
+                    self.device_cap_names().join(" ")
+                ),
+            ),
+        ];
+        rows.iter()
+            .map(|(name, value)| format!("{:<18}{}\n", format!("{}:", name), value))
+            .collect()
     }
     #[inline(always)]
     pub fn node_guid(&self) -> u64 {
@@ -584,14 +6230,40 @@ impl IbvDeviceAttr {
     pub fn sys_image_guid(&self) -> u64 {
         self.sys_image_guid
     }
+    // node_guid as it sits on the wire (big-endian), the order tools print it in.
+    #[inline(always)]
+    pub fn guid_bytes(&self) -> [u8; 8] {
+        self.node_guid.to_ne_bytes()
+    }
+    // node_guid in the `xxxx:xxxx:xxxx:xxxx` form ibstat and ibv_devinfo print.
+    pub fn node_guid_string(&self) -> String {
+        format_guid(self.node_guid.to_ne_bytes())
+    }
+    pub fn sys_image_guid_string(&self) -> String {
+        format_guid(self.sys_image_guid.to_ne_bytes())
+    }
     #[inline(always)]
     pub fn max_mr_size(&self) -> u64 {
         self.max_mr_size
     }
+    // Bitmask of the page sizes the device can map an MR with; bit n set means 2^n.
     #[inline(always)]
     pub fn page_size_cap(&self) -> u64 {
         self.page_size_cap
     }
+    pub fn largest_supported_page_size(&self) -> Option<u64> {
+        (self.page_size_cap != 0).then(|| 1 << (63 - self.page_size_cap.leading_zeros()))
+    }
+    pub fn smallest_supported_page_size(&self) -> Option<u64> {
+        (self.page_size_cap != 0).then(|| 1 << self.page_size_cap.trailing_zeros())
+    }
+    // Every page size in page_size_cap, ascending.
+    pub fn supported_page_sizes(&self) -> Vec<u64> {
+        (0..64)
+            .filter(|bit| self.page_size_cap & (1 << bit) != 0)
+            .map(|bit| 1 << bit)
+            .collect()
+    }
     #[inline(always)]
     pub fn vendor_id(&self) -> u32 {
         self.vendor_id
@@ -664,6 +6336,11 @@ impl IbvDeviceAttr {
     pub fn atomic_cap(&self) -> u32 {
         self.atomic_cap
     }
+    // Values this crate doesn't know read as AtomicCap::None.
+    #[inline(always)]
+    pub fn atomic_cap_enum(&self) -> AtomicCap {
+        AtomicCap::try_from(self.atomic_cap).unwrap_or(AtomicCap::None)
+    }
     #[inline(always)]
     pub fn max_ee(&self) -> i32 {
         self.max_ee
@@ -735,6 +6412,10 @@ impl IbvDeviceAttr {
 }
 
 impl IbvPortAttr {
+    #[inline(always)]
+    pub fn supports_mtu(&self, mtu: IbvMtu) -> bool {
+        u32::from(mtu) <= self.max_mtu
+    }
     #[inline(always)]
     pub fn state(&self) -> u32 {
         self.state
@@ -807,6 +6488,41 @@ impl IbvPortAttr {
     pub fn active_speed(&self) -> u8 {
         self.active_speed
     }
+    // The legacy u8 field can't encode NDR and later; newer kernels report the
+    // speed in active_speed_ex and leave it zero when they don't.
+    #[inline(always)]
+    pub fn active_speed_ex(&self) -> u32 {
+        if self.active_speed_ex != 0 {
+            self.active_speed_ex
+        } else {
+            self.active_speed as u32
+        }
+    }
+    // Signalling rate of the link (lanes x per-lane rate), as ibv_devinfo reports it.
+    // None for widths or speeds this table doesn't know.
+    pub fn link_speed_gbps(&self) -> Option<f64> {
+        let lanes = match self.active_width {
+            1 => 1.0,
+            2 => 4.0,
+            4 => 8.0,
+            8 => 12.0,
+            16 => 2.0,
+            _ => return None,
+        };
+        let lane_gbps = match self.active_speed_ex() {
+            1 => 2.5,     // SDR
+            2 => 5.0,     // DDR
+            4 => 10.0,    // QDR
+            8 => 10.0,    // FDR10
+            16 => 14.0,   // FDR
+            32 => 25.0,   // EDR
+            64 => 50.0,   // HDR
+            128 => 100.0, // NDR
+            256 => 200.0, // XDR
+            _ => return None,
+        };
+        Some(lanes * lane_gbps)
+    }
     #[inline(always)]
     pub fn getphys_state(&self) -> u8 {
         self.phys_state
@@ -823,9 +6539,288 @@ impl IbvPortAttr {
     pub fn port_cap_flags2(&self) -> u16 {
         self.port_cap_flags2
     }
+    #[inline(always)]
+    pub fn cap_flags(&self) -> PortCapFlags {
+        ffi::ibv_port_cap_flags(self.port_cap_flags)
+    }
+    #[inline(always)]
+    pub fn cap_flags2(&self) -> PortCapFlags2 {
+        ffi::ibv_port_cap_flags2(self.port_cap_flags2 as u32)
+    }
+    // The port can host a subnet manager (IsSM).
+    #[inline(always)]
+    pub fn is_sm(&self) -> bool {
+        self.cap_flags().contains(PortCapFlags::IBV_PORT_SM)
+    }
+    #[inline(always)]
+    pub fn supports_client_reregister(&self) -> bool {
+        self.cap_flags()
+            .contains(PortCapFlags::IBV_PORT_CLIENT_REG_SUP)
+    }
+}
+
+// DSCP occupies the upper 6 bits of the IPv6 traffic class / IPv4 TOS byte.
+#[inline(always)]
+pub fn dscp_to_traffic_class(dscp: u8) -> u8 {
+    (dscp & 0x3f) << 2
+}
+
+pub struct IbvAhAttrBuilder {
+    attr: IbvAhAttr,
+}
+
+impl IbvAhAttrBuilder {
+    pub fn new(port_num: u8) -> Self {
+        let mut attr = unsafe { std::mem::zeroed::<IbvAhAttr>() };
+        attr.port_num = port_num;
+        Self { attr }
+    }
+    pub fn dlid(mut self, dlid: u16) -> Self {
+        self.attr.dlid = dlid;
+        self
+    }
+    pub fn sl(mut self, sl: u8) -> Self {
+        self.attr.sl = sl;
+        self
+    }
+    pub fn src_path_bits(mut self, src_path_bits: u8) -> Self {
+        self.attr.src_path_bits = src_path_bits;
+        self
+    }
+    pub fn static_rate(mut self, static_rate: u8) -> Self {
+        self.attr.static_rate = static_rate;
+        self
+    }
+    // Adds a GRH addressed to `dgid`, sourced from the local GID at `sgid_index`.
+    pub fn global(mut self, dgid: IbvGid, sgid_index: u8) -> Self {
+        self.attr.is_global = 1;
+        self.attr.grh.dgid = dgid;
+        self.attr.grh.sgid_index = sgid_index;
+        self.attr.grh.hop_limit = 64;
+        self
+    }
+    pub fn hop_limit(mut self, hop_limit: u8) -> Self {
+        self.attr.grh.hop_limit = hop_limit;
+        self
+    }
+    pub fn flow_label(mut self, flow_label: u32) -> Self {
+        self.attr.grh.flow_label = flow_label;
+        self
+    }
+    // Only meaningful together with `global`.
+    pub fn traffic_class(mut self, traffic_class: u8) -> Self {
+        self.attr.grh.traffic_class = traffic_class;
+        self
+    }
+    pub fn build(self) -> IbvAhAttr {
+        self.attr
+    }
+}
+
+impl IbvWc {
+    // Immediate data travels and is stored in network byte order; this converts it
+    // to host order, matching the to_be() done when posting (`PostedWr::imm`,
+    // `IbvSendWr::set_imm_data`). None if the completion carries none.
+    #[inline(always)]
+    pub fn imm_data(&self) -> Option<u32> {
+        self.imm_data_raw().map(u32::from_be)
+    }
+    // Whether a GRH was received with the datagram (UD only). The GRH space in the
+    // receive buffer is used either way, but holds garbage without this flag.
+    #[inline(always)]
+    pub fn has_grh(&self) -> bool {
+        self.wc_flags & ffi::ibv_wc_flags::IBV_WC_GRH.0 != 0
+    }
+    // The immediate data exactly as the provider stored it, in network order.
+    #[inline(always)]
+    pub fn imm_data_raw(&self) -> Option<u32> {
+        if self.wc_flags & ffi::ibv_wc_flags::IBV_WC_WITH_IMM.0 == 0 {
+            return None;
+        }
+        Some(unsafe { self.__bindgen_anon_1.imm_data })
+    }
+    #[inline(always)]
+    pub fn slid(&self) -> u16 {
+        self.slid
+    }
+    #[inline(always)]
+    pub fn sl(&self) -> u8 {
+        self.sl
+    }
+    #[inline(always)]
+    pub fn dlid_path_bits(&self) -> u8 {
+        self.dlid_path_bits
+    }
+    #[inline(always)]
+    pub fn src_qp(&self) -> u32 {
+        self.src_qp
+    }
+    pub fn classify(&self) -> CompletionEvent {
+        let wr_id = self.wr_id;
+        if self.status != ffi::ibv_wc_status::IBV_WC_SUCCESS {
+            return CompletionEvent::Error {
+                wr_id,
+                status: self.status,
+            };
+        }
+        let len = self.byte_len;
+        let imm = self.imm_data();
+        match (self.opcode, imm) {
+            (ffi::ibv_wc_opcode::IBV_WC_SEND, _) => CompletionEvent::SendDone { wr_id },
+            (ffi::ibv_wc_opcode::IBV_WC_RDMA_WRITE, _) => CompletionEvent::WriteDone { wr_id },
+            (ffi::ibv_wc_opcode::IBV_WC_RDMA_READ, _) => CompletionEvent::ReadDone { wr_id },
+            (ffi::ibv_wc_opcode::IBV_WC_COMP_SWAP, _)
+            | (ffi::ibv_wc_opcode::IBV_WC_FETCH_ADD, _) => CompletionEvent::AtomicDone { wr_id },
+            (ffi::ibv_wc_opcode::IBV_WC_RECV, None) => CompletionEvent::RecvData { wr_id, len },
+            (ffi::ibv_wc_opcode::IBV_WC_RECV, Some(imm)) => {
+                CompletionEvent::RecvWithImm { wr_id, len, imm }
+            }
+            (ffi::ibv_wc_opcode::IBV_WC_RECV_RDMA_WITH_IMM, Some(imm)) => {
+                CompletionEvent::WriteWithImm { wr_id, len, imm }
+            }
+            (
+                opcode @ (ffi::ibv_wc_opcode::IBV_WC_TM_ADD
+                | ffi::ibv_wc_opcode::IBV_WC_TM_DEL
+                | ffi::ibv_wc_opcode::IBV_WC_TM_SYNC
+                | ffi::ibv_wc_opcode::IBV_WC_TM_RECV
+                | ffi::ibv_wc_opcode::IBV_WC_TM_NO_TAG),
+                _,
+            ) => CompletionEvent::TagMatching { wr_id, opcode, len },
+            (opcode, _) => CompletionEvent::Other { wr_id, opcode },
+        }
+    }
+    // The NIC verified the IPv4 header checksum and the TCP/UDP checksum. Only set
+    // on UD and raw packet QPs of devices with checksum offload.
+    #[inline(always)]
+    pub fn ip_csum_ok(&self) -> bool {
+        self.wc_flags & ffi::ibv_wc_flags::IBV_WC_IP_CSUM_OK.0 != 0
+    }
+    // Verbs reports both checksums through the single IBV_WC_IP_CSUM_OK bit.
+    #[inline(always)]
+    pub fn l4_csum_ok(&self) -> bool {
+        self.ip_csum_ok()
+    }
+    // AH attributes for answering the sender of this UD receive over a LID-routed
+    // (IB, no GRH) path. Replies to GRH-carrying datagrams, which includes all of
+    // RoCE, need the GRH and `reply_ah_attr_with_grh`.
+    pub fn reply_ah_attr(&self, port_num: u8) -> IbvAhAttr {
+        IbvAhAttrBuilder::new(port_num)
+            .dlid(self.slid)
+            .sl(self.sl)
+            .src_path_bits(self.dlid_path_bits)
+            .build()
+    }
+    // Like `reply_ah_attr`, but swaps the source and destination GIDs of `grh`, the
+    // 40 bytes the HCA placed at the start of the receive buffer.
+    pub fn reply_ah_attr_with_grh(
+        &self,
+        context: &IbvContext,
+        port_num: u8,
+        grh: &[u8],
+    ) -> Result<IbvAhAttr, IOError> {
+        if grh.len() < std::mem::size_of::<ffi::ibv_grh>() {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "GRH buffer shorter than 40 bytes",
+            ));
+        }
+        let mut wc = *self;
+        let mut ah_attr = unsafe { std::mem::zeroed::<IbvAhAttr>() };
+        let ret = unsafe {
+            ffi::ibv_init_ah_from_wc(
+                context.as_mut_ptr(),
+                port_num,
+                &mut wc,
+                grh.as_ptr() as *mut ffi::ibv_grh,
+                &mut ah_attr,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(ah_attr)
+    }
+    // The decoded status if the WR failed on a protection or access check.
+    #[inline(always)]
+    pub fn access_error(&self) -> Option<AccessError> {
+        AccessError::from_status(self.status)
+    }
+    #[inline(always)]
+    pub fn status_str(&self) -> &'static str {
+        unsafe { CStr::from_ptr(ffi::ibv_wc_status_str(self.status)) }
+            .to_str()
+            .unwrap_or("unknown")
+    }
+}
+
+impl IbvAsyncEvent {
+    pub fn event_type_str(&self) -> &'static str {
+        unsafe { CStr::from_ptr(ffi::ibv_event_type_str(self.event_type)) }
+            .to_str()
+            .unwrap_or("unknown")
+    }
+    // The QP the event refers to, for the QP-scoped event types.
+    pub fn qp(&self) -> Option<NonNull<ffi::ibv_qp>> {
+        match self.event_type {
+            ffi::ibv_event_type::IBV_EVENT_QP_FATAL
+            | ffi::ibv_event_type::IBV_EVENT_QP_REQ_ERR
+            | ffi::ibv_event_type::IBV_EVENT_QP_ACCESS_ERR
+            | ffi::ibv_event_type::IBV_EVENT_COMM_EST
+            | ffi::ibv_event_type::IBV_EVENT_SQ_DRAINED
+            | ffi::ibv_event_type::IBV_EVENT_PATH_MIG
+            | ffi::ibv_event_type::IBV_EVENT_PATH_MIG_ERR
+            | ffi::ibv_event_type::IBV_EVENT_QP_LAST_WQE_REACHED => {
+                NonNull::new(unsafe { self.element.qp })
+            }
+            _ => None,
+        }
+    }
+    // The first packet arrived on a QP still in RTR; moving it to RTS is now safe.
+    #[inline(always)]
+    pub fn is_comm_est(&self) -> bool {
+        self.event_type == ffi::ibv_event_type::IBV_EVENT_COMM_EST
+    }
+    // The device is unusable, e.g. after a firmware reset or driver reload; every
+    // resource on the context is dead and it must be closed and reopened.
+    #[inline(always)]
+    pub fn is_device_fatal(&self) -> bool {
+        self.event_type == ffi::ibv_event_type::IBV_EVENT_DEVICE_FATAL
+    }
+    // A QP moved to SQD with the notification enabled has no more outstanding sends.
+    #[inline(always)]
+    pub fn is_sq_drained(&self) -> bool {
+        self.event_type == ffi::ibv_event_type::IBV_EVENT_SQ_DRAINED
+    }
+    // An SRQ-attached QP in the error state will consume no more SRQ WQEs.
+    #[inline(always)]
+    pub fn is_last_wqe_reached(&self) -> bool {
+        self.event_type == ffi::ibv_event_type::IBV_EVENT_QP_LAST_WQE_REACHED
+    }
+}
+
+impl IbvSendWr {
+    // Sets the immediate data of a *_WITH_IMM WR from a host-order value; the
+    // receiver gets it back with `IbvWc::imm_data`.
+    #[inline(always)]
+    pub fn set_imm_data(&mut self, imm_data: u32) {
+        self.__bindgen_anon_1.imm_data = imm_data.to_be();
+    }
+}
+
+impl IbvGrh {
+    // The 40-byte GRH at the start of a UD receive buffer. The buffer need not be
+    // aligned.
+    pub fn from_recv_buf(buf: &[u8]) -> Option<IbvGrh> {
+        if buf.len() < std::mem::size_of::<IbvGrh>() {
+            return None;
+        }
+        Some(unsafe { std::ptr::read_unaligned(buf.as_ptr() as *const IbvGrh) })
+    }
 }
 
 impl IbvGid {
+    // The raw union fields. A GID is stored as it goes on the wire, so these are in
+    // network byte order; print or compare the `_host` variants instead.
     #[inline(always)]
     pub fn subnet_prefix(&self) -> u64 {
         unsafe { self.global.subnet_prefix }
@@ -834,6 +6829,71 @@ impl IbvGid {
     pub fn interface_id(&self) -> u64 {
         unsafe { self.global.interface_id }
     }
+    #[inline(always)]
+    pub fn subnet_prefix_host(&self) -> u64 {
+        u64::from_be(self.subnet_prefix())
+    }
+    #[inline(always)]
+    pub fn interface_id_host(&self) -> u64 {
+        u64::from_be(self.interface_id())
+    }
+    pub fn to_ipv6(&self) -> Ipv6Addr {
+        Ipv6Addr::from(self.octets())
+    }
+    // The IPv4-mapped IPv6 form (::ffff:a.b.c.d) RoCEv2 uses for IPv4 addresses.
+    pub fn from_ipv4(addr: Ipv4Addr) -> IbvGid {
+        IbvGid {
+            raw: addr.to_ipv6_mapped().octets(),
+        }
+    }
+    pub fn as_ipv4(&self) -> Option<Ipv4Addr> {
+        Ipv6Addr::from(unsafe { self.raw }).to_ipv4_mapped()
+    }
+    #[inline(always)]
+    pub fn octets(&self) -> [u8; 16] {
+        unsafe { self.raw }
+    }
+    // Unused GID table entries read back as all zeroes.
+    #[inline(always)]
+    pub fn is_zero(&self) -> bool {
+        self.octets() == [0; 16]
+    }
+}
+
+// All eight groups in full, as ibv_devinfo -v and show_gids print GIDs.
+impl std::fmt::Display for IbvGid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let octets = self.octets();
+        for (i, pair) in octets.chunks(2).enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02x}{:02x}", pair[0], pair[1])?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for IbvGid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(self, f)
+    }
+}
+
+// Parses what Display prints, any other IPv6 notation (fe80::1, ::ffff:10.0.0.1),
+// or a bare IPv4 address, which becomes its IPv4-mapped GID.
+impl std::str::FromStr for IbvGid {
+    type Err = AddrParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.parse::<Ipv6Addr>() {
+            Ok(addr) => Ok(IbvGid { raw: addr.octets() }),
+            Err(err) => s
+                .parse::<Ipv4Addr>()
+                .map(IbvGid::from_ipv4)
+                .map_err(|_| err),
+        }
+    }
 }
 
 impl IbvQpInitAttr {
@@ -866,14 +6926,340 @@ impl IbvQpInitAttr {
         self.cap.max_inline_data = max_inline_data;
     }
     #[inline(always)]
-    pub fn set_qp_type(&mut self, qp_type: u32) {
-        self.qp_type = qp_type;
+    pub fn set_qp_type(&mut self, qp_type: impl Into<u32>) {
+        self.qp_type = qp_type.into();
+    }
+    #[inline(always)]
+    pub fn set_sq_sig_all(&mut self, sq_sig_all: i32) {
+        self.sq_sig_all = sq_sig_all;
+    }
+}
+// Extended QPs name their PD in the attr instead of the create call, so a QP can be
+// placed in any PD of the context (e.g. keep the remotely writable MRs and the QP
+// that exposes them in a PD of their own). Every local SGE and every incoming RDMA
+// is still checked against that one PD. `ibv_create_qp_ex` requires
+// IBV_QP_INIT_ATTR_PD, and the PD, CQs and QP must all belong to the same context.
+impl IbvQpInitAttrEx {
+    #[inline(always)]
+    pub fn set_pd(&mut self, pd: &IbvPd) {
+        self.pd = pd.as_mut_ptr();
+        self.comp_mask |= ffi::ibv_qp_init_attr_mask::IBV_QP_INIT_ATTR_PD.0;
+    }
+    #[inline(always)]
+    pub fn set_send_cq(&mut self, send_cq: &IbvCq) {
+        self.send_cq = send_cq.ibv_cq.as_ptr();
+    }
+    #[inline(always)]
+    pub fn set_recv_cq(&mut self, recv_cq: &IbvCq) {
+        self.recv_cq = recv_cq.ibv_cq.as_ptr();
+    }
+    #[inline(always)]
+    pub fn set_cap(&mut self, cap: ffi::ibv_qp_cap) {
+        self.cap = cap;
+    }
+    #[inline(always)]
+    pub fn set_qp_type(&mut self, qp_type: impl Into<u32>) {
+        self.qp_type = qp_type.into();
     }
     #[inline(always)]
     pub fn set_sq_sig_all(&mut self, sq_sig_all: i32) {
         self.sq_sig_all = sq_sig_all;
     }
+    #[inline(always)]
+    pub fn set_send_ops_flags(&mut self, send_ops_flags: ffi::ibv_qp_create_send_ops_flags) {
+        self.send_ops_flags = send_ops_flags.0 as u64;
+        self.comp_mask |= ffi::ibv_qp_init_attr_mask::IBV_QP_INIT_ATTR_SEND_OPS_FLAGS.0;
+    }
+}
+
+// For `IbvSrq::new_ex`. A basic SRQ only needs the PD; an XRC SRQ also needs the
+// XRC domain and the CQ its receive completions go to, since the XRC receive QPs
+// sharing it have no receive CQ of their own.
+impl IbvSrqInitAttrEx {
+    pub fn new(max_wr: u32, max_sge: u32) -> Self {
+        let mut attr = unsafe { std::mem::zeroed::<IbvSrqInitAttrEx>() };
+        attr.attr.max_wr = max_wr;
+        attr.attr.max_sge = max_sge;
+        attr
+    }
+    #[inline(always)]
+    pub fn set_srq_type(&mut self, srq_type: ffi::ibv_srq_type::Type) {
+        self.srq_type = srq_type;
+        self.comp_mask |= ffi::ibv_srq_init_attr_mask::IBV_SRQ_INIT_ATTR_TYPE.0;
+    }
+    #[inline(always)]
+    pub fn set_pd(&mut self, pd: &IbvPd) {
+        self.pd = pd.as_mut_ptr();
+        self.comp_mask |= ffi::ibv_srq_init_attr_mask::IBV_SRQ_INIT_ATTR_PD.0;
+    }
+    #[inline(always)]
+    pub fn set_xrcd(&mut self, xrcd: &IbvXrcd) {
+        self.xrcd = xrcd.as_mut_ptr();
+        self.comp_mask |= ffi::ibv_srq_init_attr_mask::IBV_SRQ_INIT_ATTR_XRCD.0;
+    }
+    #[inline(always)]
+    pub fn set_cq(&mut self, cq: &IbvCq) {
+        self.cq = cq.as_mut_ptr();
+        self.comp_mask |= ffi::ibv_srq_init_attr_mask::IBV_SRQ_INIT_ATTR_CQ.0;
+    }
+    // Tag matching: the HCA matches incoming messages against up to
+    // `max_num_tags` posted tags, with at most `max_ops` tag list updates
+    // outstanding. Requires the SRQ type to be IBV_SRQT_TM and a CQ.
+    #[inline(always)]
+    pub fn set_tm_cap(&mut self, max_num_tags: u32, max_ops: u32) {
+        self.tm_cap.max_num_tags = max_num_tags;
+        self.tm_cap.max_ops = max_ops;
+        self.comp_mask |= ffi::ibv_srq_init_attr_mask::IBV_SRQ_INIT_ATTR_TM.0;
+    }
+    // A tag-matching SRQ completing into `cq`.
+    pub fn tag_matching(
+        pd: &IbvPd,
+        cq: &IbvCq,
+        max_wr: u32,
+        max_sge: u32,
+        max_num_tags: u32,
+        max_ops: u32,
+    ) -> Self {
+        let mut attr = Self::new(max_wr, max_sge);
+        attr.set_srq_type(ffi::ibv_srq_type::IBV_SRQT_TM);
+        attr.set_pd(pd);
+        attr.set_cq(cq);
+        attr.set_tm_cap(max_num_tags, max_ops);
+        attr
+    }
+    // An XRC SRQ in one go.
+    pub fn xrc(pd: &IbvPd, xrcd: &IbvXrcd, cq: &IbvCq, max_wr: u32, max_sge: u32) -> Self {
+        let mut attr = Self::new(max_wr, max_sge);
+        attr.set_srq_type(ffi::ibv_srq_type::IBV_SRQT_XRC);
+        attr.set_pd(pd);
+        attr.set_xrcd(xrcd);
+        attr.set_cq(cq);
+        attr
+    }
+}
+
+impl IbvAccess {
+    // Presets. Local write is always included since it's required whenever remote
+    // write or remote atomic is granted.
+    #[inline(always)]
+    pub fn local() -> Self {
+        Self::IBV_ACCESS_LOCAL_WRITE
+    }
+    #[inline(always)]
+    pub fn remote_read() -> Self {
+        Self::IBV_ACCESS_LOCAL_WRITE | Self::IBV_ACCESS_REMOTE_READ
+    }
+    #[inline(always)]
+    pub fn remote_write() -> Self {
+        Self::IBV_ACCESS_LOCAL_WRITE | Self::IBV_ACCESS_REMOTE_WRITE
+    }
+    #[inline(always)]
+    pub fn remote_read_write() -> Self {
+        Self::IBV_ACCESS_LOCAL_WRITE | Self::IBV_ACCESS_REMOTE_READ | Self::IBV_ACCESS_REMOTE_WRITE
+    }
+    #[inline(always)]
+    pub fn remote_atomic() -> Self {
+        Self::IBV_ACCESS_LOCAL_WRITE | Self::IBV_ACCESS_REMOTE_ATOMIC
+    }
+    #[inline(always)]
+    pub fn remote_all() -> Self {
+        Self::remote_read_write() | Self::IBV_ACCESS_REMOTE_ATOMIC
+    }
+    /// Hints that the memory is backed by hugepages so the driver can use larger
+    /// translation entries. It is only a hint and must match the real backing:
+    /// setting it on 4K-page memory can make registration fail or corrupt data
+    /// on some providers.
+    #[inline(always)]
+    pub fn hugetlb(self) -> Self {
+        self | Self::IBV_ACCESS_HUGETLB
+    }
+    // Registers the MR at IOVA 0: peers and SGEs address it by offset, and its
+    // virtual address never leaves the process. See `IbvMr::addr`.
+    #[inline(always)]
+    pub fn zero_based(self) -> Self {
+        self | Self::IBV_ACCESS_ZERO_BASED
+    }
+    // Required on any MR that memory windows will be bound to.
+    #[inline(always)]
+    pub fn mw_bind(self) -> Self {
+        self | Self::IBV_ACCESS_MW_BIND
+    }
+    // Allow the peer to FLUSH this MR to global visibility / persistence.
+    #[inline(always)]
+    pub fn flush_global(self) -> Self {
+        self | Self::IBV_ACCESS_FLUSH_GLOBAL
+    }
+    #[inline(always)]
+    pub fn flush_persistent(self) -> Self {
+        self | Self::IBV_ACCESS_FLUSH_PERSISTENT
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IbvTransport {
+    Ib,
+    Roce,
+    Iwarp,
+    Usnic,
+    UsnicUdp,
+    Unknown,
+}
+
+impl IbvTransport {
+    // RoCE devices report the IB transport; only the port's link layer tells them apart.
+    fn classify(transport_type: ffi::ibv_transport_type::Type, link_layer: u8) -> Self {
+        match transport_type {
+            ffi::ibv_transport_type::IBV_TRANSPORT_IB
+                if link_layer == ffi::IBV_LINK_LAYER_ETHERNET as u8 =>
+            {
+                IbvTransport::Roce
+            }
+            ffi::ibv_transport_type::IBV_TRANSPORT_IB => IbvTransport::Ib,
+            ffi::ibv_transport_type::IBV_TRANSPORT_IWARP => IbvTransport::Iwarp,
+            ffi::ibv_transport_type::IBV_TRANSPORT_USNIC => IbvTransport::Usnic,
+            ffi::ibv_transport_type::IBV_TRANSPORT_USNIC_UDP => IbvTransport::UsnicUdp,
+            _ => IbvTransport::Unknown,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct IbvDeviceInfo {
+    pub name: String,
+    pub guid: u64,
+    pub node_type: ffi::ibv_node_type::Type,
+    pub transport: IbvTransport,
+    // Zero when the device could not be opened to query them.
+    pub vendor_id: u32,
+    pub vendor_part_id: u32,
+}
+
+// Enumerates the RDMA devices on the host. Each device is briefly opened to read
+// its vendor ids and port 1's link layer.
+pub fn list_devices() -> Result<Vec<IbvDeviceInfo>, IOError> {
+    let mut num_devs: c_int = 0;
+    let dev_list_ptr = unsafe { ffi::ibv_get_device_list(&mut num_devs) };
+    if dev_list_ptr.is_null() {
+        return Err(IOError::last_os_error());
+    }
+    let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as usize) };
+    let devices = dev_list
+        .iter()
+        .map(|&dev| unsafe {
+            let mut info = IbvDeviceInfo {
+                name: CStr::from_ptr(ffi::ibv_get_device_name(dev))
+                    .to_string_lossy()
+                    .into_owned(),
+                guid: u64::from_be(ffi::ibv_get_device_guid(dev)),
+                node_type: (*dev).node_type,
+                transport: IbvTransport::classify(
+                    (*dev).transport_type,
+                    ffi::IBV_LINK_LAYER_UNSPECIFIED as u8,
+                ),
+                vendor_id: 0,
+                vendor_part_id: 0,
+            };
+            let ctx = ffi::ibv_open_device(dev);
+            if !ctx.is_null() {
+                let mut device_attr = std::mem::zeroed::<IbvDeviceAttr>();
+                if ffi::ibv_query_device(ctx, &mut device_attr) == 0 {
+                    info.vendor_id = device_attr.vendor_id;
+                    info.vendor_part_id = device_attr.vendor_part_id;
+                }
+                let mut port_attr = std::mem::zeroed::<IbvPortAttr>();
+                if ffi::ibv_query_port(
+                    ctx,
+                    1,
+                    &mut port_attr as *mut _ as *mut ffi::_compat_ibv_port_attr,
+                ) == 0
+                {
+                    info.transport =
+                        IbvTransport::classify((*dev).transport_type, port_attr.link_layer);
+                }
+                ffi::ibv_close_device(ctx);
+            }
+            info
+        })
+        .collect();
+    unsafe { ffi::ibv_free_device_list(dev_list_ptr) };
+    Ok(devices)
+}
+
+// Two RC QPs on `port_num` connected to each other, both reporting to `cq`, e.g. to
+// run producer and consumer in one process. Each addresses the port itself: its
+// own LID on InfiniBand, or on RoCE a GRH whose destination GID is the source GID.
+// The path MTU is the port's active MTU.
+pub fn loopback_pair(
+    context: &IbvContext,
+    pd: &IbvPd,
+    port_num: u8,
+    cq: &IbvCq,
+) -> Result<(IbvQp, IbvQp), IOError> {
+    if !context.supports_loopback(port_num)? {
+        return Err(IOError::new(
+            ErrorKind::Unsupported,
+            format!(
+                "port {} can't loop back: it is not active or has no LID/GID",
+                port_num
+            ),
+        ));
+    }
+    let port_attr = context.query_port(port_num)?;
+    let mtu = IbvMtu::try_from(port_attr.active_mtu()).unwrap_or(IbvMtu::Mtu1024);
+    let mut ah = IbvAhAttrBuilder::new(port_num);
+    if port_attr.link_layer == ffi::IBV_LINK_LAYER_ETHERNET as u8 {
+        let sgid_index = context.loopback_gid_index(port_num)?;
+        let gid = context.query_gid(port_num, sgid_index as i32)?;
+        ah = ah.global(gid, sgid_index);
+    } else {
+        ah = ah.dlid(port_attr.lid);
+    }
+    let ah_attr = ah.build();
+    let a = IbvQpBuilder::new(cq, cq).build(pd)?;
+    let b = IbvQpBuilder::new(cq, cq).build(pd)?;
+    let (psn_a, psn_b) = (random_psn(), random_psn());
+    for (qp, remote_qpn, local_psn, remote_psn) in
+        [(&a, b.qpn(), psn_a, psn_b), (&b, a.qpn(), psn_b, psn_a)]
+    {
+        qp.modify_reset2init(port_num)?;
+        qp.modify_init2rtr_with_ah(remote_qpn, remote_psn, mtu, &ah_attr)?;
+        qp.modify_rtr2rts(local_psn)?;
+    }
+    Ok((a, b))
+}
+
+// Every ACTIVE (device, port) pair on the host, e.g. the rails to spread
+// connections over. Each device with an active port is opened once and its
+// context shared by all of its entries; devices that fail to open are skipped.
+pub fn active_ports() -> Result<Vec<(Arc<IbvContext>, u8, IbvPortAttr)>, IOError> {
+    let mut num_devs: c_int = 0;
+    let dev_list_ptr = unsafe { ffi::ibv_get_device_list(&mut num_devs) };
+    if dev_list_ptr.is_null() {
+        return Err(IOError::last_os_error());
+    }
+    let dev_list = unsafe { std::slice::from_raw_parts(dev_list_ptr, num_devs as usize) };
+    let mut ports = Vec::new();
+    for &dev in dev_list {
+        let context = match NonNull::new(unsafe { ffi::ibv_open_device(dev) }) {
+            Some(ibv_context) => Arc::new(IbvContext { ibv_context }),
+            None => continue,
+        };
+        let port_cnt = match context.num_ports() {
+            Ok(n) => n,
+            Err(_) => continue,
+        };
+        for port_num in 1..=port_cnt {
+            if let Ok(port_attr) = context.query_port(port_num) {
+                if port_attr.state == ffi::ibv_port_state::IBV_PORT_ACTIVE {
+                    ports.push((context.clone(), port_num, port_attr));
+                }
+            }
+        }
+    }
+    unsafe { ffi::ibv_free_device_list(dev_list_ptr) };
+    Ok(ports)
 }
+
 pub fn ibv_fork_init() -> Result<(), IOError> {
     let ret = unsafe { ffi::ibv_fork_init() };
     if ret != 0 {
@@ -881,3 +7267,62 @@ pub fn ibv_fork_init() -> Result<(), IOError> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cmp::Ordering::{Equal, Greater, Less};
+
+    #[test]
+    fn psn_add_wraps_at_2_24() {
+        assert_eq!(psn_add(PSN_MASK, 1), 0);
+        assert_eq!(psn_add(PSN_MASK - 1, 5), 3);
+        assert_eq!(psn_add(5, 0), 5);
+        assert_eq!(psn_add(0, PSN_MASK), PSN_MASK);
+    }
+
+    #[test]
+    fn psn_compare_across_the_wrap() {
+        assert_eq!(psn_compare(7, 7), Equal);
+        assert_eq!(psn_compare(1, 2), Less);
+        assert_eq!(psn_compare(2, 1), Greater);
+        assert_eq!(psn_compare(PSN_MASK, 0), Less);
+        assert_eq!(psn_compare(0, PSN_MASK), Greater);
+        assert_eq!(psn_compare(PSN_MASK - 10, 10), Less);
+        assert_eq!(psn_compare(10, PSN_MASK - 10), Greater);
+    }
+
+    #[test]
+    fn psn_compare_at_half_the_space() {
+        let half = 1 << 23;
+        assert_eq!(psn_compare(0, half - 1), Less);
+        assert_eq!(psn_compare(half - 1, 0), Greater);
+        // Exactly 2^23 apart the order is undefined and compares Greater both ways.
+        assert_eq!(psn_compare(0, half), Greater);
+        assert_eq!(psn_compare(half, 0), Greater);
+        assert_eq!(psn_compare(PSN_MASK, half - 1), Greater);
+        assert_eq!(psn_compare(half - 1, PSN_MASK), Greater);
+    }
+
+    #[test]
+    fn random_psn_avoiding_never_covers_last() {
+        for window in [1, 1 << 10, 1 << 22, (1 << 23) - 1, 1 << 23, u32::MAX] {
+            for last in [0, 1, 1 << 22, 1 << 23, PSN_MASK - 1, PSN_MASK] {
+                for _ in 0..2000 {
+                    let psn = random_psn_avoiding(last, window);
+                    assert!(psn <= PSN_MASK);
+                    let window = window.min(MAX_PSN_WINDOW);
+                    // [psn, psn + window) modulo 2^24 must not contain `last`.
+                    let offset = last.wrapping_sub(psn) & PSN_MASK;
+                    assert!(
+                        offset >= window,
+                        "psn {:#x} window {:#x} covers last {:#x}",
+                        psn,
+                        window,
+                        last
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,3 +1,22 @@
+pub mod ah_cache;
+pub mod bench;
+pub mod channel;
+pub mod completion;
+pub mod context;
 pub mod error;
 pub mod ffi;
+pub mod gid_monitor;
+pub mod hw_counters;
 pub mod ibv;
+pub mod numa;
+pub mod pool;
+pub mod qp;
+pub mod qp_error;
+pub mod rdma_channel;
+pub mod recovery;
+pub mod replication;
+pub mod resource;
+pub mod roce_cc;
+pub mod rpc;
+pub mod signaling;
+pub mod tcp;
@@ -0,0 +1,257 @@
+// NUMA placement for a QP's resources. Buffers and completion vectors on the
+// socket the NIC hangs off avoid a cross-socket hop for every DMA and interrupt.
+use std::collections::HashSet;
+use std::io::{Error as IOError, ErrorKind};
+use std::ops::{Deref, DerefMut};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::ibv::IbvContext;
+
+// From <linux/mempolicy.h>.
+const MPOL_PREFERRED: libc::c_int = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumaPolicy {
+    // Leave placement to the kernel.
+    #[default]
+    Any,
+    // The node the device is attached to (`IbvContext::numa_node`), or `Any` when
+    // the device doesn't report one.
+    DeviceLocal,
+    Node(i32),
+}
+
+impl NumaPolicy {
+    pub fn node(&self, context: &IbvContext) -> Option<i32> {
+        match *self {
+            NumaPolicy::Any => None,
+            NumaPolicy::DeviceLocal => context.numa_node(),
+            NumaPolicy::Node(node) => Some(node),
+        }
+    }
+    // Allocates `len` bytes placed according to the policy.
+    pub fn alloc(&self, context: &IbvContext, len: usize) -> Result<NumaBuffer, IOError> {
+        NumaBuffer::new(len, self.node(context))
+    }
+    // A completion vector whose interrupt is served on the policy's node, handed
+    // out round-robin; round-robin over all vectors when none is local.
+    pub fn comp_vector(&self, context: &IbvContext) -> i32 {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let mut vectors = match self.node(context) {
+            Some(node) => local_comp_vectors(context, node),
+            None => Vec::new(),
+        };
+        if vectors.is_empty() {
+            vectors = (0..context.num_comp_vectors()).collect();
+        }
+        if vectors.is_empty() {
+            return 0;
+        }
+        vectors[NEXT.fetch_add(1, Ordering::Relaxed) % vectors.len()]
+    }
+}
+
+// Parses a cpulist such as "0-3,8,10-11".
+fn parse_cpulist(list: &str) -> HashSet<usize> {
+    let mut cpus = HashSet::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let mut bounds = range.splitn(2, '-').map(|b| b.trim().parse::<usize>());
+        match (bounds.next(), bounds.next()) {
+            (Some(Ok(lo)), Some(Ok(hi))) => cpus.extend(lo..=hi),
+            (Some(Ok(cpu)), None) => {
+                cpus.insert(cpu);
+            }
+            _ => {}
+        }
+    }
+    cpus
+}
+
+// The IRQ behind each completion vector the device names in /proc/interrupts
+// ("<driver>_comp<N>@pci:<bdf>", as mlx4/mlx5 register them). Other drivers get
+// their comp IRQs from the device's msi_irqs in ascending order, assumed to be in
+// vector order.
+fn comp_vector_irqs(context: &IbvContext) -> Vec<(i32, u32)> {
+    let interrupts = std::fs::read_to_string("/proc/interrupts").unwrap_or_default();
+    let irq_of = |line: &str| line.split(':').next()?.trim().parse::<u32>().ok();
+    let mut irqs = Vec::new();
+    if let Some(bdf) = context.pci_address() {
+        for line in interrupts.lines() {
+            let name = match line.split_whitespace().last() {
+                Some(name) if name.ends_with(&format!("@pci:{}", bdf)) => name,
+                _ => continue,
+            };
+            let vector = name
+                .split_once("_comp")
+                .and_then(|(_, rest)| rest.split('@').next())
+                .and_then(|n| n.parse::<i32>().ok());
+            if let (Some(vector), Some(irq)) = (vector, irq_of(line)) {
+                irqs.push((vector, irq));
+            }
+        }
+    }
+    if irqs.is_empty() {
+        let msi: HashSet<u32> =
+            std::fs::read_dir(format!("{}/device/msi_irqs", context.ibdev_path()))
+                .map(|dir| {
+                    dir.filter_map(|e| e.ok()?.file_name().to_str()?.parse().ok())
+                        .collect()
+                })
+                .unwrap_or_default();
+        let mut comp: Vec<u32> = interrupts
+            .lines()
+            .filter(|line| line.contains("comp"))
+            .filter_map(irq_of)
+            .filter(|irq| msi.contains(irq))
+            .collect();
+        comp.sort_unstable();
+        irqs = (0..).zip(comp).collect();
+    }
+    irqs.retain(|&(vector, _)| vector < context.num_comp_vectors());
+    irqs
+}
+
+fn irq_cpus(irq: u32) -> HashSet<usize> {
+    std::fs::read_to_string(format!("/proc/irq/{}/smp_affinity_list", irq))
+        .map(|list| parse_cpulist(&list))
+        .unwrap_or_default()
+}
+
+// The CPUs completion vector `vector`'s interrupt is affined to, ascending; empty
+// if its IRQ can't be found. Pin the thread handling that vector's CQ events here.
+pub fn comp_vector_cpus(context: &IbvContext, vector: i32) -> Vec<usize> {
+    let mut cpus: Vec<usize> = comp_vector_irqs(context)
+        .into_iter()
+        .find(|&(v, _)| v == vector)
+        .map(|(_, irq)| irq_cpus(irq).into_iter().collect())
+        .unwrap_or_default();
+    cpus.sort_unstable();
+    cpus
+}
+
+// Completion vectors whose IRQ affinity lies within `node`; see `comp_vector_irqs`
+// for how vectors are matched to IRQs.
+pub fn local_comp_vectors(context: &IbvContext, node: i32) -> Vec<i32> {
+    let node_cpus =
+        match std::fs::read_to_string(format!("/sys/devices/system/node/node{}/cpulist", node)) {
+            Ok(list) => parse_cpulist(&list),
+            Err(_) => return Vec::new(),
+        };
+    let mut vectors: Vec<i32> = comp_vector_irqs(context)
+        .into_iter()
+        .filter(|&(_, irq)| {
+            let cpus = irq_cpus(irq);
+            !cpus.is_empty() && cpus.is_subset(&node_cpus)
+        })
+        .map(|(vector, _)| vector)
+        .collect();
+    vectors.sort_unstable();
+    vectors
+}
+
+// Anonymous memory with a preferred node set before it is first touched. The pages
+// are faulted in up front, so they are placed by the time the region is
+// registered.
+pub struct NumaBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    node: Option<i32>,
+}
+
+impl NumaBuffer {
+    pub fn new(len: usize, node: Option<i32>) -> Result<Self, IOError> {
+        if len == 0 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                "cannot allocate an empty buffer",
+            ));
+        }
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(IOError::last_os_error());
+        }
+        let buf = Self {
+            ptr: unsafe { NonNull::new_unchecked(addr as *mut u8) },
+            len,
+            node,
+        };
+        if let Some(node) = node {
+            buf.prefer_node(node)?;
+        }
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) }.max(1) as usize;
+        for offset in (0..len).step_by(page) {
+            unsafe { buf.ptr.as_ptr().add(offset).write_volatile(0) };
+        }
+        Ok(buf)
+    }
+    // MPOL_PREFERRED rather than MPOL_BIND: if the node runs out of memory the
+    // pages fall back elsewhere instead of the process getting OOM-killed.
+    fn prefer_node(&self, node: i32) -> Result<(), IOError> {
+        if node < 0 {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("invalid NUMA node {}", node),
+            ));
+        }
+        let bits = libc::c_ulong::BITS as usize;
+        let node = node as usize;
+        let mut mask = vec![0 as libc::c_ulong; node / bits + 1];
+        mask[node / bits] |= 1 << (node % bits);
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.ptr.as_ptr(),
+                self.len,
+                MPOL_PREFERRED,
+                mask.as_ptr(),
+                // The kernel reads maxnode - 1 bits.
+                mask.len() * bits + 1,
+                0,
+            )
+        };
+        if ret != 0 {
+            return Err(IOError::last_os_error());
+        }
+        Ok(())
+    }
+    // The node asked for, None if placement was left to the kernel.
+    #[inline(always)]
+    pub fn node(&self) -> Option<i32> {
+        self.node
+    }
+}
+
+impl Deref for NumaBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl DerefMut for NumaBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Drop for NumaBuffer {
+    fn drop(&mut self) {
+        let ret = unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len) };
+        if ret != 0 {
+            panic!("munmap(). errno: {}", IOError::last_os_error());
+        }
+    }
+}
+
+unsafe impl Send for NumaBuffer {}
+unsafe impl Sync for NumaBuffer {}
@@ -0,0 +1,134 @@
+// A bounded pool of registered buffers leased out RAII-style. All buffers share
+// one allocation and one MR, so the pool costs a single registration.
+use std::io::{Error as IOError, ErrorKind};
+use std::sync::Mutex;
+
+use crate::ibv::{IbvAccess, IbvMr, IbvPd, IbvSge, RemoteToken};
+
+pub struct RegisteredBufferPool {
+    // The MR is declared before the buffer so it is deregistered first.
+    mr: IbvMr,
+    // Only owns the memory; leases go through `base` so that disjoint &mut slices
+    // never alias a reference to the whole buffer.
+    _buf: Box<[u8]>,
+    base: *mut u8,
+    count: usize,
+    size: usize,
+    free: Mutex<Vec<usize>>,
+}
+
+impl RegisteredBufferPool {
+    pub fn new(pd: &IbvPd, count: usize, size: usize, access: IbvAccess) -> Result<Self, IOError> {
+        if count == 0 || size == 0 || size > u32::MAX as usize {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("invalid pool of {} buffers of {} bytes", count, size),
+            ));
+        }
+        let len = count
+            .checked_mul(size)
+            .ok_or_else(|| IOError::new(ErrorKind::InvalidInput, "pool size overflows usize"))?;
+        let mut buf = vec![0_u8; len].into_boxed_slice();
+        let mr = IbvMr::new(pd, &buf, access)?;
+        Ok(Self {
+            mr,
+            base: buf.as_mut_ptr(),
+            _buf: buf,
+            count,
+            size,
+            free: Mutex::new((0..count).rev().collect()),
+        })
+    }
+    // Leases a free buffer, or returns None when all of them are out.
+    pub fn acquire(&self) -> Option<PooledBuffer<'_>> {
+        let index = self.free.lock().unwrap().pop()?;
+        Some(PooledBuffer { pool: self, index })
+    }
+    #[inline(always)]
+    pub fn capacity(&self) -> usize {
+        self.count
+    }
+    pub fn available(&self) -> usize {
+        self.free.lock().unwrap().len()
+    }
+    #[inline(always)]
+    pub fn buffer_size(&self) -> usize {
+        self.size
+    }
+    #[inline(always)]
+    pub fn mr(&self) -> &IbvMr {
+        &self.mr
+    }
+}
+
+unsafe impl Send for RegisteredBufferPool {}
+unsafe impl Sync for RegisteredBufferPool {}
+
+// A leased buffer; it goes back to the pool on drop. Make sure no posted WR still
+// references it by then.
+pub struct PooledBuffer<'a> {
+    pool: &'a RegisteredBufferPool,
+    index: usize,
+}
+
+impl PooledBuffer<'_> {
+    #[inline(always)]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.pool.size
+    }
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+    #[inline(always)]
+    pub fn addr(&self) -> u64 {
+        self.pool.mr.addr() + (self.index * self.pool.size) as u64
+    }
+    #[inline(always)]
+    pub fn lkey(&self) -> u32 {
+        self.pool.mr.lkey()
+    }
+    #[inline(always)]
+    pub fn rkey(&self) -> u32 {
+        self.pool.mr.rkey()
+    }
+    // An SGE over the first `len` bytes of the buffer.
+    pub fn sge(&self, len: u32) -> Result<IbvSge, IOError> {
+        if len as usize > self.pool.size {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("sge of {} bytes exceeds buffer of {}", len, self.pool.size),
+            ));
+        }
+        self.pool.mr.sge(self.addr(), len)
+    }
+    pub fn remote_token(&self) -> RemoteToken {
+        RemoteToken {
+            addr: self.addr(),
+            rkey: self.rkey(),
+            length: self.pool.size as u64,
+        }
+    }
+    // Each index is leased at most once at a time, so these are the only
+    // references to the buffer's bytes (the NIC aside).
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr(), self.pool.size) }
+    }
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr(), self.pool.size) }
+    }
+    #[inline(always)]
+    fn ptr(&self) -> *mut u8 {
+        unsafe { self.pool.base.add(self.index * self.pool.size) }
+    }
+}
+
+impl Drop for PooledBuffer<'_> {
+    fn drop(&mut self) {
+        self.pool.free.lock().unwrap().push(self.index);
+    }
+}
@@ -0,0 +1,126 @@
+// Typestate wrapper over IbvQp: each state of the verbs QP state machine is its
+// own type and transitions consume the QP and return it in the next state, so an
+// out-of-order transition, or posting in a state that doesn't allow it, fails to
+// compile. A failed transition drops (destroys) the QP.
+use std::io::Error as IOError;
+use std::marker::PhantomData;
+
+use crate::ffi;
+use crate::ibv::{
+    IbvEndpoint, IbvMtu, IbvPd, IbvQp, IbvQpAttr, IbvQpBuilder, IbvRecvWr, IbvSendWr, QpAttrMask,
+};
+
+pub struct Reset;
+pub struct Init;
+pub struct Rtr;
+pub struct Rts;
+pub struct Error;
+
+mod sealed {
+    pub trait Sealed {}
+    impl Sealed for super::Reset {}
+    impl Sealed for super::Init {}
+    impl Sealed for super::Rtr {}
+    impl Sealed for super::Rts {}
+    impl Sealed for super::Error {}
+}
+
+pub trait QpState: sealed::Sealed {}
+impl<S: sealed::Sealed> QpState for S {}
+
+// States in which receives may be posted.
+pub trait CanRecv: QpState {}
+impl CanRecv for Init {}
+impl CanRecv for Rtr {}
+impl CanRecv for Rts {}
+
+pub struct Qp<S: QpState> {
+    qp: IbvQp,
+    _state: PhantomData<S>,
+}
+
+impl<S: QpState> Qp<S> {
+    fn into_state<T: QpState>(self) -> Qp<T> {
+        Qp {
+            qp: self.qp,
+            _state: PhantomData,
+        }
+    }
+    fn modify_state(&self, qp_state: ffi::ibv_qp_state::Type) -> Result<(), IOError> {
+        let mut qp_attr = unsafe { std::mem::zeroed::<IbvQpAttr>() };
+        qp_attr.qp_state = qp_state;
+        self.qp.modify(&qp_attr, QpAttrMask::IBV_QP_STATE)
+    }
+    // Read-only access for queries; the transitions stay with the typestate.
+    pub fn inner(&self) -> &IbvQp {
+        &self.qp
+    }
+    // Leaves the typestate; the caller takes over tracking the QP's state.
+    pub fn into_inner(self) -> IbvQp {
+        self.qp
+    }
+    #[inline(always)]
+    pub fn qpn(&self) -> u32 {
+        self.qp.qpn()
+    }
+    // Any state can move to ERROR, flushing outstanding work.
+    pub fn into_error(self) -> Result<Qp<Error>, IOError> {
+        self.modify_state(ffi::ibv_qp_state::IBV_QPS_ERR)?;
+        Ok(self.into_state())
+    }
+}
+
+impl Qp<Reset> {
+    pub fn new(builder: IbvQpBuilder, pd: &IbvPd) -> Result<Self, IOError> {
+        Ok(Qp {
+            qp: builder.build(pd)?,
+            _state: PhantomData,
+        })
+    }
+    pub fn init(self, port_num: u8) -> Result<Qp<Init>, IOError> {
+        self.qp.modify_reset2init(port_num)?;
+        Ok(self.into_state())
+    }
+}
+
+impl Qp<Init> {
+    pub fn rtr(self, port_num: u8, remote: &IbvEndpoint, mtu: IbvMtu) -> Result<Qp<Rtr>, IOError> {
+        self.qp
+            .modify_init2rtr_with_mtu(0, port_num, remote.qpn, remote.psn, remote.lid, mtu)?;
+        Ok(self.into_state())
+    }
+}
+
+impl Qp<Rtr> {
+    pub fn rts(self, psn: u32) -> Result<Qp<Rts>, IOError> {
+        self.qp.modify_rtr2rts(psn)?;
+        Ok(self.into_state())
+    }
+}
+
+impl Qp<Rts> {
+    pub fn post_send(
+        &self,
+        wr: &IbvSendWr,
+        bad_wr: *const *const IbvSendWr,
+    ) -> Result<(), IOError> {
+        self.qp.post_send(wr, bad_wr)
+    }
+}
+
+impl<S: CanRecv> Qp<S> {
+    pub fn post_recv(
+        &self,
+        wr: &IbvRecvWr,
+        bad_wr: *const *const IbvRecvWr,
+    ) -> Result<(), IOError> {
+        self.qp.post_recv(wr, bad_wr)
+    }
+}
+
+impl Qp<Error> {
+    pub fn reset(self) -> Result<Qp<Reset>, IOError> {
+        self.modify_state(ffi::ibv_qp_state::IBV_QPS_RESET)?;
+        Ok(self.into_state())
+    }
+}
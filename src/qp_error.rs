@@ -0,0 +1,99 @@
+// Explaining QP fatals. IBV_EVENT_QP_FATAL and the other QP error events only say
+// that a QP failed; the cause is in the status of the error completion just
+// before it. QpErrorContext remembers the first error completion per QP so the
+// event can be reported together with it.
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::ffi;
+use crate::ibv::{AccessError, IbvAsyncEvent, IbvWc};
+
+#[derive(Clone, Copy, Debug)]
+pub struct ErrorCompletion {
+    pub wr_id: u64,
+    pub status: ffi::ibv_wc_status::Type,
+    pub status_str: &'static str,
+    pub vendor_err: u32,
+}
+
+// A QP error event with the completion that most likely caused it.
+#[derive(Clone, Copy, Debug)]
+pub struct QpFatal {
+    pub qp_num: u32,
+    pub event_type: ffi::ibv_event_type::Type,
+    pub event_str: &'static str,
+    pub cause: Option<ErrorCompletion>,
+}
+
+impl fmt::Display for QpFatal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "QP {:#x} {}", self.qp_num, self.event_str)?;
+        match self.cause {
+            None => write!(f, " with no error completion seen"),
+            Some(cause) => {
+                write!(
+                    f,
+                    " following {} on wr_id {} (vendor_err {:#x})",
+                    cause.status_str, cause.wr_id, cause.vendor_err
+                )?;
+                match AccessError::from_status(cause.status) {
+                    Some(err) => write!(f, ": {}", err.hint()),
+                    None => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct QpErrorContext {
+    // First error completion per QP number.
+    causes: Mutex<HashMap<u32, ErrorCompletion>>,
+}
+
+impl QpErrorContext {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // Feed every completion polled from the QPs' CQs through here. Only the first
+    // error per QP is kept: the flush errors for the rest of its WRs come after the
+    // real cause and say nothing about it.
+    pub fn record(&self, wc: &IbvWc) {
+        if wc.status == ffi::ibv_wc_status::IBV_WC_SUCCESS {
+            return;
+        }
+        self.causes
+            .lock()
+            .unwrap()
+            .entry(wc.qp_num)
+            .or_insert(ErrorCompletion {
+                wr_id: wc.wr_id,
+                status: wc.status,
+                status_str: wc.status_str(),
+                vendor_err: wc.vendor_err,
+            });
+    }
+    // The diagnostic for a QP error event, None for other events. The event may
+    // arrive before its completion is polled; drain the CQs first for the best
+    // chance of a cause.
+    pub fn on_event(&self, event: &IbvAsyncEvent) -> Option<QpFatal> {
+        match event.event_type {
+            ffi::ibv_event_type::IBV_EVENT_QP_FATAL
+            | ffi::ibv_event_type::IBV_EVENT_QP_REQ_ERR
+            | ffi::ibv_event_type::IBV_EVENT_QP_ACCESS_ERR => {}
+            _ => return None,
+        }
+        let qp_num = unsafe { event.qp()?.as_ref().qp_num };
+        Some(QpFatal {
+            qp_num,
+            event_type: event.event_type,
+            event_str: event.event_type_str(),
+            cause: self.causes.lock().unwrap().get(&qp_num).copied(),
+        })
+    }
+    // Forgets a QP's cause, e.g. once it has been reset and reconnected.
+    pub fn clear(&self, qp_num: u32) {
+        self.causes.lock().unwrap().remove(&qp_num);
+    }
+}
@@ -0,0 +1,298 @@
+// The batteries-included entry point: RdmaChannelBuilder opens a device, connects
+// to a peer over a TCP bootstrap and returns an RdmaChannel with two-sided
+// send/recv and one-sided read/write against the peer's buffer. Everything it is
+// built from (TcpRdmaConnector, RecvManager, IbvQp, ...) stays public; reach it
+// through `qp`, `pd` and `context` when the defaults don't fit.
+use std::io::{Error as IOError, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::c_void;
+
+use crate::ffi;
+use crate::ibv::{
+    IbvAccess, IbvContext, IbvCq, IbvMr, IbvPd, IbvQp, IbvQpBuilder, IbvSge, IbvWc, PostedWr,
+    RecvManager, RemoteToken,
+};
+use crate::tcp::{TcpConnectParams, TcpRdmaConnector};
+
+#[derive(Clone, Debug)]
+pub struct RdmaChannelBuilder {
+    // None opens the first device.
+    device: Option<String>,
+    port_num: u8,
+    max_msg_size: u32,
+    recv_depth: usize,
+    buffer_size: usize,
+    timeout: Duration,
+    params: TcpConnectParams,
+}
+
+impl Default for RdmaChannelBuilder {
+    fn default() -> Self {
+        Self {
+            device: None,
+            port_num: 1,
+            max_msg_size: 4096,
+            recv_depth: 64,
+            buffer_size: 1 << 20,
+            timeout: Duration::from_secs(10),
+            params: TcpConnectParams::default(),
+        }
+    }
+}
+
+impl RdmaChannelBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn device(mut self, name: &str) -> Self {
+        self.device = Some(name.to_string());
+        self
+    }
+    pub fn port_num(mut self, port_num: u8) -> Self {
+        self.port_num = port_num;
+        self
+    }
+    // Largest message `send` accepts; the peer must use the same or a smaller one.
+    pub fn max_msg_size(mut self, max_msg_size: u32) -> Self {
+        self.max_msg_size = max_msg_size;
+        self
+    }
+    // Receives kept posted; the peer must not have more sends than this in flight.
+    pub fn recv_depth(mut self, recv_depth: usize) -> Self {
+        self.recv_depth = recv_depth;
+        self
+    }
+    // Size of the buffer the peer can read and write.
+    pub fn buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+    // How long an operation waits for its completion and `recv` for a message.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    pub fn tcp_params(mut self, params: TcpConnectParams) -> Self {
+        self.params = params;
+        self
+    }
+    // Client side: connects to the peer's `listen` or `accept` at `addr`.
+    pub fn connect<A: ToSocketAddrs>(self, addr: A) -> Result<RdmaChannel, IOError> {
+        self.establish(|connector, pd, qp| connector.connect_stream(addr, pd, qp))
+    }
+    // Server side: binds `addr` and waits for one peer to `connect`.
+    pub fn listen<A: ToSocketAddrs>(self, addr: A) -> Result<RdmaChannel, IOError> {
+        let listener = TcpListener::bind(addr)?;
+        self.accept(&listener)
+    }
+    // Like `listen`, on a listener the caller keeps, e.g. to accept several peers.
+    pub fn accept(self, listener: &TcpListener) -> Result<RdmaChannel, IOError> {
+        self.establish(|connector, pd, qp| connector.accept_stream(listener, pd, qp))
+    }
+    fn establish<F>(self, handshake: F) -> Result<RdmaChannel, IOError>
+    where
+        F: FnOnce(&TcpRdmaConnector, &IbvPd, IbvQpBuilder) -> Result<(IbvQp, TcpStream), IOError>,
+    {
+        let context = IbvContext::new(self.device.as_deref()).map_err(|err| {
+            IOError::new(
+                ErrorKind::NotFound,
+                format!(
+                    "cannot open device {}: {}",
+                    self.device.as_deref().unwrap_or("<first>"),
+                    err
+                ),
+            )
+        })?;
+        let pd = IbvPd::new(&context)?;
+        let depth = self.recv_depth.max(1);
+        let send_cq = IbvCq::new::<c_void>(&context, 16, None, None, 0)?;
+        let recv_cq = IbvCq::new::<c_void>(&context, depth as i32, None, None, 0)?;
+        let builder = IbvQpBuilder::new(&send_cq, &recv_cq)
+            .max_send_wr(1)
+            .max_recv_wr(depth as u32);
+        let connector = TcpRdmaConnector::new(&context, self.port_num, self.params);
+        let (qp, mut stream) = handshake(&connector, &pd, builder)?;
+        let recv = RecvManager::new(&pd, &qp, depth, self.max_msg_size)?;
+        let send_buf = vec![0_u8; self.max_msg_size as usize].into_boxed_slice();
+        let send_mr = IbvMr::new(&pd, &send_buf, IbvAccess::local())?;
+        let buf = vec![0_u8; self.buffer_size].into_boxed_slice();
+        let buf_mr = IbvMr::new(&pd, &buf, self.params.access)?;
+
+        // The receives above are posted before our token goes out, so the peer
+        // holding it may send right away.
+        stream.write_all(&buf_mr.remote_token().to_wire())?;
+        let mut wire = [0_u8; RemoteToken::WIRE_SIZE];
+        stream.read_exact(&mut wire)?;
+        Ok(RdmaChannel {
+            qp,
+            recv,
+            send_mr,
+            buf_mr,
+            send_cq,
+            recv_cq,
+            pd,
+            context,
+            send_buf,
+            buf,
+            remote: RemoteToken::from_wire(&wire),
+            max_msg_size: self.max_msg_size,
+            timeout: self.timeout,
+            next_wr_id: AtomicU64::new(0),
+        })
+    }
+}
+
+pub struct RdmaChannel {
+    // Field order matters: QP, MRs, CQs, PD, then the context, and the buffers only
+    // once their MRs are deregistered.
+    qp: IbvQp,
+    recv: RecvManager,
+    send_mr: IbvMr,
+    buf_mr: IbvMr,
+    send_cq: IbvCq,
+    recv_cq: IbvCq,
+    pd: IbvPd,
+    context: IbvContext,
+    send_buf: Box<[u8]>,
+    buf: Box<[u8]>,
+    remote: RemoteToken,
+    max_msg_size: u32,
+    timeout: Duration,
+    // Each operation gets its own wr_id, so the completion of one that timed out
+    // isn't taken for a later one's.
+    next_wr_id: AtomicU64,
+}
+
+impl RdmaChannel {
+    // Sends `msg` and waits until the NIC reports it delivered.
+    pub fn send(&mut self, msg: &[u8]) -> Result<(), IOError> {
+        if msg.len() > self.max_msg_size as usize {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes exceeds max_msg_size {}",
+                    msg.len(),
+                    self.max_msg_size
+                ),
+            ));
+        }
+        self.send_buf[..msg.len()].copy_from_slice(msg);
+        let sge = self.send_mr.sge(self.send_mr.addr(), msg.len() as u32)?;
+        self.post_and_wait(PostedWr::send(0, vec![sge]))
+    }
+    // Blocks until the next message arrives; its receive is reposted, also when
+    // the completion failed.
+    pub fn recv(&self) -> Result<Vec<u8>, IOError> {
+        let wc = self.wait_one(&self.recv_cq, Instant::now() + self.timeout)?;
+        self.recv.on_recv(&self.qp, &wc, |data| data.to_vec())
+    }
+    // Writes `len` bytes of the local buffer at `offset` to the peer's buffer at
+    // `remote_offset` and waits for the write to complete.
+    pub fn write(&self, offset: usize, remote_offset: u64, len: usize) -> Result<(), IOError> {
+        let (sge, remote) = self.one_sided(offset, remote_offset, len)?;
+        self.post_and_wait(PostedWr::write(0, vec![sge], &remote))
+    }
+    // Reads `len` bytes of the peer's buffer at `remote_offset` into the local
+    // buffer at `offset`.
+    pub fn read(&mut self, offset: usize, remote_offset: u64, len: usize) -> Result<(), IOError> {
+        let (sge, remote) = self.one_sided(offset, remote_offset, len)?;
+        self.post_and_wait(PostedWr::read(0, vec![sge], &remote))
+    }
+    // The local buffer: the source of `write`, the target of `read`, and what the
+    // peer reads and writes.
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+    pub fn buffer_mut(&mut self) -> &mut [u8] {
+        &mut self.buf
+    }
+    // The peer's buffer.
+    #[inline(always)]
+    pub fn remote(&self) -> RemoteToken {
+        self.remote
+    }
+    #[inline(always)]
+    pub fn qp(&self) -> &IbvQp {
+        &self.qp
+    }
+    #[inline(always)]
+    pub fn pd(&self) -> &IbvPd {
+        &self.pd
+    }
+    #[inline(always)]
+    pub fn context(&self) -> &IbvContext {
+        &self.context
+    }
+    fn one_sided(
+        &self,
+        offset: usize,
+        remote_offset: u64,
+        len: usize,
+    ) -> Result<(IbvSge, RemoteToken), IOError> {
+        let fits = remote_offset
+            .checked_add(len as u64)
+            .is_some_and(|end| end <= self.remote.length);
+        if !fits {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} bytes at offset {} exceed the peer's {} byte buffer",
+                    len, remote_offset, self.remote.length
+                ),
+            ));
+        }
+        let len32 = u32::try_from(len).map_err(|_| {
+            IOError::new(
+                ErrorKind::InvalidInput,
+                format!("{} bytes exceed one WR's 4 GiB limit", len),
+            )
+        })?;
+        let sge = self.buf_mr.sge(self.buf_mr.addr() + offset as u64, len32)?;
+        let remote = RemoteToken {
+            addr: self.remote.addr + remote_offset,
+            rkey: self.remote.rkey,
+            length: len as u64,
+        };
+        Ok((sge, remote))
+    }
+    fn post_and_wait(&self, mut wr: PostedWr) -> Result<(), IOError> {
+        let wr_id = self.next_wr_id.fetch_add(1, Ordering::Relaxed);
+        wr.set_wr_id(wr_id);
+        self.qp.post(&wr)?;
+        let deadline = Instant::now() + self.timeout;
+        let wc = loop {
+            let wc = self.wait_one(&self.send_cq, deadline)?;
+            if wc.wr_id == wr_id {
+                break wc;
+            }
+        };
+        if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS {
+            return Err(IOError::other(format!(
+                "work request failed: {}",
+                wc.status_str()
+            )));
+        }
+        Ok(())
+    }
+    fn wait_one(&self, cq: &IbvCq, deadline: Instant) -> Result<IbvWc, IOError> {
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }];
+        loop {
+            let n = cq
+                .poll(&mut wcs)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?
+                .len();
+            if n > 0 {
+                return Ok(wcs[0]);
+            }
+            if Instant::now() >= deadline {
+                return Err(IOError::new(
+                    ErrorKind::TimedOut,
+                    format!("no completion within {:?}", self.timeout),
+                ));
+            }
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Surviving a device reset. A firmware update or driver reload raises
+// IBV_EVENT_DEVICE_FATAL, after which the context and everything created on it
+// are dead. ReopenableContext keeps the application's resources (PDs, CQs, QPs,
+// MRs, whatever `S` holds) together with the context, so they can all be torn
+// down and rebuilt on a fresh context for the same device.
+use std::io::{Error as IOError, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::ibv::IbvContext;
+
+type Rebuild<S> = Box<dyn FnMut(&IbvContext) -> Result<S, IOError> + Send>;
+
+pub struct ReopenableContext<S> {
+    // Declared before the context so it is dropped first.
+    state: Option<S>,
+    context: Option<IbvContext>,
+    node_guid: u64,
+    rebuild: Rebuild<S>,
+}
+
+impl<S> ReopenableContext<S> {
+    // Builds the initial state with `rebuild`, which runs again on every reopen and
+    // must recreate everything the application needs on the new context.
+    pub fn new<F>(context: IbvContext, mut rebuild: F) -> Result<Self, IOError>
+    where
+        F: FnMut(&IbvContext) -> Result<S, IOError> + Send + 'static,
+    {
+        let state = rebuild(&context)?;
+        Ok(Self {
+            state: Some(state),
+            node_guid: context.node_guid(),
+            context: Some(context),
+            rebuild: Box::new(rebuild),
+        })
+    }
+    // None after a failed `reopen`, until one succeeds.
+    pub fn context(&self) -> Option<&IbvContext> {
+        self.context.as_ref()
+    }
+    pub fn state(&self) -> Option<&S> {
+        self.state.as_ref()
+    }
+    pub fn state_mut(&mut self) -> Option<&mut S> {
+        self.state.as_mut()
+    }
+    #[inline(always)]
+    pub fn node_guid(&self) -> u64 {
+        self.node_guid
+    }
+    // Waits up to `timeout` for an async event and reports whether it was
+    // DEVICE_FATAL. Other events are acknowledged and ignored; use the context
+    // directly to handle those.
+    pub fn wait_fatal(&self, timeout: Duration) -> Result<bool, IOError> {
+        let context = self
+            .context
+            .as_ref()
+            .ok_or_else(|| IOError::new(ErrorKind::NotConnected, "context is closed"))?;
+        Ok(context
+            .wait_async_event(timeout)?
+            .is_some_and(|event| event.is_device_fatal()))
+    }
+    // Drops the state and the context, then reopens the device by node GUID,
+    // retrying until it reappears or `timeout` passes, and rebuilds the state. After
+    // an error the old resources are gone either way; call `reopen` again to retry.
+    pub fn reopen(&mut self, timeout: Duration) -> Result<(), IOError> {
+        self.state = None;
+        self.context = None;
+        let start = Instant::now();
+        let mut delay = Duration::from_millis(10);
+        let context = loop {
+            match IbvContext::open_by_guid(self.node_guid) {
+                Ok(context) => break context,
+                Err(err) if start.elapsed() >= timeout => return Err(err),
+                Err(_) => {
+                    std::thread::sleep(delay);
+                    delay = (delay * 2).min(Duration::from_secs(1));
+                }
+            }
+        };
+        let state = (self.rebuild)(&context)?;
+        self.context = Some(context);
+        self.state = Some(state);
+        Ok(())
+    }
+}
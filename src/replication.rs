@@ -0,0 +1,106 @@
+// Fan-out of one local buffer to N peers, e.g. a log entry to its followers. Each
+// target is a connected RC QP; all of them report to one shared send CQ.
+use std::io::{Error as IOError, ErrorKind};
+
+use crate::error::ReplicationError;
+use crate::ffi;
+use crate::ibv::{IbvCq, IbvQp, IbvSendWr, IbvSge, IbvWc, RemoteToken};
+
+pub struct ReplicationGroup {
+    // The QPs go first so they are destroyed before the CQ they report to.
+    qps: Vec<IbvQp>,
+    send_cq: IbvCq,
+    pending: usize,
+}
+
+impl ReplicationGroup {
+    pub fn new(send_cq: IbvCq, qps: Vec<IbvQp>) -> Result<Self, IOError> {
+        if let Some(i) = qps
+            .iter()
+            .position(|qp| unsafe { (*qp.as_raw()).send_cq } != send_cq.as_mut_ptr())
+        {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!("qp {} does not use the group's send CQ", i),
+            ));
+        }
+        Ok(Self {
+            qps,
+            send_cq,
+            pending: 0,
+        })
+    }
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.qps.len()
+    }
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.qps.is_empty()
+    }
+    pub fn qps(&self) -> &[IbvQp] {
+        &self.qps
+    }
+    // Posts one signaled RDMA write of `local` per QP, `targets[i]` going through
+    // qp i. Call `wait_all` before reusing `local`. If a post fails, the writes
+    // already posted are still waited for by `wait_all`.
+    pub fn write_all(
+        &mut self,
+        local: &IbvSge,
+        targets: &[RemoteToken],
+    ) -> Result<(), ReplicationError> {
+        if targets.len() != self.qps.len() {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "{} targets for a group of {} QPs",
+                    targets.len(),
+                    self.qps.len()
+                ),
+            )
+            .into());
+        }
+        for (i, (qp, target)) in self.qps.iter().zip(targets).enumerate() {
+            let target = target.sge_for(0, local.length as u64)?;
+            let mut sge = *local;
+            let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+            wr.wr_id = i as u64;
+            wr.sg_list = &mut sge;
+            wr.num_sge = 1;
+            wr.opcode = ffi::ibv_wr_opcode::IBV_WR_RDMA_WRITE;
+            wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+            wr.wr.rdma.remote_addr = target.addr;
+            wr.wr.rdma.rkey = target.rkey;
+            let mut bad_wr = std::ptr::null::<IbvSendWr>();
+            qp.post_send(&wr, std::ptr::addr_of_mut!(bad_wr))?;
+            self.pending += 1;
+        }
+        Ok(())
+    }
+    // Waits for every outstanding write. All completions are reaped even after a
+    // failure, so the group is clean afterwards; the first failing target is
+    // reported.
+    pub fn wait_all(&mut self) -> Result<(), ReplicationError> {
+        let mut wcs = vec![unsafe { std::mem::zeroed::<IbvWc>() }; self.qps.len().max(1)];
+        let mut failed = None;
+        while self.pending > 0 {
+            let polled = self
+                .send_cq
+                .poll(&mut wcs)
+                .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+            self.pending -= polled.len().min(self.pending);
+            for wc in polled {
+                if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS && failed.is_none() {
+                    failed = Some(ReplicationError::TargetFailed {
+                        target: wc.wr_id as usize,
+                        status: wc.status_str(),
+                    });
+                }
+            }
+        }
+        match failed {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+}
@@ -0,0 +1,169 @@
+// Locked-memory accounting. Queues and registered regions are pinned and count
+// against RLIMIT_MEMLOCK; running out shows up as a bare ENOMEM from
+// ibv_reg_mr/ibv_create_qp, so check the budget up front instead.
+use std::io::{Error as IOError, ErrorKind};
+
+use crate::ibv::{IbvMr, IbvQpInitAttr};
+
+const PAGE_SIZE: u64 = 4096;
+// Sizes used by mlx5-class providers; other providers are in the same range.
+const CQE_SIZE: u64 = 64;
+const SEND_WQE_BASE: u64 = 64;
+const DATA_SEG_SIZE: u64 = 16;
+// Usage above this share of the limit is reported by `MemlockStatus::near_limit`.
+const NEAR_LIMIT_PERCENT: u64 = 90;
+
+fn page_align(len: u64) -> u64 {
+    (len + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+// Estimates of the memory pinned for a set of QPs, CQs and MRs. The numbers are
+// upper-bound approximations of what the provider allocates, not exact values.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ResourceUsage {
+    qp_bytes: u64,
+    cq_bytes: u64,
+    mr_bytes: u64,
+}
+
+impl ResourceUsage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn qp_bytes_for(attr: &IbvQpInitAttr) -> u64 {
+        let cap = &attr.cap;
+        let send_wqe =
+            (SEND_WQE_BASE + DATA_SEG_SIZE * cap.max_send_sge as u64 + cap.max_inline_data as u64)
+                .next_power_of_two();
+        let recv_wqe = (DATA_SEG_SIZE * cap.max_recv_sge.max(1) as u64).next_power_of_two();
+        let sq = send_wqe * (cap.max_send_wr as u64).next_power_of_two();
+        let rq = recv_wqe * (cap.max_recv_wr as u64).next_power_of_two();
+        // One extra page for the doorbell record.
+        page_align(sq + rq) + PAGE_SIZE
+    }
+    pub fn cq_bytes_for(cqe: i32) -> u64 {
+        page_align(CQE_SIZE * (cqe.max(0) as u64 + 1).next_power_of_two()) + PAGE_SIZE
+    }
+    pub fn mr_bytes_for(length: u64) -> u64 {
+        // An unaligned region can straddle one more page than its length suggests.
+        page_align(length) + PAGE_SIZE
+    }
+    pub fn add_qp(&mut self, attr: &IbvQpInitAttr) -> &mut Self {
+        self.qp_bytes += Self::qp_bytes_for(attr);
+        self
+    }
+    pub fn add_cq(&mut self, cqe: i32) -> &mut Self {
+        self.cq_bytes += Self::cq_bytes_for(cqe);
+        self
+    }
+    pub fn add_mr_len(&mut self, length: u64) -> &mut Self {
+        self.mr_bytes += Self::mr_bytes_for(length);
+        self
+    }
+    pub fn add_mr(&mut self, mr: &IbvMr) -> &mut Self {
+        self.add_mr_len(mr.length())
+    }
+    #[inline(always)]
+    pub fn qp_bytes(&self) -> u64 {
+        self.qp_bytes
+    }
+    #[inline(always)]
+    pub fn cq_bytes(&self) -> u64 {
+        self.cq_bytes
+    }
+    #[inline(always)]
+    pub fn mr_bytes(&self) -> u64 {
+        self.mr_bytes
+    }
+    #[inline(always)]
+    pub fn locked_bytes(&self) -> u64 {
+        self.qp_bytes + self.cq_bytes + self.mr_bytes
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemlockStatus {
+    // None when the limit is RLIM_INFINITY.
+    pub limit: Option<u64>,
+    // Memory already pinned or mlocked by this process.
+    pub locked: u64,
+    pub requested: u64,
+}
+
+impl MemlockStatus {
+    pub fn would_exceed(&self) -> bool {
+        match self.limit {
+            Some(limit) => self.locked + self.requested > limit,
+            None => false,
+        }
+    }
+    pub fn near_limit(&self) -> bool {
+        match self.limit {
+            Some(limit) => (self.locked + self.requested) * 100 >= limit * NEAR_LIMIT_PERCENT,
+            None => false,
+        }
+    }
+}
+
+// Soft RLIMIT_MEMLOCK in bytes, None when unlimited.
+pub fn memlock_limit() -> Result<Option<u64>, IOError> {
+    let mut rlim = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlim) } != 0 {
+        return Err(IOError::last_os_error());
+    }
+    if rlim.rlim_cur == libc::RLIM_INFINITY {
+        Ok(None)
+    } else {
+        // rlim_t is only u64 on 64-bit targets.
+        #[allow(clippy::unnecessary_cast)]
+        Ok(Some(rlim.rlim_cur as u64))
+    }
+}
+
+// VmLck + VmPin from /proc/self/status; verbs registrations are accounted as VmPin.
+fn locked_bytes() -> Result<u64, IOError> {
+    let status = std::fs::read_to_string("/proc/self/status")?;
+    let mut total = 0;
+    for line in status.lines() {
+        if let Some(rest) = line
+            .strip_prefix("VmLck:")
+            .or_else(|| line.strip_prefix("VmPin:"))
+        {
+            let kb = rest
+                .trim()
+                .trim_end_matches("kB")
+                .trim()
+                .parse::<u64>()
+                .map_err(|e| IOError::new(ErrorKind::InvalidData, e))?;
+            total += kb * 1024;
+        }
+    }
+    Ok(total)
+}
+
+// Checks whether pinning `additional` more bytes (see `ResourceUsage::locked_bytes`)
+// stays within `ulimit -l`. Fails with OutOfMemory when it would not; otherwise the
+// returned status tells whether the process is getting close.
+pub fn check_memlock_limit(additional: u64) -> Result<MemlockStatus, IOError> {
+    let status = MemlockStatus {
+        limit: memlock_limit()?,
+        locked: locked_bytes()?,
+        requested: additional,
+    };
+    if status.would_exceed() {
+        return Err(IOError::new(
+            ErrorKind::OutOfMemory,
+            format!(
+                "pinning {} more bytes on top of {} would exceed RLIMIT_MEMLOCK ({} bytes); \
+                 raise `ulimit -l` or register less memory",
+                status.requested,
+                status.locked,
+                status.limit.unwrap_or(0)
+            ),
+        ));
+    }
+    Ok(status)
+}
@@ -0,0 +1,252 @@
+// RoCEv2 congestion control (DCQCN) settings as the driver exposes them. ECN
+// marking is switched per priority on the notification point (the receiver, which
+// turns marked packets into CNPs) and the reaction point (the sender, which slows
+// down on CNPs); both must be on for DCQCN to work. Layout as mlx5 uses it:
+// /sys/class/net/<netdev>/ecn/roce_{np,rp}/ and, with debugfs mounted and
+// readable, /sys/kernel/debug/mlx5/<pci>/cc_params/. `configure_lossless` lines a
+// QP's DSCP up with them and with the PFC settings under /sys/class/net/<netdev>/qos/.
+use std::collections::BTreeMap;
+use std::io::{Error as IOError, ErrorKind};
+use std::path::Path;
+
+use crate::ffi;
+use crate::ibv::{dscp_to_traffic_class, IbvContext, IbvQp};
+
+pub const NUM_PRIORITIES: usize = 8;
+
+#[derive(Debug, Clone, Default)]
+pub struct RoceCcParams {
+    pub netdev: String,
+    // Indexed by priority; None where the driver has no such entry.
+    pub np_enabled: [Option<bool>; NUM_PRIORITIES],
+    pub rp_enabled: [Option<bool>; NUM_PRIORITIES],
+    // The numeric entries besides the enables, e.g. "roce_np/cnp_dscp" or
+    // "cc_params/rp_ai_rate" (rate-limit parameters, debugfs only on mlx5).
+    pub params: BTreeMap<String, u64>,
+}
+
+impl RoceCcParams {
+    // Whether ECN is enabled on both ends for `prio`.
+    pub fn ecn_enabled(&self, prio: usize) -> bool {
+        prio < NUM_PRIORITIES
+            && self.np_enabled[prio] == Some(true)
+            && self.rp_enabled[prio] == Some(true)
+    }
+    // Priorities with ECN enabled on one side but not the other, the usual
+    // misconfiguration.
+    pub fn half_enabled(&self) -> Vec<usize> {
+        (0..NUM_PRIORITIES)
+            .filter(|&prio| {
+                self.np_enabled[prio].unwrap_or(false) != self.rp_enabled[prio].unwrap_or(false)
+            })
+            .collect()
+    }
+}
+
+fn read_u64(path: &Path) -> Option<u64> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+fn read_enables(dir: &Path) -> [Option<bool>; NUM_PRIORITIES] {
+    let mut enabled = [None; NUM_PRIORITIES];
+    for (prio, slot) in enabled.iter_mut().enumerate() {
+        *slot = read_u64(&dir.join(prio.to_string())).map(|v| v != 0);
+    }
+    enabled
+}
+
+// Adds the numeric files directly in `dir` to `params`, as "<prefix>/<name>".
+// Unreadable entries (debugfs is root-only) are left out.
+fn read_params(dir: &Path, prefix: &str, params: &mut BTreeMap<String, u64>) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        if !path.is_file() {
+            continue;
+        }
+        if let Some(value) = read_u64(&path) {
+            params.insert(
+                format!("{}/{}", prefix, entry.file_name().to_string_lossy()),
+                value,
+            );
+        }
+    }
+}
+
+// The netdev of `port_num`, from the port's GID attributes, falling back to the
+// device's first netdev.
+fn port_netdev(context: &IbvContext, port_num: u8) -> Option<String> {
+    std::fs::read_to_string(format!(
+        "{}/ports/{}/gid_attrs/ndevs/0",
+        context.ibdev_path(),
+        port_num
+    ))
+    .ok()
+    .map(|name| name.trim().to_string())
+    .filter(|name| !name.is_empty())
+    .or_else(|| context.netdevs().into_iter().next())
+}
+
+// Reads the ECN settings of `port_num`. Fails with NotFound on ports without a
+// netdev (InfiniBand) or drivers without the ecn directory.
+pub fn read_roce_cc_params(context: &IbvContext, port_num: u8) -> Result<RoceCcParams, IOError> {
+    let netdev = port_netdev(context, port_num).ok_or_else(|| {
+        IOError::new(
+            ErrorKind::NotFound,
+            format!("port {} has no network interface", port_num),
+        )
+    })?;
+    let ecn = Path::new("/sys/class/net").join(&netdev).join("ecn");
+    if !ecn.is_dir() {
+        return Err(IOError::new(
+            ErrorKind::NotFound,
+            format!("{} exposes no ECN settings", netdev),
+        ));
+    }
+    let mut params = RoceCcParams {
+        np_enabled: read_enables(&ecn.join("roce_np/enable")),
+        rp_enabled: read_enables(&ecn.join("roce_rp/enable")),
+        netdev,
+        ..Default::default()
+    };
+    read_params(&ecn.join("roce_np"), "roce_np", &mut params.params);
+    read_params(&ecn.join("roce_rp"), "roce_rp", &mut params.params);
+    if let Some(pci) = context.pci_address() {
+        let debugfs = Path::new("/sys/kernel/debug/mlx5")
+            .join(pci)
+            .join("cc_params");
+        read_params(&debugfs, "cc_params", &mut params.params);
+    }
+    Ok(params)
+}
+
+// What `configure_lossless` found and did. `warnings` lists every setting that
+// doesn't line up; empty means the path should be lossless.
+#[derive(Debug, Clone)]
+pub struct LosslessReport {
+    pub dscp: u8,
+    pub traffic_class: u8,
+    // The priority the port maps `dscp` to.
+    pub priority: usize,
+    // None where the driver doesn't expose the setting in sysfs.
+    pub trust_dscp: Option<bool>,
+    pub pfc_enabled: Option<bool>,
+    pub ecn_enabled: bool,
+    // Whether the QP's address vector was updated; see `configure_lossless`.
+    pub applied: bool,
+    pub warnings: Vec<String>,
+}
+
+// The port's DSCP to priority table from qos/dscp2prio ("<dscp> <prio>" per line,
+// other text ignored), or the kernel default of dscp / 8.
+fn dscp_priority(qos: &Path, dscp: u8) -> usize {
+    let table = std::fs::read_to_string(qos.join("dscp2prio")).unwrap_or_default();
+    for line in table.lines() {
+        let nums: Vec<usize> = line
+            .split(|c: char| !c.is_ascii_digit())
+            .filter_map(|n| n.parse().ok())
+            .collect();
+        if let [d, prio] = nums[..] {
+            if d == dscp as usize && prio < NUM_PRIORITIES {
+                return prio;
+            }
+        }
+    }
+    (dscp >> 3) as usize
+}
+
+// Per-priority PFC enables from qos/pfc, eight 0/1 values.
+fn read_pfc(qos: &Path) -> Option<[bool; NUM_PRIORITIES]> {
+    let text = std::fs::read_to_string(qos.join("pfc")).ok()?;
+    let flags: Vec<bool> = text
+        .split(|c: char| !c.is_ascii_digit())
+        .filter(|f| !f.is_empty())
+        .map(|f| f != "0")
+        .collect();
+    flags.try_into().ok()
+}
+
+// Checks that traffic sent with `dscp` on `port_num` is lossless: the port trusts
+// DSCP, PFC is on for the priority the DSCP maps to, and ECN is enabled there for
+// DCQCN. A QP in SQD gets the DSCP written into its address vector; for a QP
+// still in RESET or INIT pass `report.traffic_class` to the RTR transition
+// (e.g. modify_init2rtr_global) instead. Other states are rejected, since the
+// traffic class can't change outside SQD.
+pub fn configure_lossless(
+    context: &IbvContext,
+    qp: &IbvQp,
+    dscp: u8,
+    port_num: u8,
+) -> Result<LosslessReport, IOError> {
+    if dscp > 63 {
+        return Err(IOError::new(
+            ErrorKind::InvalidInput,
+            format!("DSCP {} out of range 0..=63", dscp),
+        ));
+    }
+    let params = read_roce_cc_params(context, port_num)?;
+    let qos = Path::new("/sys/class/net").join(&params.netdev).join("qos");
+    let priority = dscp_priority(&qos, dscp);
+    let trust_dscp = std::fs::read_to_string(qos.join("trust"))
+        .ok()
+        .map(|trust| trust.trim() == "dscp");
+    let pfc_enabled = read_pfc(&qos).map(|pfc| pfc[priority]);
+    let mut report = LosslessReport {
+        dscp,
+        traffic_class: dscp_to_traffic_class(dscp),
+        priority,
+        trust_dscp,
+        pfc_enabled,
+        ecn_enabled: params.ecn_enabled(priority),
+        applied: false,
+        warnings: Vec::new(),
+    };
+    if trust_dscp == Some(false) {
+        report.warnings.push(format!(
+            "{} trusts PCP, not DSCP: the DSCP {} marking won't select priority {}",
+            params.netdev, dscp, priority
+        ));
+    }
+    match pfc_enabled {
+        Some(false) => report.warnings.push(format!(
+            "PFC is disabled for priority {} (DSCP {}) on {}; drops under congestion",
+            priority, dscp, params.netdev
+        )),
+        None => report.warnings.push(format!(
+            "{} doesn't expose PFC settings in sysfs; check priority {} with the DCB tools",
+            params.netdev, priority
+        )),
+        Some(true) => {}
+    }
+    if !report.ecn_enabled {
+        report.warnings.push(format!(
+            "ECN is not enabled on both ends for priority {} on {}; DCQCN is off",
+            priority, params.netdev
+        ));
+    }
+    let (qp_attr, _) =
+        qp.query(ffi::ibv_qp_attr_mask::IBV_QP_STATE.0 | ffi::ibv_qp_attr_mask::IBV_QP_AV.0)?;
+    match qp_attr.qp_state {
+        ffi::ibv_qp_state::IBV_QPS_RESET | ffi::ibv_qp_state::IBV_QPS_INIT => {}
+        ffi::ibv_qp_state::IBV_QPS_SQD => {
+            let mut ah_attr = qp_attr.ah_attr;
+            ah_attr.grh.traffic_class = report.traffic_class;
+            qp.update_av(&ah_attr)?;
+            report.applied = true;
+        }
+        state => {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "QP {} is in state {}: set the traffic class at RTR, or move it to SQD first",
+                    qp.qpn(),
+                    state
+                ),
+            ))
+        }
+    }
+    Ok(report)
+}
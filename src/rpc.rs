@@ -0,0 +1,346 @@
+// Request/reply over one RC QP. Each call gets a correlation id that travels as the
+// immediate of its SEND_WITH_IMM; the peer answers with the same id and the reply
+// bit set, so replies can come back in any order. Both sides can call and serve on
+// the same channel. Like MessageChannel, the peer must not have more messages in
+// flight than `recv_depth`.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io::{Error as IOError, ErrorKind};
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use libc::c_void;
+
+use crate::ffi;
+use crate::ibv::{
+    IbvAccess, IbvContext, IbvCq, IbvEndpoint, IbvMr, IbvMtu, IbvPd, IbvQp, IbvQpBuilder,
+    IbvSendWr, IbvWc, RecvManager,
+};
+
+// Set in the immediate of replies; the low 31 bits are the correlation id.
+const REPLY_BIT: u32 = 1 << 31;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RpcOpts {
+    pub port_num: u8,
+    pub max_msg_size: u32,
+    // Receives kept posted.
+    pub recv_depth: usize,
+    // Requests and replies this side may have unacknowledged by the NIC at once.
+    pub max_in_flight: usize,
+    pub timeout: Duration,
+}
+
+impl Default for RpcOpts {
+    fn default() -> Self {
+        Self {
+            port_num: 1,
+            max_msg_size: 4096,
+            recv_depth: 64,
+            max_in_flight: 16,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+struct RpcState {
+    next_id: u32,
+    // Send buffer slots not used by a send in flight; the slot index is the wr_id.
+    free_slots: Vec<usize>,
+    send_buf: Box<[u8]>,
+    // Calls waiting for a reply, and replies not collected yet.
+    outstanding: HashSet<u32>,
+    replies: HashMap<u32, Vec<u8>>,
+    requests: VecDeque<(u32, Vec<u8>)>,
+    // Messages from the peer without a correlation id, reported by `next_request`.
+    bad_requests: usize,
+    // Set by the first failed completion or poll. The QP is in error by then, so
+    // every call, request and post after it fails with the same error.
+    failed: Option<(ErrorKind, String)>,
+}
+
+impl RpcState {
+    fn fail(&mut self, err: IOError) -> IOError {
+        self.failed.get_or_insert((err.kind(), err.to_string()));
+        err
+    }
+    fn failure(&self) -> Option<IOError> {
+        self.failed
+            .as_ref()
+            .map(|(kind, msg)| IOError::new(*kind, format!("channel failed: {}", msg)))
+    }
+}
+
+pub struct RpcChannel {
+    // Field order matters: the QP has to be destroyed before its CQs.
+    qp: IbvQp,
+    recv: RecvManager,
+    send_mr: IbvMr,
+    state: Mutex<RpcState>,
+    send_cq: IbvCq,
+    recv_cq: IbvCq,
+    port_num: u8,
+    max_msg_size: u32,
+    timeout: Duration,
+}
+
+impl RpcChannel {
+    // Creates the channel with its QP in INIT and the receive pool posted. Exchange
+    // `local_endpoint` with the peer, then call `connect`.
+    pub fn new(context: &IbvContext, pd: &IbvPd, opts: RpcOpts) -> Result<Self, IOError> {
+        let depth = opts.recv_depth.max(1);
+        let in_flight = opts.max_in_flight.max(1);
+        let send_cq = IbvCq::new::<c_void>(context, in_flight as i32, None, None, 0)?;
+        let recv_cq = IbvCq::new::<c_void>(context, depth as i32, None, None, 0)?;
+        let qp = IbvQpBuilder::new(&send_cq, &recv_cq)
+            .max_send_wr(in_flight as u32)
+            .max_recv_wr(depth as u32)
+            .build(pd)?;
+        qp.modify_reset2init(opts.port_num)?;
+        let recv = RecvManager::new(pd, &qp, depth, opts.max_msg_size)?;
+        let send_buf = vec![0_u8; in_flight * opts.max_msg_size as usize].into_boxed_slice();
+        let send_mr = IbvMr::new(pd, &send_buf, IbvAccess::local())?;
+        Ok(Self {
+            qp,
+            recv,
+            send_mr,
+            state: Mutex::new(RpcState {
+                next_id: 0,
+                free_slots: (0..in_flight).rev().collect(),
+                send_buf,
+                outstanding: HashSet::new(),
+                replies: HashMap::new(),
+                requests: VecDeque::new(),
+                bad_requests: 0,
+                failed: None,
+            }),
+            send_cq,
+            recv_cq,
+            port_num: opts.port_num,
+            max_msg_size: opts.max_msg_size,
+            timeout: opts.timeout,
+        })
+    }
+    pub fn local_endpoint(&self, context: &IbvContext, psn: u32) -> Result<IbvEndpoint, IOError> {
+        IbvEndpoint::new(context, &self.qp, self.port_num, psn)
+    }
+    pub fn connect(&self, local: &IbvEndpoint, remote: &IbvEndpoint) -> Result<(), IOError> {
+        let mtu = IbvMtu::Mtu4096.min(local.mtu).min(remote.mtu);
+        self.qp
+            .modify_init2rtr_to(self.port_num, local, remote, mtu)?;
+        self.qp.modify_rtr2rts(local.psn)
+    }
+    // Sends `request` and returns a future resolving to the reply, or to TimedOut
+    // once the channel's timeout passes. A reply arriving after that is dropped.
+    pub fn call(&self, request: &[u8]) -> Result<RpcCall<'_>, IOError> {
+        let id = {
+            let mut state = self.state.lock().unwrap();
+            let id = state.next_id;
+            state.next_id = (id + 1) & !REPLY_BIT;
+            self.post(&mut state, request, id)?;
+            state.outstanding.insert(id);
+            id
+        };
+        Ok(RpcCall {
+            channel: self,
+            id,
+            deadline: Instant::now() + self.timeout,
+        })
+    }
+    // Blocking `call`.
+    pub fn call_blocking(&self, request: &[u8]) -> Result<Vec<u8>, IOError> {
+        let call = self.call(request)?;
+        loop {
+            if let Some(res) = call.try_complete()? {
+                return Ok(res);
+            }
+        }
+    }
+    // The next request from the peer, if one has arrived; answer it with `reply`.
+    // A message the peer sent without a correlation id comes back as InvalidData.
+    pub fn next_request(&self) -> Result<Option<(u32, Vec<u8>)>, IOError> {
+        let mut state = self.state.lock().unwrap();
+        self.progress(&mut state)?;
+        if state.bad_requests > 0 {
+            state.bad_requests -= 1;
+            return Err(IOError::new(
+                ErrorKind::InvalidData,
+                "message without a correlation id",
+            ));
+        }
+        Ok(state.requests.pop_front())
+    }
+    pub fn reply(&self, id: u32, response: &[u8]) -> Result<(), IOError> {
+        let mut state = self.state.lock().unwrap();
+        self.post(&mut state, response, id | REPLY_BIT)
+    }
+    pub fn qp(&self) -> &IbvQp {
+        &self.qp
+    }
+    fn post(&self, state: &mut RpcState, msg: &[u8], imm: u32) -> Result<(), IOError> {
+        if msg.len() > self.max_msg_size as usize {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "message of {} bytes exceeds max_msg_size {}",
+                    msg.len(),
+                    self.max_msg_size
+                ),
+            ));
+        }
+        if let Some(err) = state.failure() {
+            return Err(err);
+        }
+        let deadline = Instant::now() + self.timeout;
+        let slot = loop {
+            if let Some(slot) = state.free_slots.pop() {
+                break slot;
+            }
+            self.reap_sends(state).map_err(|err| state.fail(err))?;
+            if state.free_slots.is_empty() && Instant::now() >= deadline {
+                return Err(IOError::new(
+                    ErrorKind::TimedOut,
+                    "no send slot freed up before the timeout",
+                ));
+            }
+        };
+        let offset = slot * self.max_msg_size as usize;
+        state.send_buf[offset..offset + msg.len()].copy_from_slice(msg);
+        let mut sge = self
+            .send_mr
+            .sge(self.send_mr.addr() + offset as u64, msg.len() as u32)?;
+        let mut wr = unsafe { std::mem::zeroed::<IbvSendWr>() };
+        wr.wr_id = slot as u64;
+        wr.sg_list = &mut sge;
+        wr.num_sge = 1;
+        wr.opcode = ffi::ibv_wr_opcode::IBV_WR_SEND_WITH_IMM;
+        wr.send_flags = ffi::ibv_send_flags::IBV_SEND_SIGNALED.0;
+        wr.set_imm_data(imm);
+        let mut bad_wr = std::ptr::null::<IbvSendWr>();
+        if let Err(err) = self.qp.post_send(&wr, std::ptr::addr_of_mut!(bad_wr)) {
+            state.free_slots.push(slot);
+            return Err(err);
+        }
+        Ok(())
+    }
+    fn reap_sends(&self, state: &mut RpcState) -> Result<(), IOError> {
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 16];
+        let polled = self
+            .send_cq
+            .poll(&mut wcs)
+            .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+        state
+            .free_slots
+            .extend(polled.iter().map(|wc| wc.wr_id as usize));
+        match polled
+            .iter()
+            .find(|wc| wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS)
+        {
+            Some(wc) => Err(IOError::other(format!("send failed: {}", wc.status_str()))),
+            None => Ok(()),
+        }
+    }
+    // Reaps send completions and sorts arrived messages into replies and requests.
+    // Errors here concern the whole channel, see `RpcState::failed`.
+    fn progress(&self, state: &mut RpcState) -> Result<(), IOError> {
+        if let Some(err) = state.failure() {
+            return Err(err);
+        }
+        self.reap_sends(state).map_err(|err| state.fail(err))?;
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 16];
+        let polled = self
+            .recv_cq
+            .poll(&mut wcs)
+            .map_err(|_| state.fail(IOError::other("ibv_poll_cq() failed")))?;
+        // Every completion is handled, and its slot reposted, before the first error
+        // is returned; the rest of the batch would be lost otherwise.
+        let mut first_err = None;
+        for wc in polled {
+            let msg = match self.recv.on_recv(&self.qp, wc, |data| data.to_vec()) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    first_err.get_or_insert(err);
+                    continue;
+                }
+            };
+            let imm = match wc.imm_data() {
+                Some(imm) => imm,
+                None => {
+                    state.bad_requests += 1;
+                    continue;
+                }
+            };
+            let id = imm & !REPLY_BIT;
+            if imm & REPLY_BIT == 0 {
+                state.requests.push_back((id, msg));
+            } else if state.outstanding.contains(&id) {
+                state.replies.insert(id, msg);
+            }
+        }
+        match first_err {
+            Some(err) => Err(state.fail(err)),
+            None => Ok(()),
+        }
+    }
+}
+
+// An outstanding `RpcChannel::call`. Polling it drives the channel's CQs and,
+// while the reply is outstanding, wakes itself right away, busy-polling like
+// CompletionFuture.
+pub struct RpcCall<'a> {
+    channel: &'a RpcChannel,
+    id: u32,
+    deadline: Instant,
+}
+
+impl RpcCall<'_> {
+    #[inline(always)]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+    fn try_complete(&self) -> Result<Option<Vec<u8>>, IOError> {
+        let mut state = self.channel.state.lock().unwrap();
+        let res = self.channel.progress(&mut state);
+        if let Some(reply) = state.replies.remove(&self.id) {
+            state.outstanding.remove(&self.id);
+            return Ok(Some(reply));
+        }
+        if let Err(err) = res {
+            state.outstanding.remove(&self.id);
+            return Err(err);
+        }
+        if Instant::now() >= self.deadline {
+            state.outstanding.remove(&self.id);
+            return Err(IOError::new(
+                ErrorKind::TimedOut,
+                format!("no reply to call {}", self.id),
+            ));
+        }
+        Ok(None)
+    }
+}
+
+impl Future for RpcCall<'_> {
+    type Output = Result<Vec<u8>, IOError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.try_complete() {
+            Ok(Some(reply)) => Poll::Ready(Ok(reply)),
+            Err(err) => Poll::Ready(Err(err)),
+            Ok(None) => {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+// Dropping a call before its reply arrives abandons it; a late reply is dropped.
+impl Drop for RpcCall<'_> {
+    fn drop(&mut self) {
+        let mut state = self.channel.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.outstanding.remove(&self.id);
+        state.replies.remove(&self.id);
+    }
+}
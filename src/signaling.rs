@@ -0,0 +1,145 @@
+// Selective signaling: post most sends unsignaled and only every Nth signaled.
+// An unsignaled WR's send queue slot is only released when a later signaled WR on
+// the same QP completes, so without a signal every so often the SQ fills and
+// posts fail with ENOMEM. SelectiveSignaler decides which WRs to signal and
+// reclaims the slots as their completions come in.
+use std::collections::VecDeque;
+use std::io::{Error as IOError, ErrorKind};
+use std::time::{Duration, Instant};
+
+use crate::ffi;
+use crate::ibv::{IbvCq, IbvQp, IbvWc, PostedWr};
+
+pub struct SelectiveSignaler<'a> {
+    qp: &'a IbvQp,
+    send_cq: &'a IbvCq,
+    interval: u32,
+    max_send_wr: u32,
+    // WRs posted since the last signaled one.
+    unsignaled: u32,
+    // WRs occupying SQ slots, signaled or not.
+    in_flight: u32,
+    // For each signaled WR not completed yet, how many slots its completion frees.
+    batches: VecDeque<u32>,
+    // How long `post` and `wait_signaled` wait for a completion.
+    timeout: Duration,
+}
+
+impl<'a> SelectiveSignaler<'a> {
+    // Signals every `interval`-th send. `send_cq` must be the QP's send CQ and only
+    // receive completions of this QP's sends, since the slot accounting relies on
+    // seeing every one of them in order.
+    pub fn new(qp: &'a IbvQp, send_cq: &'a IbvCq, interval: u32) -> Result<Self, IOError> {
+        let max_send_wr = qp.query_cap()?.max_send_wr;
+        if interval == 0 || interval > max_send_wr {
+            return Err(IOError::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "signal interval {} must be within 1..={} (max_send_wr)",
+                    interval, max_send_wr
+                ),
+            ));
+        }
+        Ok(Self {
+            qp,
+            send_cq,
+            interval,
+            max_send_wr,
+            unsignaled: 0,
+            in_flight: 0,
+            batches: VecDeque::new(),
+            timeout: Duration::from_secs(10),
+        })
+    }
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+    // Posts `wr`, signaled if it is the interval-th since the last signal or the
+    // one filling the SQ, unsignaled otherwise. Blocks reaping completions while
+    // the SQ is full.
+    pub fn post(&mut self, wr: PostedWr) -> Result<(), IOError> {
+        while self.in_flight >= self.max_send_wr {
+            self.wait_one()?;
+        }
+        let signal = self.unsignaled + 1 >= self.interval || self.in_flight + 1 >= self.max_send_wr;
+        let flags = if signal {
+            wr.send_flags().0 | ffi::ibv_send_flags::IBV_SEND_SIGNALED.0
+        } else {
+            wr.send_flags().0 & !ffi::ibv_send_flags::IBV_SEND_SIGNALED.0
+        };
+        let wr = wr.flags(ffi::ibv_send_flags(flags));
+        self.qp.post(&wr)?;
+        self.in_flight += 1;
+        if signal {
+            self.batches.push_back(self.unsignaled + 1);
+            self.unsignaled = 0;
+        } else {
+            self.unsignaled += 1;
+        }
+        Ok(())
+    }
+    // Reaps whatever completions are available without blocking and returns how
+    // many SQ slots that freed. A failed WR is returned as an error, after the rest
+    // of the batch has been accounted for; the QP is in the error state by then.
+    pub fn drain(&mut self) -> Result<u32, IOError> {
+        let mut wcs = [unsafe { std::mem::zeroed::<IbvWc>() }; 16];
+        let polled = self
+            .send_cq
+            .poll(&mut wcs)
+            .map_err(|_| IOError::other("ibv_poll_cq() failed"))?;
+        let mut freed = 0;
+        let mut first_err = None;
+        for wc in polled {
+            // Errors flush every WR, unsignaled ones included, with one completion
+            // each; those don't map to batches any more.
+            if wc.status != ffi::ibv_wc_status::IBV_WC_SUCCESS {
+                first_err.get_or_insert_with(|| {
+                    IOError::other(format!("send {} failed: {}", wc.wr_id, wc.status_str()))
+                });
+                self.in_flight = self.in_flight.saturating_sub(1);
+                freed += 1;
+                continue;
+            }
+            let batch = self.batches.pop_front().unwrap_or(1);
+            self.in_flight = self.in_flight.saturating_sub(batch);
+            freed += batch;
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(freed),
+        }
+    }
+    // Waits for every signaled WR to complete. Unsignaled WRs posted after the last
+    // signal still hold their slots; post a signaled one to retire them too.
+    pub fn wait_signaled(&mut self) -> Result<(), IOError> {
+        while !self.batches.is_empty() {
+            self.wait_one()?;
+        }
+        Ok(())
+    }
+    fn wait_one(&mut self) -> Result<(), IOError> {
+        if self.batches.is_empty() {
+            // Can't happen while `post` signals the WR that fills the SQ.
+            return Err(IOError::other("send queue full with no signaled WR"));
+        }
+        let deadline = Instant::now() + self.timeout;
+        while self.drain()? == 0 {
+            if Instant::now() >= deadline {
+                return Err(IOError::new(
+                    ErrorKind::TimedOut,
+                    format!("no send completion within {:?}", self.timeout),
+                ));
+            }
+        }
+        Ok(())
+    }
+    #[inline(always)]
+    pub fn in_flight(&self) -> u32 {
+        self.in_flight
+    }
+    #[inline(always)]
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+}
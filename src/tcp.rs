@@ -0,0 +1,192 @@
+// RC connection setup over a TCP bootstrap, as most RDMA examples do it: each side
+// creates its QP, the two swap endpoints (QPN, PSN, LID, MTU and, on RoCE, GID)
+// over a TCP socket, drive their QPs to RTS, and sync once more so neither sends
+// before the other can receive. TCP is only used for the handshake.
+use std::io::{Error as IOError, ErrorKind, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::ffi;
+use crate::ibv::{IbvAccess, IbvContext, IbvEndpoint, IbvGid, IbvMtu, IbvPd, IbvQp, IbvQpBuilder};
+
+// "RDMA", so a stray connection from something else fails the handshake cleanly.
+const MAGIC: u32 = 0x5244_4d41;
+const MSG_LEN: usize = 4 + 4 + 4 + 2 + 4 + 4 + 1 + 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TcpConnectParams {
+    // Upper bound for the path MTU; the smaller port MTU wins.
+    pub mtu: IbvMtu,
+    // Remote access the peer gets through the QP.
+    pub access: IbvAccess,
+    // Applies to the TCP handshake only.
+    pub timeout: Duration,
+}
+
+impl Default for TcpConnectParams {
+    fn default() -> Self {
+        Self {
+            mtu: IbvMtu::Mtu4096,
+            access: IbvAccess::remote_read_write(),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// What goes over the wire: the endpoint, with the GID the peer must target on RoCE.
+struct Handshake {
+    endpoint: IbvEndpoint,
+}
+
+impl Handshake {
+    fn encode(&self) -> [u8; MSG_LEN] {
+        let mut buf = [0_u8; MSG_LEN];
+        let ep = &self.endpoint;
+        buf[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+        buf[4..8].copy_from_slice(&ep.qpn.to_be_bytes());
+        buf[8..12].copy_from_slice(&ep.psn.to_be_bytes());
+        buf[12..14].copy_from_slice(&ep.lid.to_be_bytes());
+        buf[14..18].copy_from_slice(&u32::from(ep.mtu).to_be_bytes());
+        buf[18..22].copy_from_slice(&ep.max_msg_sz.to_be_bytes());
+        if let Some(gid) = ep.gid {
+            buf[22] = 1;
+            buf[23..39].copy_from_slice(&gid.octets());
+        }
+        buf
+    }
+    fn decode(buf: &[u8; MSG_LEN]) -> Result<Self, IOError> {
+        let u32_at = |at: usize| u32::from_be_bytes(buf[at..at + 4].try_into().unwrap());
+        let invalid = |msg: &str| IOError::new(ErrorKind::InvalidData, msg.to_string());
+        if u32_at(0) != MAGIC {
+            return Err(invalid("peer is not speaking the RDMA handshake"));
+        }
+        let mtu = IbvMtu::try_from(u32_at(14)).map_err(|_| invalid("peer sent an invalid MTU"))?;
+        let mut raw = [0_u8; 16];
+        raw.copy_from_slice(&buf[23..39]);
+        Ok(Self {
+            endpoint: IbvEndpoint {
+                qpn: u32_at(4),
+                psn: u32_at(8),
+                lid: u16::from_be_bytes([buf[12], buf[13]]),
+                gid: (buf[22] != 0).then_some(IbvGid { raw }),
+                gid_index: 0,
+                mtu,
+                max_msg_sz: u32_at(18),
+            },
+        })
+    }
+}
+
+pub struct TcpRdmaConnector<'a> {
+    context: &'a IbvContext,
+    port_num: u8,
+    params: TcpConnectParams,
+}
+
+impl<'a> TcpRdmaConnector<'a> {
+    pub fn new(context: &'a IbvContext, port_num: u8, params: TcpConnectParams) -> Self {
+        Self {
+            context,
+            port_num,
+            params,
+        }
+    }
+    // Client side: connects to `addr`, where the peer runs `accept`, and returns
+    // the QP `qp` builds, in RTS and connected to the peer's.
+    pub fn connect<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        pd: &IbvPd,
+        qp: IbvQpBuilder,
+    ) -> Result<IbvQp, IOError> {
+        Ok(self.connect_stream(addr, pd, qp)?.0)
+    }
+    // Like `connect`, but hands back the bootstrap socket as well, e.g. to swap MR
+    // tokens or other application metadata with the peer.
+    pub fn connect_stream<A: ToSocketAddrs>(
+        &self,
+        addr: A,
+        pd: &IbvPd,
+        qp: IbvQpBuilder,
+    ) -> Result<(IbvQp, TcpStream), IOError> {
+        let mut last_err = IOError::new(ErrorKind::InvalidInput, "no address to connect to");
+        for addr in addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.params.timeout) {
+                Ok(stream) => return self.handshake(stream, pd, qp),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+    // Server side: takes the next connection on `listener` and completes the
+    // handshake like `connect`.
+    pub fn accept(
+        &self,
+        listener: &TcpListener,
+        pd: &IbvPd,
+        qp: IbvQpBuilder,
+    ) -> Result<IbvQp, IOError> {
+        Ok(self.accept_stream(listener, pd, qp)?.0)
+    }
+    // `accept` counterpart of `connect_stream`.
+    pub fn accept_stream(
+        &self,
+        listener: &TcpListener,
+        pd: &IbvPd,
+        qp: IbvQpBuilder,
+    ) -> Result<(IbvQp, TcpStream), IOError> {
+        let (stream, _) = listener.accept()?;
+        self.handshake(stream, pd, qp)
+    }
+    fn handshake(
+        &self,
+        mut stream: TcpStream,
+        pd: &IbvPd,
+        qp: IbvQpBuilder,
+    ) -> Result<(IbvQp, TcpStream), IOError> {
+        stream.set_read_timeout(Some(self.params.timeout))?;
+        stream.set_write_timeout(Some(self.params.timeout))?;
+        stream.set_nodelay(true)?;
+
+        let qp = qp.build(pd)?;
+        let mut local = Handshake {
+            endpoint: IbvEndpoint::with_random_psn(self.context, &qp, self.port_num)?,
+        };
+        if local.endpoint.gid.is_some() {
+            // RoCE routes by IP: source from the GID carrying the address the
+            // bootstrap socket uses, as that is the one the peer can reach.
+            if let Ok(index) = self.socket_gid_index(&stream) {
+                local.endpoint.gid = Some(self.context.query_gid(self.port_num, index as i32)?);
+                local.endpoint.gid_index = index;
+            }
+        }
+        stream.write_all(&local.encode())?;
+        let mut buf = [0_u8; MSG_LEN];
+        stream.read_exact(&mut buf)?;
+        let remote = Handshake::decode(&buf)?;
+
+        let mtu = self
+            .params
+            .mtu
+            .min(local.endpoint.mtu)
+            .min(remote.endpoint.mtu);
+        qp.modify_reset2init_with_access(self.port_num, 0, self.params.access)?;
+        qp.modify_init2rtr_to(self.port_num, &local.endpoint, &remote.endpoint, mtu)?;
+        qp.modify_rtr2rts(local.endpoint.psn)?;
+
+        // Both QPs are at least RTR once the peer's byte arrives.
+        stream.write_all(&[1])?;
+        stream.read_exact(&mut [0_u8; 1])?;
+        Ok((qp, stream))
+    }
+    fn socket_gid_index(&self, stream: &TcpStream) -> Result<u8, IOError> {
+        match stream.local_addr()?.ip() {
+            IpAddr::V4(addr) => self.context.find_ipv4_gid_index(self.port_num, addr),
+            IpAddr::V6(addr) => self.context.find_gid_index(
+                self.port_num,
+                &IbvGid { raw: addr.octets() },
+                Some(ffi::ibv_gid_type_IBV_GID_TYPE_ROCE_V2),
+            ),
+        }
+    }
+}